@@ -0,0 +1,9 @@
+fn main() {
+    // grpc的代码由proto生成，使用内置的protoc避免依赖系统安装
+    #[cfg(feature = "grpc")]
+    {
+        let protoc = protoc_bin_vendored::protoc_bin_path().expect("protoc is not found");
+        std::env::set_var("PROTOC", protoc);
+        tonic_build::compile_protos("proto/optim.proto").expect("compile protos fail");
+    }
+}
@@ -0,0 +1,86 @@
+use crate::api_key;
+use crate::config;
+use crate::image_encoder;
+use crate::image_processing;
+use crate::middleware;
+use crate::state;
+use crate::stats;
+use axum::http::StatusCode;
+use axum::middleware::from_fn;
+use axum::routing::{delete, get, post};
+use axum::{Json, Router};
+use serde::Serialize;
+
+pub fn new_router() -> Router {
+    Router::new()
+        .route("/admin/drain", post(drain))
+        .route("/admin/watermarks", delete(clear_watermarks))
+        .route("/admin/usage", get(usage))
+        .route("/admin/cache", get(cache_stats))
+        .route("/admin/encoder", get(encoder_profile))
+        .route("/admin/reload", post(reload))
+        // 管理接口需校验token
+        .route_layer(from_fn(middleware::admin))
+        .route("/stats", get(stats))
+}
+
+#[derive(Serialize)]
+struct DrainResult {
+    processing: u32,
+}
+
+async fn drain() -> (StatusCode, Json<DrainResult>) {
+    let processing = state::get_processing();
+    if !state::is_stopping() {
+        state::set_stopping();
+        tokio::spawn(async {
+            let timeout = config::get_drain_timeout();
+            tracing::info!(timeout = timeout.as_secs(), "Server is draining");
+            let count = state::wait_for_idle(timeout).await;
+            if count != 0 {
+                tracing::warn!(processing = count, "Drain timeout exceeded");
+            }
+            state::drain();
+        });
+    }
+    (StatusCode::ACCEPTED, Json(DrainResult { processing }))
+}
+
+#[derive(Serialize)]
+struct ClearCacheResult {
+    count: usize,
+}
+
+async fn clear_watermarks() -> Json<ClearCacheResult> {
+    let count = image_processing::clear_watermark_cache().await;
+    Json(ClearCacheResult { count })
+}
+
+async fn reload() -> Json<ClearCacheResult> {
+    let count = config::reload_env();
+    tracing::info!(count, "Env values are reloaded");
+    Json(ClearCacheResult { count })
+}
+
+async fn usage() -> Json<Vec<api_key::UsageReport>> {
+    Json(api_key::get_usage_reports())
+}
+
+#[derive(Serialize)]
+struct CacheStatsResult {
+    watermark: image_processing::CacheStats,
+    decoded: image_processing::CacheStats,
+}
+
+async fn cache_stats() -> Json<CacheStatsResult> {
+    let (watermark, decoded) = image_processing::get_cache_stats().await;
+    Json(CacheStatsResult { watermark, decoded })
+}
+
+async fn encoder_profile() -> Json<image_encoder::EncoderProfile> {
+    Json(image_encoder::get_encoder_profile().clone())
+}
+
+async fn stats() -> Json<Vec<stats::FormatReport>> {
+    Json(stats::get_reports())
+}
@@ -0,0 +1,210 @@
+use crate::config;
+use crate::image_processing::OutputType;
+use chrono::Local;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Api key with daily quotas, 0 means unlimited.
+#[derive(Debug, Clone, Default)]
+pub struct ApiKey {
+    pub name: String,
+    pub max_requests: u64,
+    pub max_megapixels: u64,
+    /// Allowed tasks, none means all tasks.
+    pub tasks: Option<Vec<String>>,
+    /// Allowed output types, none means all types.
+    pub output_types: Option<Vec<OutputType>>,
+}
+
+/// Usage of api key in the day.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Usage {
+    pub date: String,
+    pub requests: u64,
+    pub megapixels: f64,
+    pub bytes: u64,
+}
+
+// 配置格式为name:key:max_requests:max_megapixels，限制可省略
+fn parse_api_key(value: &str) -> Option<(String, ApiKey)> {
+    let arr: Vec<_> = value.split(':').map(|item| item.trim()).collect();
+    if arr.len() < 2 || arr[0].is_empty() || arr[1].is_empty() {
+        return None;
+    }
+    let get_limit = |index: usize| {
+        arr.get(index)
+            .and_then(|item| item.parse().ok())
+            .unwrap_or_default()
+    };
+    let name = arr[0].to_string();
+    let output_types = config::get_api_key_allowed("FORMATS", &name).map(|values| {
+        values
+            .iter()
+            .filter_map(|item| {
+                let result = item.parse().ok();
+                if result.is_none() {
+                    tracing::warn!(name, format = item, "Api key format is invalid");
+                }
+                result
+            })
+            .collect()
+    });
+    Some((
+        arr[1].to_string(),
+        ApiKey {
+            tasks: config::get_api_key_allowed("TASKS", &name),
+            output_types,
+            max_requests: get_limit(2),
+            max_megapixels: get_limit(3),
+            name,
+        },
+    ))
+}
+
+static API_KEYS: Lazy<HashMap<String, ApiKey>> = Lazy::new(|| {
+    config::get_api_keys()
+        .iter()
+        .filter_map(|item| {
+            let result = parse_api_key(item);
+            if result.is_none() {
+                tracing::warn!("Api key config is invalid");
+            }
+            result
+        })
+        .collect()
+});
+
+static USAGES: Lazy<Mutex<HashMap<String, Usage>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn today() -> String {
+    Local::now().format("%Y-%m-%d").to_string()
+}
+
+/// Whether api key is required.
+pub fn is_enabled() -> bool {
+    !API_KEYS.is_empty()
+}
+
+/// Get the api key config by key.
+pub fn get(key: &str) -> Option<&'static ApiKey> {
+    API_KEYS.get(key)
+}
+
+/// Get the api key config by name.
+pub fn get_by_name(name: &str) -> Option<&'static ApiKey> {
+    API_KEYS.values().find(|api_key| api_key.name == name)
+}
+
+/// Check whether the task and output type are allowed for the api key,
+/// the output type none means keeping the original type.
+pub fn check_allowed(
+    name: &str,
+    task: &str,
+    output_type: Option<OutputType>,
+) -> Result<(), String> {
+    let Some(api_key) = API_KEYS.values().find(|item| item.name == name) else {
+        return Ok(());
+    };
+    if let Some(tasks) = &api_key.tasks {
+        if !tasks.iter().any(|item| item == task) {
+            return Err(format!("task({task}) is not allowed"));
+        }
+    }
+    if let (Some(output_types), Some(output_type)) = (&api_key.output_types, output_type) {
+        if !output_types.contains(&output_type) {
+            return Err(format!(
+                "output type({}) is not allowed",
+                output_type.as_str()
+            ));
+        }
+    }
+    Ok(())
+}
+
+// 获取当天的使用量，跨天则重置
+fn with_usage<T>(name: &str, f: impl FnOnce(&mut Usage) -> T) -> T {
+    let mut usages = USAGES.lock().unwrap_or_else(|e| e.into_inner());
+    let date = today();
+    let usage = usages.entry(name.to_string()).or_default();
+    if usage.date != date {
+        *usage = Usage {
+            date,
+            ..Default::default()
+        };
+    }
+    f(usage)
+}
+
+/// Check the quotas of api key and count the request,
+/// returns the error message if any quota is exceeded.
+pub fn check_request(api_key: &ApiKey) -> Result<(), String> {
+    with_usage(&api_key.name, |usage| {
+        if api_key.max_requests != 0 && usage.requests >= api_key.max_requests {
+            return Err(format!("max requests({}) exceeded", api_key.max_requests));
+        }
+        if api_key.max_megapixels != 0 && usage.megapixels >= api_key.max_megapixels as f64 {
+            return Err(format!(
+                "max megapixels({}) exceeded",
+                api_key.max_megapixels
+            ));
+        }
+        usage.requests += 1;
+        Ok(())
+    })
+}
+
+/// Add the processed pixels and source bytes to the usage of api key.
+pub fn add_processed(name: &str, pixels: u64, bytes: usize) {
+    with_usage(name, |usage| {
+        usage.megapixels += pixels as f64 / 1_000_000.0;
+        usage.bytes += bytes as u64;
+    });
+}
+
+#[derive(Serialize)]
+pub struct UsageReport {
+    pub name: String,
+    pub max_requests: u64,
+    pub max_megapixels: u64,
+    #[serde(flatten)]
+    pub usage: Usage,
+}
+
+/// Get the usage report of all api keys in the day.
+pub fn get_usage_reports() -> Vec<UsageReport> {
+    let mut reports: Vec<_> = API_KEYS
+        .values()
+        .map(|api_key| UsageReport {
+            name: api_key.name.clone(),
+            max_requests: api_key.max_requests,
+            max_megapixels: api_key.max_megapixels,
+            usage: with_usage(&api_key.name, |usage| usage.clone()),
+        })
+        .collect();
+    reports.sort_by(|a, b| a.name.cmp(&b.name));
+    reports
+}
+
+/// Load the usages from file, it is ignored if the file does not exist.
+pub fn load_usages(file: &str) -> std::io::Result<()> {
+    let data = match std::fs::read(file) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let values: HashMap<String, Usage> = serde_json::from_slice(&data)?;
+    let mut usages = USAGES.lock().unwrap_or_else(|e| e.into_inner());
+    *usages = values;
+    Ok(())
+}
+
+/// Save the usages to file.
+pub fn save_usages(file: &str) -> std::io::Result<()> {
+    let data = {
+        let usages = USAGES.lock().unwrap_or_else(|e| e.into_inner());
+        serde_json::to_vec(&*usages)?
+    };
+    std::fs::write(file, data)
+}
@@ -0,0 +1,86 @@
+use crate::config;
+use crate::image_processing::Task;
+use chrono::Local;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::io::Write;
+use std::sync::mpsc::{channel, Sender};
+
+/// Record of the sensitive operations for audit.
+#[derive(Serialize)]
+pub struct AuditRecord<'a> {
+    pub trace_id: &'a str,
+    pub api_key: &'a str,
+    /// Source of the image, base64 data is not recorded.
+    pub file: &'a str,
+    pub operations: &'a [Task],
+    /// Sha256 of the output data, none if the processing fails.
+    pub hash: Option<String>,
+    pub error: Option<&'a str>,
+}
+
+// 水印与遮挡需要审计
+pub fn is_audited(task: &Task) -> bool {
+    matches!(
+        task,
+        Task::Watermark { .. } | Task::Watermarks { .. } | Task::Redact { .. }
+    )
+}
+
+/// Sha256 of the output data.
+pub fn get_hash(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+// 审计文件由单独的线程按顺序追加写入，避免阻塞处理的线程以及多个记录交错
+static AUDIT_WRITER: Lazy<Sender<(String, String)>> = Lazy::new(|| {
+    let (tx, rx) = channel::<(String, String)>();
+    let result = std::thread::Builder::new()
+        .name("audit-writer".to_string())
+        .spawn(move || {
+            for (file, line) in rx {
+                let result = std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&file)
+                    .and_then(|mut f| writeln!(f, "{line}"));
+                if let Err(e) = result {
+                    tracing::error!(target: "audit", file, "Write audit record fail, {e}");
+                }
+            }
+        });
+    if let Err(e) = result {
+        tracing::error!(target: "audit", "Spawn audit writer fail, {e}");
+    }
+    tx
+});
+
+/// Write the record to the tracing target `audit`,
+/// and append to the audit file as json line if configured.
+pub fn record(record: &AuditRecord) {
+    #[derive(Serialize)]
+    struct Line<'a> {
+        time: String,
+        #[serde(flatten)]
+        record: &'a AuditRecord<'a>,
+    }
+    let line = match serde_json::to_string(&Line {
+        time: Local::now().to_rfc3339(),
+        record,
+    }) {
+        Ok(line) => line,
+        Err(e) => {
+            tracing::error!(target: "audit", "Serialize audit record fail, {e}");
+            return;
+        }
+    };
+    tracing::info!(target: "audit", "{line}");
+    let file = config::get_audit_file();
+    if file.is_empty() {
+        return;
+    }
+    if AUDIT_WRITER.send((file.clone(), line)).is_err() {
+        tracing::error!(target: "audit", file, "Audit writer is closed");
+    }
+}
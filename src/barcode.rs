@@ -0,0 +1,169 @@
+use image::{DynamicImage, GrayImage, Luma};
+use qrcode::render::svg;
+use qrcode::QrCode;
+use serde::Deserialize;
+use snafu::{ResultExt, Snafu};
+
+#[derive(Debug, Snafu)]
+pub enum BarcodeError {
+    #[snafu(display("Generate qr code fail: {source}"))]
+    Qr { source: qrcode::types::QrError },
+    #[snafu(display("Text is invalid: {message}"))]
+    InvalidText { message: String },
+    #[snafu(display("Encode png fail: {source}"))]
+    Png { source: lodepng::Error },
+}
+
+type Result<T, E = BarcodeError> = std::result::Result<T, E>;
+
+/// Kind of the generated code.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CodeKind {
+    #[default]
+    Qr,
+    Code128,
+}
+
+// code128各符号的条空宽度，0-102为数据，103-105为起始符(A、B、C)
+const CODE128_PATTERNS: [&str; 106] = [
+    "212222", "222122", "222221", "121223", "121322", "131222", "122213", "122312", "132212",
+    "221213", "221312", "231212", "112232", "122132", "122231", "113222", "123122", "123221",
+    "223211", "221132", "221231", "213212", "223112", "312131", "311222", "321122", "321221",
+    "312212", "322112", "322211", "212123", "212321", "232121", "111323", "131123", "131321",
+    "112313", "132113", "132311", "211313", "231113", "231311", "112133", "112331", "132131",
+    "113123", "113321", "133121", "313121", "211331", "231131", "213113", "213311", "213131",
+    "311123", "311321", "331121", "312113", "312311", "332111", "314111", "221411", "431111",
+    "111224", "111422", "121124", "121421", "141122", "141221", "112214", "112412", "122114",
+    "122411", "142112", "142211", "241211", "221114", "413111", "241112", "134111", "111242",
+    "121142", "121241", "114212", "124112", "124211", "411212", "421112", "421211", "212141",
+    "214121", "412121", "111143", "111341", "131141", "114113", "114311", "411113", "411311",
+    "113141", "114131", "311141", "411131", "211412", "211214", "211232",
+];
+const CODE128_START_B: usize = 104;
+const CODE128_STOP: &str = "2331112";
+// 两侧的空白区域(模块数)
+const CODE128_QUIET_ZONE: usize = 10;
+// 最多编码的字符数，每个字符11个模块，避免生成过宽的图片
+const CODE128_MAX_LENGTH: usize = 80;
+
+// 使用B字符集编码，返回各模块是否为条(黑色)，包括两侧的空白区域
+fn encode_code128(text: &str) -> Result<Vec<bool>> {
+    if text.len() > CODE128_MAX_LENGTH {
+        return InvalidTextSnafu {
+            message: format!("code128 text should not be longer than {CODE128_MAX_LENGTH}"),
+        }
+        .fail();
+    }
+    if let Some(c) = text.chars().find(|c| !(' '..='~').contains(c)) {
+        return InvalidTextSnafu {
+            message: format!("code128 does not support the char({c:?})"),
+        }
+        .fail();
+    }
+    let mut values = vec![CODE128_START_B];
+    values.extend(text.bytes().map(|b| (b - b' ') as usize));
+    // 校验位为起始符加上各数据与位置的乘积之和
+    let checksum = values
+        .iter()
+        .enumerate()
+        .map(|(index, value)| index.max(1) * value)
+        .sum::<usize>()
+        % 103;
+    values.push(checksum);
+
+    let mut modules = vec![false; CODE128_QUIET_ZONE];
+    let patterns = values
+        .iter()
+        .map(|value| CODE128_PATTERNS[*value])
+        .chain([CODE128_STOP]);
+    for pattern in patterns {
+        // 条空交替，以条开始
+        for (index, width) in pattern.bytes().enumerate() {
+            let bar = index % 2 == 0;
+            modules.extend(std::iter::repeat_n(bar, (width - b'0') as usize));
+        }
+    }
+    modules.resize(modules.len() + CODE128_QUIET_ZONE, false);
+    Ok(modules)
+}
+
+fn ensure_text(text: &str) -> Result<()> {
+    if text.is_empty() {
+        return InvalidTextSnafu {
+            message: "text should not be empty",
+        }
+        .fail();
+    }
+    Ok(())
+}
+
+/// Generate the code image, the width of qr code is at least size, the code128
+/// barcode is scaled by integer module width and its height is a third of size.
+pub fn new_code_image(kind: CodeKind, text: &str, size: u32) -> Result<DynamicImage> {
+    ensure_text(text)?;
+    let img = match kind {
+        CodeKind::Qr => QrCode::new(text)
+            .context(QrSnafu)?
+            .render::<Luma<u8>>()
+            .min_dimensions(size, size)
+            .build(),
+        CodeKind::Code128 => {
+            let modules = encode_code128(text)?;
+            let scale = (size / modules.len() as u32).max(1);
+            let height = (size / 3).max(20);
+            GrayImage::from_fn(modules.len() as u32 * scale, height, |x, _| {
+                if modules[(x / scale) as usize] {
+                    Luma([0])
+                } else {
+                    Luma([255])
+                }
+            })
+        }
+    };
+    Ok(DynamicImage::ImageLuma8(img))
+}
+
+/// Generate the code as lossless grayscale png.
+pub fn new_code_png(kind: CodeKind, text: &str, size: u32) -> Result<Vec<u8>> {
+    let img = new_code_image(kind, text, size)?.into_luma8();
+    lodepng::encode_memory(
+        img.as_raw(),
+        img.width() as usize,
+        img.height() as usize,
+        lodepng::ColorType::GREY,
+        8,
+    )
+    .context(PngSnafu)
+}
+
+/// Generate the code as svg with the same size as the image.
+pub fn new_code_svg(kind: CodeKind, text: &str, size: u32) -> Result<String> {
+    ensure_text(text)?;
+    let value = match kind {
+        CodeKind::Qr => QrCode::new(text)
+            .context(QrSnafu)?
+            .render::<svg::Color>()
+            .min_dimensions(size, size)
+            .build(),
+        CodeKind::Code128 => {
+            let modules = encode_code128(text)?;
+            let scale = (size / modules.len() as u32).max(1);
+            let width = modules.len() as u32 * scale;
+            let height = (size / 3).max(20);
+            let mut bars = String::new();
+            for (index, bar) in modules.iter().enumerate() {
+                if *bar {
+                    bars.push_str(&format!(
+                        "M{} 0h{scale}v{height}h-{scale}z",
+                        index as u32 * scale
+                    ));
+                }
+            }
+            format!(
+                r##"<?xml version="1.0" standalone="yes"?><svg xmlns="http://www.w3.org/2000/svg" version="1.1" width="{width}" height="{height}" viewBox="0 0 {width} {height}" shape-rendering="crispEdges"><rect x="0" y="0" width="{width}" height="{height}" fill="#fff"/><path fill="#000" d="{bars}"/></svg>"##
+            )
+        }
+    };
+    Ok(value)
+}
@@ -0,0 +1,281 @@
+use crate::config;
+use crate::image_processing::parse_hex_color;
+use ab_glyph::{point, Font, FontVec, PxScale, PxScaleFont, ScaleFont};
+use image::imageops::FilterType;
+use image::{DynamicImage, Rgba, RgbaImage};
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use snafu::{ResultExt, Snafu};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Size of the social card.
+pub const CARD_WIDTH: u32 = 1200;
+pub const CARD_HEIGHT: u32 = 630;
+
+#[derive(Debug, Snafu)]
+pub enum CardError {
+    #[snafu(display("Card template({name}) is not found"))]
+    TemplateNotFound { name: String },
+    #[snafu(display("Card template({name}) is invalid: {source}"))]
+    Template {
+        name: String,
+        source: serde_json::Error,
+    },
+    #[snafu(display("Read font({file}) fail: {source}"))]
+    Io {
+        file: String,
+        source: std::io::Error,
+    },
+    #[snafu(display("Font({file}) is invalid"))]
+    InvalidFont { file: String },
+    #[snafu(display("Color({value}) is invalid"))]
+    InvalidColor { value: String },
+    #[snafu(display("Encode png fail: {source}"))]
+    Png { source: lodepng::Error },
+}
+
+type Result<T, E = CardError> = std::result::Result<T, E>;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TextAlign {
+    #[default]
+    Left,
+    Center,
+    Right,
+}
+
+/// Text block of the card filled from the param.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CardText {
+    /// Name of the param filled to the block.
+    pub param: String,
+    /// Text used if the param is not set.
+    #[serde(default)]
+    pub default: String,
+    pub x: u32,
+    pub y: u32,
+    /// Max width of the line, the text is wrapped if it is exceeded, 0 means no wrap.
+    #[serde(default)]
+    pub width: u32,
+    /// Path of the ttf or otf font file.
+    pub font: String,
+    pub size: f32,
+    #[serde(default = "default_color")]
+    pub color: String,
+    /// Max lines of the text, the last line ends with ellipsis if truncated, 0 means unlimited.
+    #[serde(default)]
+    pub max_lines: usize,
+    #[serde(default)]
+    pub align: TextAlign,
+    /// Line height relative to the font size.
+    #[serde(default = "default_line_height")]
+    pub line_height: f32,
+}
+
+fn default_color() -> String {
+    "000000".to_string()
+}
+
+fn default_line_height() -> f32 {
+    1.2
+}
+
+/// Template of the social card.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CardTemplate {
+    /// File of the background image, or color as #rrggbb.
+    pub background: String,
+    #[serde(default)]
+    pub texts: Vec<CardText>,
+}
+
+/// Get the template of the name from config.
+pub fn get_template(name: &str) -> Result<CardTemplate> {
+    let value = Some(name)
+        .filter(|name| {
+            !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        })
+        .and_then(config::get_card_template)
+        .ok_or_else(|| TemplateNotFoundSnafu { name }.build())?;
+    serde_json::from_str(&value).context(TemplateSnafu { name })
+}
+
+// 字体文件加载后缓存
+static FONTS: Lazy<Mutex<HashMap<String, Arc<FontVec>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn load_font(file: &str) -> Result<Arc<FontVec>> {
+    if let Some(font) = FONTS.lock().unwrap_or_else(|e| e.into_inner()).get(file) {
+        return Ok(font.clone());
+    }
+    let data = std::fs::read(file).context(IoSnafu { file })?;
+    let font =
+        Arc::new(FontVec::try_from_vec(data).map_err(|_| InvalidFontSnafu { file }.build())?);
+    FONTS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(file.to_string(), font.clone());
+    Ok(font)
+}
+
+fn measure(font: &PxScaleFont<&FontVec>, text: &str) -> f32 {
+    let mut width = 0.0;
+    let mut previous = None;
+    for c in text.chars() {
+        let id = font.glyph_id(c);
+        if let Some(previous) = previous {
+            width += font.kern(previous, id);
+        }
+        width += font.h_advance(id);
+        previous = Some(id);
+    }
+    width
+}
+
+// 按宽度换行，英文等优先在空格处换行，中文等则按字符换行
+fn wrap_lines(font: &PxScaleFont<&FontVec>, text: &str, width: f32) -> Vec<String> {
+    let mut lines = vec![];
+    for paragraph in text.lines() {
+        let mut line = String::new();
+        for c in paragraph.chars() {
+            line.push(c);
+            if width <= 0.0 || line.chars().count() == 1 || measure(font, &line) <= width {
+                continue;
+            }
+            line.pop();
+            let rest = match line.rfind(' ') {
+                Some(index) if !c.is_whitespace() => {
+                    let rest = line[index + 1..].to_string();
+                    line.truncate(index);
+                    rest
+                }
+                _ => String::new(),
+            };
+            lines.push(line.trim_end().to_string());
+            line = rest;
+            if !c.is_whitespace() {
+                line.push(c);
+            }
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+// 超出最大行数时截断，最后一行以省略号结束
+fn truncate_lines(font: &PxScaleFont<&FontVec>, lines: &mut Vec<String>, text: &CardText) {
+    if text.max_lines == 0 || lines.len() <= text.max_lines {
+        return;
+    }
+    lines.truncate(text.max_lines);
+    let Some(last) = lines.last_mut() else {
+        return;
+    };
+    loop {
+        let value = format!("{}…", last.trim_end());
+        if text.width == 0 || last.is_empty() || measure(font, &value) <= text.width as f32 {
+            *last = value;
+            return;
+        }
+        last.pop();
+    }
+}
+
+fn draw_text(canvas: &mut RgbaImage, text: &CardText, value: &str) -> Result<()> {
+    let color = parse_hex_color(&text.color).ok_or_else(|| {
+        InvalidColorSnafu {
+            value: text.color.clone(),
+        }
+        .build()
+    })?;
+    let font = load_font(&text.font)?;
+    let scaled = font.as_scaled(PxScale::from(text.size));
+    let mut lines = wrap_lines(&scaled, value, text.width as f32);
+    truncate_lines(&scaled, &mut lines, text);
+
+    let line_height = text.size * text.line_height;
+    for (index, line) in lines.iter().enumerate() {
+        let line_width = measure(&scaled, line);
+        let offset = match text.align {
+            TextAlign::Left => 0.0,
+            TextAlign::Center => (text.width as f32 - line_width) / 2.0,
+            TextAlign::Right => text.width as f32 - line_width,
+        };
+        let mut x = text.x as f32 + offset.max(0.0);
+        let baseline = text.y as f32 + scaled.ascent() + index as f32 * line_height;
+        let mut previous = None;
+        for c in line.chars() {
+            let id = scaled.glyph_id(c);
+            if let Some(previous) = previous {
+                x += scaled.kern(previous, id);
+            }
+            previous = Some(id);
+            let glyph = id.with_scale_and_position(text.size, point(x, baseline));
+            x += scaled.h_advance(id);
+            let Some(outlined) = font.outline_glyph(glyph) else {
+                continue;
+            };
+            let bounds = outlined.px_bounds();
+            outlined.draw(|gx, gy, coverage| {
+                let px = bounds.min.x as i64 + gx as i64;
+                let py = bounds.min.y as i64 + gy as i64;
+                if px < 0 || py < 0 || px >= canvas.width() as i64 || py >= canvas.height() as i64 {
+                    return;
+                }
+                // 按覆盖率与颜色的透明度混合
+                let alpha = coverage.clamp(0.0, 1.0) * color[3] as f32 / 255.0;
+                let pixel = canvas.get_pixel_mut(px as u32, py as u32);
+                for i in 0..3 {
+                    pixel[i] =
+                        (pixel[i] as f32 * (1.0 - alpha) + color[i] as f32 * alpha).round() as u8;
+                }
+                pixel[3] = pixel[3].max((alpha * 255.0).round() as u8);
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Render the card of template with the params, the background image
+/// is resized to fill the card and it is ignored if the background is color.
+pub fn render(
+    template: &CardTemplate,
+    background: Option<&DynamicImage>,
+    params: &HashMap<String, String>,
+) -> Result<DynamicImage> {
+    let mut canvas = match background {
+        Some(di) => di
+            .resize_to_fill(CARD_WIDTH, CARD_HEIGHT, FilterType::Lanczos3)
+            .to_rgba8(),
+        None => {
+            let color = parse_hex_color(&template.background).ok_or_else(|| {
+                InvalidColorSnafu {
+                    value: template.background.clone(),
+                }
+                .build()
+            })?;
+            RgbaImage::from_pixel(CARD_WIDTH, CARD_HEIGHT, Rgba(color))
+        }
+    };
+    for text in template.texts.iter() {
+        let value = params
+            .get(&text.param)
+            .filter(|value| !value.is_empty())
+            .unwrap_or(&text.default);
+        if !value.is_empty() {
+            draw_text(&mut canvas, text, value)?;
+        }
+    }
+    Ok(DynamicImage::ImageRgba8(canvas))
+}
+
+/// Render the card as lossless png.
+pub fn render_png(
+    template: &CardTemplate,
+    background: Option<&DynamicImage>,
+    params: &HashMap<String, String>,
+) -> Result<Vec<u8>> {
+    let img = render(template, background, params)?.into_rgba8();
+    lodepng::encode32(img.as_raw(), img.width() as usize, img.height() as usize).context(PngSnafu)
+}
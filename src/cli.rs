@@ -0,0 +1,261 @@
+use clap::{Args, Parser, Subcommand};
+use image_optim::image_encoder::EncoderOptions;
+use image_optim::image_processing::{OutputType, PipelineBuilder};
+use notify::{RecursiveMode, Watcher};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::unbounded_channel;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+#[derive(Parser)]
+#[command(version, about = "Image optimization service")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Convert local images with the same pipeline as the server
+    Convert(ConvertArgs),
+    /// Watch the directory and convert the new or changed images
+    Watch(WatchArgs),
+}
+
+#[derive(Args, Clone)]
+pub struct ConvertOptions {
+    /// Output directory, default is the directory of image
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    /// Output types, each type is converted to a file, default is the original type
+    #[arg(short = 't', long = "output-type")]
+    output_types: Vec<String>,
+    #[arg(short, long, default_value_t = 80)]
+    quality: u8,
+    #[arg(short, long, default_value_t = 3)]
+    speed: u8,
+    /// Resize width, 0 means keeping the aspect ratio
+    #[arg(long, default_value_t = 0)]
+    width: u32,
+    /// Resize height, 0 means keeping the aspect ratio
+    #[arg(long, default_value_t = 0)]
+    height: u32,
+    /// Encoder option of key:value, e.g. progressive:true
+    #[arg(long = "option")]
+    options: Vec<String>,
+    /// Calculate the dssim diff
+    #[arg(long)]
+    diff: bool,
+}
+
+#[derive(Args, Clone)]
+pub struct ConvertArgs {
+    /// Image files or glob patterns, e.g. "assets/**/*.png"
+    #[arg(required = true)]
+    inputs: Vec<String>,
+    /// Count of images converted in parallel
+    #[arg(short, long, default_value_t = 4)]
+    jobs: usize,
+    #[command(flatten)]
+    options: ConvertOptions,
+}
+
+#[derive(Args, Clone)]
+pub struct WatchArgs {
+    /// Directory to watch, including the sub directories
+    dir: PathBuf,
+    #[command(flatten)]
+    options: ConvertOptions,
+}
+
+// 展开glob，非glob的文件直接使用
+fn get_files(inputs: &[String]) -> Result<Vec<PathBuf>, String> {
+    let mut files = vec![];
+    for input in inputs {
+        let paths = glob::glob(input).map_err(|e| format!("{input} is invalid, {e}"))?;
+        for path in paths {
+            let path = path.map_err(|e| e.to_string())?;
+            if path.is_file() {
+                files.push(path);
+            }
+        }
+    }
+    Ok(files)
+}
+
+// 转换后的文件名为{file}_{quality}.{ext}，与/images的路径一致
+fn get_output_file(options: &ConvertOptions, file: &Path, ext: &str) -> PathBuf {
+    let stem = file.file_stem().unwrap_or_default().to_string_lossy();
+    let dir = options
+        .output
+        .clone()
+        .or_else(|| file.parent().map(|dir| dir.to_path_buf()))
+        .unwrap_or_default();
+    dir.join(format!("{stem}_{}.{ext}", options.quality))
+}
+
+async fn convert_file(
+    options: &ConvertOptions,
+    file: &Path,
+    output_type: Option<OutputType>,
+) -> Result<(PathBuf, String), String> {
+    let mut encoder_options = EncoderOptions::default();
+    for item in options.options.iter() {
+        let (key, value) = item.split_once(':').unwrap_or((item, ""));
+        encoder_options.set(key, value)?;
+    }
+    let path = std::fs::canonicalize(file).map_err(|e| e.to_string())?;
+    let mut builder = PipelineBuilder::load(&format!("file://{}", path.to_string_lossy()), "");
+    if options.width != 0 || options.height != 0 {
+        builder = builder.resize(options.width, options.height);
+    }
+    builder =
+        builder.optim_with_options(output_type, options.quality, options.speed, encoder_options);
+    if options.diff {
+        builder = builder.diff();
+    }
+    let img = builder.run().await.map_err(|e| e.to_string())?;
+    let data = img.get_buffer().await.map_err(|e| e.to_string())?;
+    let output = get_output_file(options, file, &img.ext);
+    std::fs::write(&output, &data).map_err(|e| e.to_string())?;
+
+    let ratio = (100 * data.len())
+        .checked_div(img.original_size)
+        .unwrap_or_default();
+    let message = format!(
+        "{} -> {}, size:{}, ratio:{ratio}%, diff:{:.2}",
+        file.display(),
+        output.display(),
+        data.len(),
+        img.diff,
+    );
+    Ok((output, message))
+}
+
+// 转换为各输出格式，返回生成的文件
+async fn convert_all(options: &ConvertOptions, file: &Path) -> Result<Vec<PathBuf>, String> {
+    let mut output_types = vec![];
+    for value in options.output_types.iter() {
+        output_types.push(Some(
+            value.parse::<OutputType>().map_err(|e| e.to_string())?,
+        ));
+    }
+    if output_types.is_empty() {
+        output_types.push(None);
+    }
+    let mut outputs = vec![];
+    for output_type in output_types {
+        let (output, message) = convert_file(options, file, output_type).await?;
+        println!("{message}");
+        outputs.push(output);
+    }
+    Ok(outputs)
+}
+
+fn create_output_dir(options: &ConvertOptions) -> bool {
+    if let Some(output) = &options.output {
+        if let Err(e) = std::fs::create_dir_all(output) {
+            eprintln!("Create output directory fail, {e}");
+            return false;
+        }
+    }
+    true
+}
+
+/// Convert the images in parallel, returns false if any image fails.
+#[tokio::main]
+pub async fn convert(args: ConvertArgs) -> bool {
+    let files = match get_files(&args.inputs) {
+        Ok(files) => files,
+        Err(message) => {
+            eprintln!("{message}");
+            return false;
+        }
+    };
+    if !create_output_dir(&args.options) {
+        return false;
+    }
+    let options = Arc::new(args.options);
+    let semaphore = Arc::new(Semaphore::new(args.jobs.max(1)));
+    let mut set = JoinSet::new();
+    for file in files {
+        let options = options.clone();
+        let semaphore = semaphore.clone();
+        set.spawn(async move {
+            let _permit = semaphore.acquire().await;
+            convert_all(&options, &file)
+                .await
+                .map_err(|message| eprintln!("Convert {} fail, {message}", file.display()))
+                .is_ok()
+        });
+    }
+    let mut success = true;
+    while let Some(result) = set.join_next().await {
+        success = success && result.unwrap_or_default();
+    }
+    success
+}
+
+// 图片格式的文件才转换
+fn is_image_file(file: &Path) -> bool {
+    let ext = file
+        .extension()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_lowercase();
+    ["png", "jpg", "jpeg", "webp", "avif", "gif"].contains(&ext.as_str())
+}
+
+/// Watch the directory and convert the new or changed images until stopped.
+#[tokio::main]
+pub async fn watch(args: WatchArgs) -> bool {
+    if !create_output_dir(&args.options) {
+        return false;
+    }
+    let (tx, mut rx) = unbounded_channel();
+    let watcher =
+        notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+            Ok(event) if event.kind.is_create() || event.kind.is_modify() => {
+                for path in event.paths {
+                    let _ = tx.send(path);
+                }
+            }
+            Ok(_) => {}
+            Err(e) => eprintln!("Watch fail, {e}"),
+        });
+    let mut watcher = match watcher {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            eprintln!("Create watcher fail, {e}");
+            return false;
+        }
+    };
+    if let Err(e) = watcher.watch(&args.dir, RecursiveMode::Recursive) {
+        eprintln!("Watch {} fail, {e}", args.dir.display());
+        return false;
+    }
+    println!("Watching {}", args.dir.display());
+    // 转换生成的文件也会触发事件，需要忽略
+    let mut outputs = HashSet::new();
+    while let Some(path) = rx.recv().await {
+        // 写入文件会触发多次事件，等待一段时间后合并处理
+        let mut paths = HashSet::from([path]);
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        while let Ok(path) = rx.try_recv() {
+            paths.insert(path);
+        }
+        for path in paths {
+            if outputs.contains(&path) || !path.is_file() || !is_image_file(&path) {
+                continue;
+            }
+            match convert_all(&args.options, &path).await {
+                Ok(files) => outputs.extend(files),
+                Err(message) => eprintln!("Convert {} fail, {message}", path.display()),
+            }
+        }
+    }
+    true
+}
@@ -0,0 +1,680 @@
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::RwLock;
+use std::time::Duration;
+
+// 已解析的env值(包括从文件读取与${NAME}替换)，重新加载时清除
+static ENV_CACHE: Lazy<RwLock<HashMap<String, Option<String>>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+/// Clear the resolved env values, the values are read again
+/// from the env and files when used, returns the count of removed values.
+pub fn reload_env() -> usize {
+    let mut cache = ENV_CACHE.write().unwrap_or_else(|e| e.into_inner());
+    let count = cache.len();
+    cache.clear();
+    count
+}
+
+// 替换值中的${NAME}为对应env的值，未设置则为空
+fn interpolate_env(value: &str) -> String {
+    let mut result = String::new();
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        let Some(end) = rest[start..].find('}') else {
+            break;
+        };
+        result.push_str(&rest[..start]);
+        let name = &rest[start + 2..start + end];
+        result.push_str(&std::env::var(name).unwrap_or_default());
+        rest = &rest[start + end + 1..];
+    }
+    result.push_str(rest);
+    result
+}
+
+// 读取env的值，解析后缓存，避免每次使用时都读取文件
+fn get_env_string(key: &str) -> Option<String> {
+    if let Some(value) = ENV_CACHE.read().unwrap_or_else(|e| e.into_inner()).get(key) {
+        return value.clone();
+    }
+    let value = resolve_env_string(key);
+    ENV_CACHE
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(key.to_string(), value.clone());
+    value
+}
+
+// 读取env的值，未设置时可通过{key}_FILE从文件读取(如k8s挂载的secret)
+fn resolve_env_string(key: &str) -> Option<String> {
+    if let Ok(value) = std::env::var(key) {
+        return Some(interpolate_env(&value));
+    }
+    let file = std::env::var(format!("{key}_FILE")).ok()?;
+    match std::fs::read_to_string(&file) {
+        Ok(data) => Some(data.trim().to_string()),
+        Err(e) => {
+            tracing::error!(file, "Read env file fail, {e}");
+            None
+        }
+    }
+}
+
+// 读取env配置，未设置或格式不符时使用默认值
+fn get_env_value<T: FromStr>(key: &str, default: T) -> T {
+    get_env_string(key)
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+/// Timeout of loading the http source, including the retries and reading the data.
+pub fn get_load_timeout() -> Duration {
+    Duration::from_secs(get_env_value("OPTIM_LOAD_TIMEOUT", 5))
+}
+
+/// Timeout of decoding the image, 0 means no limit.
+pub fn get_decode_timeout() -> Duration {
+    Duration::from_secs(get_env_value("OPTIM_DECODE_TIMEOUT", 30))
+}
+
+/// Timeout of encoding the image, 0 means no limit.
+pub fn get_encode_timeout() -> Duration {
+    Duration::from_secs(get_env_value("OPTIM_ENCODE_TIMEOUT", 60))
+}
+
+/// Profile of the encoders: `auto` selects by the cpu features at startup,
+/// `simd` or `generic` forces the profile.
+pub fn get_encoder_profile() -> String {
+    get_env_value("OPTIM_ENCODER_PROFILE", "auto".to_string())
+}
+
+/// Speed added to the avif and webp encoding of the generic profile,
+/// as the encoding is much slower without the simd.
+pub fn get_generic_speed_offset() -> u8 {
+    get_env_value("OPTIM_GENERIC_SPEED_OFFSET", 2)
+}
+
+/// Count of the dedicated threads for encoding, 0 means using the blocking threads of tokio.
+pub fn get_encode_threads() -> usize {
+    get_env_value("OPTIM_ENCODE_THREADS", 0)
+}
+
+/// Nice value of the encoder threads, higher means lower priority(linux only).
+pub fn get_encode_nice() -> i32 {
+    get_env_value("OPTIM_ENCODE_NICE", 10)
+}
+
+/// Cpus the encoder threads are pinned to(linux only), e.g. `2,3` or `4-7`,
+/// empty means no pinning.
+pub fn get_encode_cpus() -> Vec<usize> {
+    let mut cpus = vec![];
+    for item in get_env_string("OPTIM_ENCODE_CPUS")
+        .unwrap_or_default()
+        .split(',')
+    {
+        let item = item.trim();
+        if item.is_empty() {
+            continue;
+        }
+        let (start, end) = item.split_once('-').unwrap_or((item, item));
+        match (start.trim().parse::<usize>(), end.trim().parse::<usize>()) {
+            (Ok(start), Ok(end)) if start <= end && end < 1024 => cpus.extend(start..=end),
+            _ => tracing::error!(item, "Encode cpus is invalid"),
+        }
+    }
+    cpus
+}
+
+/// Token of the admin apis(`/admin/*`), the apis are denied if it is not set.
+pub fn get_admin_token() -> String {
+    get_env_string("OPTIM_ADMIN_TOKEN").unwrap_or_default()
+}
+
+/// Max duration to wait for in-flight pipeline jobs when draining.
+pub fn get_drain_timeout() -> Duration {
+    Duration::from_secs(get_env_value("OPTIM_DRAIN_TIMEOUT", 30))
+}
+
+/// The original data will be written to a temp file if its size is over the limit,
+/// 0 means disabled.
+pub fn get_spill_size() -> usize {
+    get_env_value("OPTIM_SPILL_SIZE", 0)
+}
+
+/// Url of the external moderation service, empty means disabled.
+pub fn get_moderation_url() -> String {
+    get_env_value("OPTIM_MODERATION_URL", "".to_string())
+}
+
+/// Timeout of the moderation request.
+pub fn get_moderation_timeout() -> Duration {
+    Duration::from_millis(get_env_value("OPTIM_MODERATION_TIMEOUT", 3000))
+}
+
+/// Continue processing if the moderation service is unavailable.
+pub fn is_moderation_fail_open() -> bool {
+    get_env_value("OPTIM_MODERATION_FAIL_OPEN", false)
+}
+
+/// Post the hash of image instead of the image data.
+pub fn is_moderation_hash_only() -> bool {
+    get_env_value("OPTIM_MODERATION_HASH_ONLY", false)
+}
+
+/// Api key configs from env and file, the format is name:key:max_requests:max_megapixels.
+pub fn get_api_keys() -> Vec<String> {
+    let mut values = std::env::var("OPTIM_API_KEYS").unwrap_or_default();
+    if let Ok(file) = std::env::var("OPTIM_API_KEYS_FILE") {
+        match std::fs::read_to_string(&file) {
+            Ok(data) => values = format!("{values}\n{data}"),
+            Err(e) => tracing::error!(file, "Read api keys fail, {e}"),
+        }
+    }
+    interpolate_env(&values)
+        .split([',', '\n'])
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty() && !item.starts_with('#'))
+        .collect()
+}
+
+/// Allowed list of api key, e.g. OPTIM_API_KEY_TASKS_MOBILE=resize,optim,
+/// none means no restriction.
+pub fn get_api_key_allowed(kind: &str, name: &str) -> Option<Vec<String>> {
+    let key = format!("OPTIM_API_KEY_{kind}_{}", name.to_uppercase());
+    let value = get_env_string(&key)?;
+    Some(
+        value
+            .split(',')
+            .map(|item| item.trim().to_string())
+            .filter(|item| !item.is_empty())
+            .collect(),
+    )
+}
+
+/// Credentials of http loader for each host from env and file, the format is
+/// host=bearer:token, host=basic:user:password or host=header:name:value.
+pub fn get_loader_hosts() -> Vec<String> {
+    let mut values = std::env::var("OPTIM_LOADER_HOSTS").unwrap_or_default();
+    if let Ok(file) = std::env::var("OPTIM_LOADER_HOSTS_FILE") {
+        match std::fs::read_to_string(&file) {
+            Ok(data) => values = format!("{values}\n{data}"),
+            Err(e) => tracing::error!(file, "Read loader hosts fail, {e}"),
+        }
+    }
+    interpolate_env(&values)
+        .split([',', '\n'])
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty() && !item.starts_with('#'))
+        .collect()
+}
+
+/// Directory of the images for `/images`.
+pub fn get_optim_path() -> String {
+    get_env_value("OPTIM_PATH", "".to_string())
+}
+
+/// Named image directories selected by the `X-Storage` header,
+/// the format is name=path.
+pub fn get_storages() -> Vec<(String, String)> {
+    get_env_string("OPTIM_STORAGES")
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|item| {
+            let (name, path) = item.split_once('=')?;
+            let (name, path) = (name.trim(), path.trim());
+            if name.is_empty() || path.is_empty() {
+                return None;
+            }
+            Some((name.to_string(), path.to_string()))
+        })
+        .collect()
+}
+
+/// Path prefixes of the files readable in the image directory,
+/// empty means all files are allowed, e.g. `public/`.
+pub fn get_allowed_paths() -> Vec<String> {
+    get_env_string("OPTIM_ALLOWED_PATHS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|item| item.trim().trim_start_matches('/').to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+/// Path prefixes of the files never readable in the image directory,
+/// it takes precedence over the allowed paths, e.g. `private/`.
+pub fn get_denied_paths() -> Vec<String> {
+    get_env_string("OPTIM_DENIED_PATHS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|item| item.trim().trim_start_matches('/').to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+/// Base url of the origin server, the file missing in the image directory
+/// is loaded from it, empty means disabled.
+pub fn get_origin_url() -> String {
+    get_env_value("OPTIM_ORIGIN_URL", "".to_string())
+}
+
+/// Write the file loaded from origin server into the image directory.
+pub fn is_origin_store() -> bool {
+    get_env_value("OPTIM_ORIGIN_STORE", false)
+}
+
+/// Hosts allowed to be proxied when the file is a remote url, the host starts with `.`
+/// matches its subdomains, empty means the proxy mode is disabled.
+pub fn get_proxy_hosts() -> Vec<String> {
+    get_env_string("OPTIM_PROXY_HOSTS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|item| item.trim().to_lowercase())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+/// Max requests per minute of each proxied host, 0 means unlimited.
+pub fn get_proxy_rate_limit() -> u32 {
+    get_env_value("OPTIM_PROXY_RATE_LIMIT", 0)
+}
+
+/// Max age(seconds) of the cache control for the proxied images.
+pub fn get_proxy_cache_max_age() -> u64 {
+    get_env_value("OPTIM_PROXY_CACHE_MAX_AGE", 24 * 3600)
+}
+
+/// Max retry count of loading http source on transient errors, e.g. timeout or 503.
+pub fn get_loader_retries() -> u32 {
+    get_env_value("OPTIM_LOADER_RETRIES", 2)
+}
+
+/// Initial backoff of the retry, doubled for every retry with jitter.
+pub fn get_loader_retry_backoff() -> Duration {
+    Duration::from_millis(get_env_value("OPTIM_LOADER_RETRY_BACKOFF", 200))
+}
+
+/// Max concurrent requests of loading from the same host, 0 means no limit.
+pub fn get_loader_max_connections_per_host() -> usize {
+    get_env_value("OPTIM_LOADER_MAX_CONNECTIONS_PER_HOST", 0)
+}
+
+/// Max idle connections of each host kept in the pool.
+pub fn get_loader_pool_idle_per_host() -> usize {
+    get_env_value("OPTIM_LOADER_POOL_IDLE_PER_HOST", 32)
+}
+
+/// Idle connections in the pool are closed after the timeout.
+pub fn get_loader_pool_idle_timeout() -> Duration {
+    Duration::from_secs(get_env_value("OPTIM_LOADER_POOL_IDLE_TIMEOUT", 90))
+}
+
+/// Timeout of connecting to the host.
+pub fn get_loader_connect_timeout() -> Duration {
+    Duration::from_secs(get_env_value("OPTIM_LOADER_CONNECT_TIMEOUT", 10))
+}
+
+/// Time to live of the cached dns result, 0 means no cache.
+pub fn get_loader_dns_ttl() -> Duration {
+    Duration::from_secs(get_env_value("OPTIM_LOADER_DNS_TTL", 60))
+}
+
+/// Whether the debug report is enabled for all requests.
+pub fn is_debug_enabled() -> bool {
+    get_env_value("OPTIM_DEBUG", false)
+}
+
+/// Whether the debug report is enabled for the api key,
+/// e.g. OPTIM_API_KEY_DEBUG_MOBILE=true.
+pub fn is_api_key_debug(name: &str) -> bool {
+    get_env_value(
+        &format!("OPTIM_API_KEY_DEBUG_{}", name.to_uppercase()),
+        false,
+    )
+}
+
+/// File appended with the audit records of watermark and redaction as json lines,
+/// empty means only logged.
+pub fn get_audit_file() -> String {
+    get_env_value("OPTIM_AUDIT_FILE", "".to_string())
+}
+
+/// File of processing stats per format, empty means not persisted.
+pub fn get_stats_file() -> String {
+    get_env_value("OPTIM_STATS_FILE", "".to_string())
+}
+
+/// File of api key usages, empty means not persisted.
+pub fn get_usage_file() -> String {
+    get_env_value("OPTIM_USAGE_FILE", "".to_string())
+}
+
+/// Respond the original data if it can not be decoded
+/// or the optimized data is larger.
+pub fn is_passthrough() -> bool {
+    get_env_value("OPTIM_PASSTHROUGH", false)
+}
+
+/// Reject the image whose extension(or content type) does not match the
+/// format sniffed from its content, otherwise the sniffed format is used.
+pub fn is_strict_format() -> bool {
+    get_env_value("OPTIM_STRICT_FORMAT", false)
+}
+
+/// Max width and height of the resized, cropped or extended image.
+pub fn get_max_dimension() -> u32 {
+    get_env_value("OPTIM_MAX_DIMENSION", 16384)
+}
+
+/// Max count of in-flight pipeline jobs, 0 means no limit.
+pub fn get_max_processing() -> u32 {
+    get_env_value("OPTIM_MAX_PROCESSING", 0)
+}
+
+/// Max pixels of the in-flight decoded images, 0 means no limit.
+pub fn get_max_processing_pixels() -> u64 {
+    get_env_value::<u64>("OPTIM_MAX_PROCESSING_MEGAPIXELS", 0) * 1_000_000
+}
+
+/// Max resident memory of the process, 0 means no limit.
+pub fn get_max_memory() -> u64 {
+    get_env_value::<u64>("OPTIM_MAX_MEMORY", 0) * 1024 * 1024
+}
+
+/// Downgrade the quality instead of rejecting the request when overloaded.
+pub fn is_overload_downgrade() -> bool {
+    get_env_value("OPTIM_OVERLOAD_DOWNGRADE", false)
+}
+
+/// Seconds of the Retry-After header for 503.
+pub fn get_retry_after() -> u32 {
+    get_env_value("OPTIM_RETRY_AFTER", 5)
+}
+
+/// Default max age(seconds) of the image cache control.
+pub fn get_cache_max_age() -> u64 {
+    get_env_value("OPTIM_CACHE_MAX_AGE", 30 * 24 * 3600)
+}
+
+/// Min and max of the max age(seconds) specified by request.
+pub fn get_cache_max_age_range() -> (u64, u64) {
+    (
+        get_env_value("OPTIM_CACHE_MAX_AGE_MIN", 0),
+        get_env_value("OPTIM_CACHE_MAX_AGE_MAX", 365 * 24 * 3600),
+    )
+}
+
+/// Max concurrent processing of a bulk websocket connection.
+pub fn get_bulk_max_concurrency() -> usize {
+    get_env_value("OPTIM_BULK_MAX_CONCURRENCY", 4)
+}
+
+/// Max bytes of the upload and post body.
+pub fn get_max_upload_bytes() -> usize {
+    get_env_value("OPTIM_MAX_UPLOAD_BYTES", 20 * 1024 * 1024)
+}
+
+/// Max bytes of the encoded output, it will be encoded again with lower quality
+/// or smaller size if exceeded, 0 means no limit.
+pub fn get_max_output_bytes() -> usize {
+    get_env_value("OPTIM_MAX_OUTPUT_BYTES", 0)
+}
+
+/// Strategy of reducing the output, quality, resize or both(lower quality first).
+pub fn get_output_budget_strategy() -> String {
+    get_env_value("OPTIM_OUTPUT_BUDGET_STRATEGY", "both".to_string())
+}
+
+/// Frame of animated image converted to static format, first, middle or error.
+pub fn get_animated_frame() -> String {
+    get_env_value("OPTIM_ANIMATED_FRAME", "first".to_string())
+}
+
+/// The quality of uploaded image optimization.
+pub fn get_upload_quality() -> u8 {
+    get_env_value("OPTIM_UPLOAD_QUALITY", 90)
+}
+
+/// Process the image for HEAD request of `/images`,
+/// otherwise only the headers are responded without content length.
+pub fn is_head_process() -> bool {
+    get_env_value("OPTIM_HEAD_PROCESS", true)
+}
+
+/// The image with the same output type is not optimized again
+/// if its size is not larger than this, 0 means disabled.
+pub fn get_skip_size() -> usize {
+    get_env_value("OPTIM_SKIP_SIZE", 0)
+}
+
+/// The jpeg is not optimized again if the quality is not lower than
+/// its estimated quality.
+pub fn is_skip_by_quality() -> bool {
+    get_env_value("OPTIM_SKIP_BY_QUALITY", false)
+}
+
+/// Avif speed increases by 2 for every this many in-flight avif encodes,
+/// 0 means disabled.
+pub fn get_avif_adaptive_count() -> u32 {
+    get_env_value("OPTIM_AVIF_ADAPTIVE_COUNT", 0)
+}
+
+/// Avif quality also decreases by 10(min 50) for every adaptive level.
+pub fn is_avif_adaptive_quality() -> bool {
+    get_env_value("OPTIM_AVIF_ADAPTIVE_QUALITY", false)
+}
+
+/// Percentage(0-100) of the requests calculating the diff.
+pub fn get_diff_sample_rate() -> u8 {
+    get_env_value("OPTIM_DIFF_SAMPLE_RATE", 100)
+}
+
+/// Images wider than it are downscaled before calculating the diff,
+/// the diff is approximate, 0 means disabled.
+pub fn get_diff_max_width() -> u32 {
+    get_env_value("OPTIM_DIFF_MAX_WIDTH", 0)
+}
+
+/// Default max diff(dssim*1000) of the optimized image, 0 means disabled.
+pub fn get_max_diff() -> f64 {
+    get_env_value("OPTIM_MAX_DIFF", 0.0)
+}
+
+/// Max count of re-encoding with higher quality when the diff exceeds.
+pub fn get_max_diff_retries() -> u8 {
+    get_env_value("OPTIM_MAX_DIFF_RETRIES", 3)
+}
+
+/// Max frames of the animated webp re-encoded frame by frame,
+/// only the first frame is used if exceeded, 0 means no limit.
+pub fn get_animated_max_frames() -> usize {
+    get_env_value("OPTIM_ANIMATED_MAX_FRAMES", 300)
+}
+
+/// Max pixels of the decoded image cache, 0 means disabled.
+pub fn get_decoded_cache_pixels() -> u64 {
+    get_env_value::<u64>("OPTIM_DECODED_CACHE_MEGAPIXELS", 0) * 1_000_000
+}
+
+/// Image file responded when processing fails with fallback,
+/// empty means a 1x1 transparent png.
+pub fn get_fallback_image() -> String {
+    get_env_value("OPTIM_FALLBACK_IMAGE", "".to_string())
+}
+
+/// Path of ffmpeg for video output.
+#[cfg(feature = "video")]
+pub fn get_ffmpeg_path() -> String {
+    get_env_value("OPTIM_FFMPEG_PATH", "ffmpeg".to_string())
+}
+
+/// Certificate file(pem) of the https listener, empty means serving http.
+pub fn get_tls_cert() -> String {
+    get_env_value("OPTIM_TLS_CERT", "".to_string())
+}
+
+/// Private key file(pem) of the https listener.
+pub fn get_tls_key() -> String {
+    get_env_value("OPTIM_TLS_KEY", "".to_string())
+}
+
+/// Interval of checking the certificate and key files for reloading,
+/// 0 means never reloaded.
+pub fn get_tls_reload_interval() -> Duration {
+    Duration::from_secs(get_env_value("OPTIM_TLS_RELOAD_INTERVAL", 60))
+}
+
+/// Timeout of the tls handshake, 0 means no timeout.
+pub fn get_tls_handshake_timeout() -> Duration {
+    Duration::from_secs(get_env_value("OPTIM_TLS_HANDSHAKE_TIMEOUT", 10))
+}
+
+/// Whether http2 is enabled for the listener, false means http1 only.
+pub fn is_http2_enabled() -> bool {
+    get_env_value("OPTIM_HTTP2", true)
+}
+
+/// Max concurrent streams of a http2 connection, 0 means the default(200).
+pub fn get_http2_max_concurrent_streams() -> u32 {
+    get_env_value("OPTIM_HTTP2_MAX_CONCURRENT_STREAMS", 0)
+}
+
+/// Interval of the http2 keep-alive ping, 0 means disabled.
+pub fn get_http2_keep_alive_interval() -> Duration {
+    Duration::from_secs(get_env_value("OPTIM_HTTP2_KEEP_ALIVE_INTERVAL", 0))
+}
+
+/// The http2 connection is closed if the keep-alive ping is not acknowledged
+/// within the timeout.
+pub fn get_http2_keep_alive_timeout() -> Duration {
+    Duration::from_secs(get_env_value("OPTIM_HTTP2_KEEP_ALIVE_TIMEOUT", 20))
+}
+
+/// Whether the http1 connection is kept alive for the next request.
+pub fn is_http1_keep_alive() -> bool {
+    get_env_value("OPTIM_HTTP1_KEEP_ALIVE", true)
+}
+
+/// Timeout of reading the http1 request headers, 0 means no timeout.
+pub fn get_http1_header_read_timeout() -> Duration {
+    Duration::from_secs(get_env_value("OPTIM_HTTP1_HEADER_READ_TIMEOUT", 30))
+}
+
+/// Set TCP_NODELAY of the accepted connections.
+pub fn is_tcp_nodelay() -> bool {
+    get_env_value("OPTIM_TCP_NODELAY", false)
+}
+
+/// Port of grpc server.
+#[cfg(feature = "grpc")]
+pub fn get_grpc_port() -> u16 {
+    get_env_value("OPTIM_GRPC_PORT", 50051)
+}
+
+/// Headers of http source passed through to the response,
+/// the item ends with `*` matches the prefix, e.g. `x-amz-meta-*`.
+pub fn get_pass_headers() -> Vec<String> {
+    get_env_string("OPTIM_PASS_HEADERS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|item| item.trim().to_lowercase())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+/// Tasks applied to every pipeline after loading, the format is the same as
+/// the query of `/pipeline-images`, e.g. `effect=grayscale&enhance=true`.
+pub fn get_pre_tasks() -> String {
+    get_env_value("OPTIM_PRE_TASKS", "".to_string())
+}
+
+/// Tasks applied to every pipeline before optimizing, e.g. `watermark=url|rightBottom`.
+pub fn get_post_tasks() -> String {
+    get_env_value("OPTIM_POST_TASKS", "".to_string())
+}
+
+/// Json of the social card template, e.g. OPTIM_CARD_TEMPLATE_BLOG={"background":"cards/blog.png",...},
+/// it can also be read from OPTIM_CARD_TEMPLATE_BLOG_FILE.
+pub fn get_card_template(name: &str) -> Option<String> {
+    get_env_string(&format!("OPTIM_CARD_TEMPLATE_{}", name.to_uppercase()))
+}
+
+/// Watermarks of the size class, e.g. OPTIM_WATERMARK_SIZES_LOGO=0=small.png,800=large.png,
+/// the format is min_width=url and sorted by min width.
+pub fn get_watermark_sizes(name: &str) -> Vec<(u32, String)> {
+    let key = format!("OPTIM_WATERMARK_SIZES_{}", name.to_uppercase());
+    let mut sizes: Vec<(u32, String)> = get_env_string(&key)
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|item| {
+            let (width, url) = item.split_once('=')?;
+            Some((width.trim().parse().ok()?, url.trim().to_string()))
+        })
+        .collect();
+    sizes.sort_by_key(|(width, _)| *width);
+    sizes
+}
+
+/// Quality of the preset for the format, e.g. OPTIM_QUALITY_HIGH=jpeg:85,avif:60,webp:80,
+/// `*` matches the other formats, and the format not configured uses the default of preset.
+pub fn get_quality_preset(name: &str, format: &str) -> Option<u8> {
+    let default = match name {
+        "low" => "jpeg:60,webp:55,avif:40,*:60",
+        "medium" => "jpeg:75,webp:70,avif:50,*:75",
+        "high" => "jpeg:85,webp:80,avif:60,*:85",
+        "lossless" => "*:100",
+        _ => return None,
+    };
+    let find = |value: &str, format: &str| {
+        value.split(',').find_map(|item| {
+            let (key, quality) = item.split_once(':')?;
+            if key.trim() != format {
+                return None;
+            }
+            quality
+                .trim()
+                .parse::<u8>()
+                .ok()
+                .map(|quality| quality.min(100))
+        })
+    };
+    let value =
+        get_env_string(&format!("OPTIM_QUALITY_{}", name.to_uppercase())).unwrap_or_default();
+    find(&value, format)
+        .or_else(|| find(&value, "*"))
+        .or_else(|| find(default, format))
+        .or_else(|| find(default, "*"))
+}
+
+/// Max count of the cached watermarks, 0 means disabled.
+pub fn get_watermark_cache_size() -> usize {
+    get_env_value("OPTIM_WATERMARK_CACHE_SIZE", 10)
+}
+
+/// Time to live of the cached watermark, 0 means never expired.
+pub fn get_watermark_cache_ttl() -> Duration {
+    Duration::from_secs(get_env_value("OPTIM_WATERMARK_CACHE_TTL", 0))
+}
+
+/// Max bytes of a decoded watermark to be cached, 0 means no limit.
+pub fn get_watermark_cache_max_bytes() -> usize {
+    get_env_value("OPTIM_WATERMARK_CACHE_MAX_BYTES", 0)
+}
+
+/// Watermark urls loaded into cache when the server is starting.
+pub fn get_watermark_preload() -> Vec<String> {
+    get_env_string("OPTIM_WATERMARK_PRELOAD")
+        .unwrap_or_default()
+        .split(',')
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+/// Model file of face detection, e.g. seeta_fd_frontal_v1.0.bin.
+#[cfg(feature = "face-detection")]
+pub fn get_face_model() -> String {
+    get_env_value("OPTIM_FACE_MODEL", "seeta_fd_frontal_v1.0.bin".to_string())
+}
@@ -0,0 +1,104 @@
+use crate::config;
+use once_cell::sync::Lazy;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+// 配置了编码线程数时才创建
+static POOL: Lazy<Option<Sender<Job>>> = Lazy::new(new_pool);
+
+// 降低当前线程的调度优先级，仅支持linux
+#[cfg(target_os = "linux")]
+fn set_nice(nice: i32) {
+    // linux中各线程有独立的nice值，0表示当前线程
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } != 0 {
+        tracing::warn!(
+            nice,
+            "Set nice of encoder thread fail, {}",
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_nice(_nice: i32) {}
+
+// 将当前线程绑定至指定的cpu，仅支持linux
+#[cfg(target_os = "linux")]
+fn set_affinity(cpus: &[usize]) {
+    if cpus.is_empty() {
+        return;
+    }
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    for cpu in cpus {
+        unsafe { libc::CPU_SET(*cpu, &mut set) };
+    }
+    if unsafe { libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) } != 0 {
+        tracing::warn!(
+            ?cpus,
+            "Set cpu affinity of encoder thread fail, {}",
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_affinity(_cpus: &[usize]) {}
+
+fn new_pool() -> Option<Sender<Job>> {
+    let threads = config::get_encode_threads();
+    if threads == 0 {
+        return None;
+    }
+    let nice = config::get_encode_nice();
+    let cpus = config::get_encode_cpus();
+    let (tx, rx) = mpsc::channel::<Job>();
+    let rx = Arc::new(Mutex::new(rx));
+    for index in 0..threads {
+        let rx = rx.clone();
+        let cpus = cpus.clone();
+        let result = std::thread::Builder::new()
+            .name(format!("encoder-{index}"))
+            .spawn(move || {
+                set_nice(nice);
+                set_affinity(&cpus);
+                loop {
+                    let job = rx.lock().unwrap_or_else(|e| e.into_inner()).recv();
+                    // 发送端不会关闭，仅防止出错
+                    let Ok(job) = job else {
+                        return;
+                    };
+                    // 出错时结果的接收端失败，线程继续处理其它任务
+                    let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(job));
+                }
+            });
+        if let Err(e) = result {
+            tracing::error!("Spawn encoder thread fail, {e}");
+        }
+    }
+    tracing::info!(threads, nice, ?cpus, "Encoder threads are started");
+    Some(tx)
+}
+
+/// Whether the dedicated encoder threads are configured.
+pub fn is_enabled() -> bool {
+    POOL.is_some()
+}
+
+/// Run the function on the encoder threads, the receiver fails
+/// if the function panics or the pool is not enabled.
+pub fn spawn<T, F>(f: F) -> oneshot::Receiver<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    let (tx, rx) = oneshot::channel();
+    if let Some(pool) = POOL.as_ref() {
+        let _ = pool.send(Box::new(move || {
+            let _ = tx.send(f());
+        }));
+    }
+    rx
+}
@@ -1,3 +1,8 @@
+use crate::barcode::BarcodeError;
+use crate::card::CardError;
+use crate::config;
+use crate::image_processing::{self, ImageProcessingError};
+use crate::moderation::ModerationError;
 use axum::extract::multipart;
 use axum::http::{header, HeaderValue, Method, StatusCode, Uri};
 use axum::response::{IntoResponse, Response};
@@ -5,11 +10,79 @@ use axum::{BoxError, Json};
 use serde::Serialize;
 use tracing::error;
 
+pub const ERROR_CODE_HEADER: &str = "X-Optim-Error-Code";
+
+/// Stable code of the error, clients and alerting can branch on it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    InvalidParams,
+    Unauthorized,
+    Forbidden,
+    SourceNotFound,
+    SourceUnavailable,
+    DecodeFailed,
+    UnsupportedFormat,
+    EncodeFailed,
+    LimitExceeded,
+    Overloaded,
+    Timeout,
+    LoadTimeout,
+    DecodeTimeout,
+    EncodeTimeout,
+    ModerationFlagged,
+    Unavailable,
+    #[default]
+    Internal,
+}
+
+impl ErrorCode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::InvalidParams => "INVALID_PARAMS",
+            ErrorCode::Unauthorized => "UNAUTHORIZED",
+            ErrorCode::Forbidden => "FORBIDDEN",
+            ErrorCode::SourceNotFound => "SOURCE_NOT_FOUND",
+            ErrorCode::SourceUnavailable => "SOURCE_UNAVAILABLE",
+            ErrorCode::DecodeFailed => "DECODE_FAILED",
+            ErrorCode::UnsupportedFormat => "UNSUPPORTED_FORMAT",
+            ErrorCode::EncodeFailed => "ENCODE_FAILED",
+            ErrorCode::LimitExceeded => "LIMIT_EXCEEDED",
+            ErrorCode::Overloaded => "OVERLOADED",
+            ErrorCode::Timeout => "TIMEOUT",
+            ErrorCode::LoadTimeout => "LOAD_TIMEOUT",
+            ErrorCode::DecodeTimeout => "DECODE_TIMEOUT",
+            ErrorCode::EncodeTimeout => "ENCODE_TIMEOUT",
+            ErrorCode::ModerationFlagged => "MODERATION_FLAGGED",
+            ErrorCode::Unavailable => "UNAVAILABLE",
+            ErrorCode::Internal => "INTERNAL",
+        }
+    }
+    // 根据分类与状态码获取错误码
+    fn from_category(category: &str, status: u16) -> Self {
+        match (category, status) {
+            ("validate" | "invalid" | "regexp" | "from_utf8", _) => ErrorCode::InvalidParams,
+            ("api_key", _) | (_, 401) => ErrorCode::Unauthorized,
+            ("forbidden", _) | (_, 403) => ErrorCode::Forbidden,
+            ("too_large" | "quota", _) | (_, 413 | 429) => ErrorCode::LimitExceeded,
+            ("overload", _) => ErrorCode::Overloaded,
+            ("timeout", _) | (_, 408) => ErrorCode::Timeout,
+            ("stopping", _) | (_, 503) => ErrorCode::Unavailable,
+            ("multipart", _) => ErrorCode::InvalidParams,
+            _ => ErrorCode::Internal,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct HTTPError {
     pub message: String,
     pub category: String,
     pub status: u16,
+    pub code: ErrorCode,
+    /// Violated constraints of the params.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub violations: Vec<String>,
 }
 pub type HTTPResult<T> = Result<T, HTTPError>;
 
@@ -19,6 +92,8 @@ impl HTTPError {
             message: message.to_string(),
             category: category.to_string(),
             status: 400,
+            code: ErrorCode::from_category(category, 400),
+            violations: vec![],
         }
     }
     pub fn new_with_category_status(message: &str, category: &str, status: u16) -> Self {
@@ -26,8 +101,15 @@ impl HTTPError {
             message: message.to_string(),
             category: category.to_string(),
             status,
+            code: ErrorCode::from_category(category, status),
+            violations: vec![],
         }
     }
+    /// Set the code of error, otherwise it is derived from the category and status.
+    pub fn with_code(mut self, code: ErrorCode) -> Self {
+        self.code = code;
+        self
+    }
 }
 impl Default for HTTPError {
     fn default() -> Self {
@@ -36,6 +118,8 @@ impl Default for HTTPError {
             category: "".to_string(),
             // 默认使用400为状态码
             status: 400,
+            code: ErrorCode::Internal,
+            violations: vec![],
         }
     }
 }
@@ -45,10 +129,19 @@ impl IntoResponse for HTTPError {
             Ok(status) => status,
             Err(_) => StatusCode::BAD_REQUEST,
         };
+        let code = HeaderValue::from_static(self.code.as_str());
         // 对于出错设置为no-cache
         let mut res = Json(self).into_response();
         res.headers_mut()
             .insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+        res.headers_mut().insert(ERROR_CODE_HEADER, code);
+        // 服务不可用时提示客户端稍后重试
+        if status == StatusCode::SERVICE_UNAVAILABLE {
+            res.headers_mut().insert(
+                header::RETRY_AFTER,
+                HeaderValue::from(config::get_retry_after()),
+            );
+        }
         (status, res).into_response()
     }
 }
@@ -58,6 +151,7 @@ impl From<std::string::FromUtf8Error> for HTTPError {
         HTTPError {
             message: error.to_string(),
             category: "from_utf8".to_string(),
+            code: ErrorCode::InvalidParams,
             ..Default::default()
         }
     }
@@ -67,6 +161,9 @@ impl From<multipart::MultipartError> for HTTPError {
         HTTPError {
             message: error.to_string(),
             category: "multipart".to_string(),
+            // 超出大小限制时为413
+            status: error.status().as_u16(),
+            code: ErrorCode::from_category("multipart", error.status().as_u16()),
             ..Default::default()
         }
     }
@@ -77,15 +174,106 @@ impl From<imageoptimize::ImageError> for HTTPError {
         HTTPError {
             message: error.to_string(),
             category: "image".to_string(),
+            code: ErrorCode::DecodeFailed,
             ..Default::default()
         }
     }
 }
-impl From<imageoptimize::ImageProcessingError> for HTTPError {
-    fn from(error: imageoptimize::ImageProcessingError) -> Self {
+impl From<BarcodeError> for HTTPError {
+    fn from(error: BarcodeError) -> Self {
+        let code = match &error {
+            BarcodeError::Png { .. } => ErrorCode::EncodeFailed,
+            _ => ErrorCode::InvalidParams,
+        };
+        HTTPError {
+            message: error.to_string(),
+            category: "barcode".to_string(),
+            code,
+            ..Default::default()
+        }
+    }
+}
+impl From<CardError> for HTTPError {
+    fn from(error: CardError) -> Self {
+        // 模板不存在为请求参数错误，其它则为模板配置错误
+        let (status, code) = match &error {
+            CardError::TemplateNotFound { .. } => (404, ErrorCode::InvalidParams),
+            CardError::Png { .. } => (500, ErrorCode::EncodeFailed),
+            _ => (500, ErrorCode::Internal),
+        };
+        HTTPError::new_with_category_status(&error.to_string(), "card", status).with_code(code)
+    }
+}
+impl From<ImageProcessingError> for HTTPError {
+    fn from(error: ImageProcessingError) -> Self {
+        if let ImageProcessingError::Moderation { source } = &error {
+            let (status, code) = match source {
+                ModerationError::Flagged { .. } => (451, ErrorCode::ModerationFlagged),
+                ModerationError::Unavailable { .. } => (503, ErrorCode::Unavailable),
+            };
+            return HTTPError::new_with_category_status(&error.to_string(), "moderation", status)
+                .with_code(code);
+        }
+        // 各阶段的超时使用对应的错误码
+        if let ImageProcessingError::Timeout { stage, .. } = &error {
+            let code = match *stage {
+                image_processing::STAGE_LOAD => ErrorCode::LoadTimeout,
+                image_processing::STAGE_DECODE => ErrorCode::DecodeTimeout,
+                image_processing::STAGE_ENCODE => ErrorCode::EncodeTimeout,
+                _ => ErrorCode::Timeout,
+            };
+            return HTTPError::new_with_category_status(&error.to_string(), "timeout", 504)
+                .with_code(code);
+        }
+        if let ImageProcessingError::Validation { violations } = error {
+            return HTTPError {
+                message: "params validate fail".to_string(),
+                category: "validate".to_string(),
+                status: 422,
+                code: ErrorCode::InvalidParams,
+                violations,
+            };
+        }
+        let code = match &error {
+            ImageProcessingError::ParamsInvalid { .. }
+            | ImageProcessingError::ParseInt { .. }
+            | ImageProcessingError::FromUtf { .. } => ErrorCode::InvalidParams,
+            ImageProcessingError::Reqwest { source }
+                if source.status() == Some(reqwest::StatusCode::NOT_FOUND) =>
+            {
+                ErrorCode::SourceNotFound
+            }
+            ImageProcessingError::Reqwest { .. } | ImageProcessingError::HTTPHeaderToStr { .. } => {
+                ErrorCode::SourceUnavailable
+            }
+            ImageProcessingError::Io { source }
+                if source.kind() == std::io::ErrorKind::NotFound =>
+            {
+                ErrorCode::SourceNotFound
+            }
+            ImageProcessingError::UnsupportedFormat { .. } => ErrorCode::UnsupportedFormat,
+            ImageProcessingError::OutputTooLarge { .. } => ErrorCode::LimitExceeded,
+            ImageProcessingError::DiffTooLarge { .. } => ErrorCode::EncodeFailed,
+            ImageProcessingError::Image { source } => match source {
+                image::ImageError::Unsupported(_) => ErrorCode::UnsupportedFormat,
+                image::ImageError::Limits(_) => ErrorCode::LimitExceeded,
+                image::ImageError::Encoding(_) => ErrorCode::EncodeFailed,
+                _ => ErrorCode::DecodeFailed,
+            },
+            ImageProcessingError::Base64Decode { .. }
+            | ImageProcessingError::Images { .. }
+            | ImageProcessingError::Tiff { .. } => ErrorCode::DecodeFailed,
+            #[cfg(feature = "raw")]
+            ImageProcessingError::Raw { .. } => ErrorCode::DecodeFailed,
+            ImageProcessingError::Encode { .. } => ErrorCode::EncodeFailed,
+            #[cfg(feature = "video")]
+            ImageProcessingError::Video { .. } => ErrorCode::EncodeFailed,
+            _ => ErrorCode::Internal,
+        };
         HTTPError {
             message: error.to_string(),
             category: "image_process".to_string(),
+            code,
             ..Default::default()
         }
     }
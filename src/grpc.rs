@@ -0,0 +1,195 @@
+use crate::api_key;
+use crate::error::{HTTPError, HTTPResult};
+use crate::image_encoder::EncoderOptions;
+use crate::image_processing::{self, OutputType, Task};
+use crate::middleware;
+use crate::optim;
+use crate::state;
+use crate::task_local::{API_KEY, STORAGE, TRACE_ID};
+use base64::{engine::general_purpose, Engine as _};
+use nanoid::nanoid;
+use std::net::SocketAddr;
+use tonic::metadata::MetadataValue;
+use tonic::{Request, Response, Status};
+
+pub mod pb {
+    tonic::include_proto!("optim");
+}
+
+use pb::image_optim_server::{ImageOptim, ImageOptimServer};
+use pb::{source, ImageReply, OptimizeRequest, PipelineRequest, ResizeRequest, Source};
+
+impl From<HTTPError> for Status {
+    fn from(error: HTTPError) -> Self {
+        let message = format!("{}: {}", error.category, error.message);
+        let mut status = match error.status {
+            401 => Status::unauthenticated(message),
+            403 => Status::permission_denied(message),
+            429 => Status::resource_exhausted(message),
+            451 => Status::failed_precondition(message),
+            503 => Status::unavailable(message),
+            504 => Status::deadline_exceeded(message),
+            400..=499 => Status::invalid_argument(message),
+            _ => Status::internal(message),
+        };
+        status.metadata_mut().insert(
+            "x-optim-error-code",
+            MetadataValue::from_static(error.code.as_str()),
+        );
+        status
+    }
+}
+
+// 图片来源转换为加载任务，路径非url则为图片目录中的文件
+fn new_load_task(source: Option<Source>) -> HTTPResult<Task> {
+    let source = source.ok_or_else(|| HTTPError::new("source is required", "validate"))?;
+    let data = match source.source {
+        Some(source::Source::Data(data)) => general_purpose::STANDARD.encode(data),
+        Some(source::Source::Path(path)) => {
+            if path.starts_with("http") || path.starts_with("file://") {
+                path
+            } else {
+                optim::get_load_url(&path)?
+            }
+        }
+        None => return Err(HTTPError::new("source is empty", "validate")),
+    };
+    Ok(Task::Load {
+        data,
+        ext: source.ext,
+        page: 0,
+        bytes: None,
+    })
+}
+
+fn new_optim_task(output_type: &str, quality: u32, speed: u32) -> HTTPResult<Task> {
+    let output_type = if output_type.is_empty() {
+        None
+    } else {
+        Some(output_type.parse::<OutputType>()?)
+    };
+    let quality = if quality == 0 { 80 } else { quality.min(100) };
+    let speed = if speed == 0 { 3 } else { speed.min(10) };
+    Ok(Task::Optim {
+        output_type,
+        quality: quality as u8,
+        speed: speed as u8,
+        options: EncoderOptions::default(),
+    })
+}
+
+// 校验api key并在对应的task local中生成任务并执行，
+// 与http接口一致可通过metadata的x-storage指定存储
+async fn run<T, F>(request: Request<T>, new_tasks: F) -> Result<Response<ImageReply>, Status>
+where
+    F: FnOnce(T) -> HTTPResult<Vec<Task>>,
+{
+    // 停止中则不再接收新的请求
+    if state::is_stopping() {
+        return Err(Status::unavailable("server is stopping"));
+    }
+    let mut name = "".to_string();
+    if api_key::is_enabled() {
+        let key = request
+            .metadata()
+            .get("x-api-key")
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        let api_key =
+            api_key::get(key).ok_or_else(|| Status::unauthenticated("api key is invalid"))?;
+        api_key::check_request(api_key).map_err(Status::resource_exhausted)?;
+        name = api_key.name.clone();
+    }
+    let storage = request
+        .metadata()
+        .get("x-storage")
+        .map(|value| value.to_str().unwrap_or_default().to_string());
+    let params = request.into_inner();
+    let task = async move {
+        let task = async move { optim::pipeline(new_tasks(params)?).await };
+        match storage {
+            Some(name) => {
+                let path = middleware::get_storage_path(&name)?;
+                STORAGE.scope(path, task).await
+            }
+            None => task.await,
+        }
+    };
+    let result = API_KEY
+        .scope(name, TRACE_ID.scope(nanoid!(6), task))
+        .await?;
+    Ok(Response::new(ImageReply {
+        size: result.data.len() as u64,
+        data: result.data,
+        output_type: result.output_type,
+        original_size: result.original_size as u64,
+        diff: result.diff,
+        ratio: result.ratio as u32,
+    }))
+}
+
+#[derive(Default)]
+struct ImageOptimService;
+
+#[tonic::async_trait]
+impl ImageOptim for ImageOptimService {
+    async fn optimize(
+        &self,
+        request: Request<OptimizeRequest>,
+    ) -> Result<Response<ImageReply>, Status> {
+        run(request, |params| {
+            let mut tasks = vec![
+                new_load_task(params.source)?,
+                new_optim_task(&params.output_type, params.quality, params.speed)?,
+            ];
+            if params.diff {
+                tasks.push(Task::Diff);
+            }
+            Ok(tasks)
+        })
+        .await
+    }
+    async fn resize(
+        &self,
+        request: Request<ResizeRequest>,
+    ) -> Result<Response<ImageReply>, Status> {
+        run(request, |params| {
+            Ok(vec![
+                new_load_task(params.source)?,
+                Task::Resize {
+                    width: params.width,
+                    height: params.height,
+                    aspect: Default::default(),
+                    rounding: Default::default(),
+                },
+                new_optim_task(&params.output_type, params.quality, params.speed)?,
+            ])
+        })
+        .await
+    }
+    async fn pipeline(
+        &self,
+        request: Request<PipelineRequest>,
+    ) -> Result<Response<ImageReply>, Status> {
+        run(request, |params| {
+            let mut tasks = vec![];
+            if params.source.is_some() {
+                tasks.push(new_load_task(params.source)?);
+            }
+            let desc = params.tasks.into_iter().map(|task| task.params).collect();
+            tasks.extend(image_processing::parse_tasks(desc)?);
+            Ok(tasks)
+        })
+        .await
+    }
+}
+
+/// Start the grpc server on a separate port.
+pub async fn serve(port: u16) -> Result<(), tonic::transport::Error> {
+    let addr = SocketAddr::from(([0, 0, 0, 0], port));
+    tracing::info!(port, "Grpc server is starting");
+    tonic::transport::Server::builder()
+        .add_service(ImageOptimServer::new(ImageOptimService))
+        .serve(addr)
+        .await
+}
@@ -0,0 +1,135 @@
+use crate::config;
+use lru::LruCache;
+use once_cell::sync::Lazy;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use std::net::SocketAddr;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+// dns解析结果与并发限制最多保存的host数量，超过则淘汰最久未使用的
+const MAX_HOSTS: usize = 1024;
+
+fn new_host_cache<T>() -> LruCache<String, T> {
+    LruCache::new(NonZeroUsize::new(MAX_HOSTS).unwrap_or(NonZeroUsize::MIN))
+}
+
+type DnsCache = Arc<Mutex<LruCache<String, (Vec<SocketAddr>, Instant)>>>;
+
+// 缓存dns解析结果，过期后重新解析
+struct CachedResolver {
+    cache: DnsCache,
+    ttl: Duration,
+}
+
+impl Resolve for CachedResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().to_string();
+        let cache = self.cache.clone();
+        let ttl = self.ttl;
+        Box::pin(async move {
+            let cached = cache.lock().ok().and_then(|mut cache| {
+                cache
+                    .get(&host)
+                    .filter(|(_, resolved_at)| resolved_at.elapsed() < ttl)
+                    .map(|(addrs, _)| addrs.clone())
+            });
+            if let Some(addrs) = cached {
+                return Ok(Box::new(addrs.into_iter()) as Addrs);
+            }
+            let addrs: Vec<SocketAddr> =
+                tokio::net::lookup_host((host.as_str(), 0)).await?.collect();
+            if let Ok(mut cache) = cache.lock() {
+                cache.put(host, (addrs.clone(), Instant::now()));
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+// 重定向仅允许同一host(包括端口)，避免代理的host白名单通过重定向绕过，
+// 以及加载的认证信息发送至其它host
+fn new_redirect_policy() -> reqwest::redirect::Policy {
+    reqwest::redirect::Policy::custom(|attempt| {
+        if attempt.previous().len() >= 10 {
+            return attempt.error("too many redirects");
+        }
+        let same_host = attempt.previous().first().is_some_and(|first| {
+            first.host_str() == attempt.url().host_str() && first.port() == attempt.url().port()
+        });
+        if !same_host {
+            let host = attempt.url().host_str().unwrap_or_default().to_string();
+            return attempt.error(format!("redirect to another host({host}) is not allowed"));
+        }
+        // 认证信息(包括自定义请求头)会随重定向发送，不允许由https降级为http
+        let downgraded = attempt
+            .previous()
+            .last()
+            .is_some_and(|last| last.scheme() == "https" && attempt.url().scheme() != "https");
+        if downgraded {
+            return attempt.error("redirect from https to http is not allowed");
+        }
+        attempt.follow()
+    })
+}
+
+// 共享的http客户端，复用连接
+static CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
+    let mut builder = reqwest::Client::builder()
+        .redirect(new_redirect_policy())
+        .pool_max_idle_per_host(config::get_loader_pool_idle_per_host())
+        .pool_idle_timeout(config::get_loader_pool_idle_timeout())
+        .connect_timeout(config::get_loader_connect_timeout());
+    let ttl = config::get_loader_dns_ttl();
+    if !ttl.is_zero() {
+        builder = builder.dns_resolver(Arc::new(CachedResolver {
+            cache: Arc::new(Mutex::new(new_host_cache())),
+            ttl,
+        }));
+    }
+    builder.build().unwrap_or_else(|e| {
+        tracing::error!("Build http client fail, {e}");
+        reqwest::Client::new()
+    })
+});
+
+/// Get the shared http client used by all loaders.
+pub fn get_client() -> reqwest::Client {
+    CLIENT.clone()
+}
+
+static HOST_SEMAPHORES: Lazy<Mutex<LruCache<String, Arc<Semaphore>>>> =
+    Lazy::new(|| Mutex::new(new_host_cache()));
+
+/// Acquire the permit of the url's host to limit the concurrent requests,
+/// none if no limit is configured.
+pub async fn acquire(url: &str) -> Option<OwnedSemaphorePermit> {
+    let max = config::get_loader_max_connections_per_host();
+    if max == 0 {
+        return None;
+    }
+    let host = reqwest::Url::parse(url)
+        .ok()?
+        .host_str()
+        .unwrap_or_default()
+        .to_string();
+    let semaphore = {
+        let mut semaphores = HOST_SEMAPHORES.lock().ok()?;
+        if semaphores.len() >= semaphores.cap().get() && !semaphores.contains(&host) {
+            // 优先淘汰无请求使用的，避免使用中的被替换后并发超出限制
+            let idle = semaphores
+                .iter()
+                .rev()
+                .find(|(_, semaphore)| Arc::strong_count(semaphore) == 1)
+                .map(|(host, _)| host.clone());
+            if let Some(idle) = idle {
+                semaphores.pop(&idle);
+            }
+        }
+        semaphores
+            .get_or_insert(host, || Arc::new(Semaphore::new(max)))
+            .clone()
+    };
+    semaphore.acquire_owned().await.ok()
+}
@@ -0,0 +1,463 @@
+use dssim::Dssim;
+use image::imageops::FilterType;
+use image::{DynamicImage, RgbaImage};
+use rgb::FromSlice;
+use serde::Serialize;
+
+// 计算颜色时缩小图片的尺寸
+const SAMPLE_SIZE: u32 = 100;
+
+#[derive(Serialize, Debug)]
+pub struct DominantColor {
+    pub color: String,
+    pub percent: f64,
+}
+
+// 颜色分组，按最大跨度的通道在中位数处拆分
+struct ColorBox {
+    pixels: Vec<[u8; 3]>,
+}
+
+impl ColorBox {
+    // 跨度最大的通道与其跨度
+    fn widest_channel(&self) -> (usize, u8) {
+        let mut result = (0, 0);
+        for channel in 0..3 {
+            let min = self.pixels.iter().map(|p| p[channel]).min().unwrap_or(0);
+            let max = self.pixels.iter().map(|p| p[channel]).max().unwrap_or(0);
+            if max - min > result.1 {
+                result = (channel, max - min);
+            }
+        }
+        result
+    }
+    fn split(mut self) -> (ColorBox, ColorBox) {
+        let (channel, _) = self.widest_channel();
+        self.pixels.sort_unstable_by_key(|p| p[channel]);
+        let other = self.pixels.split_off(self.pixels.len() / 2);
+        (self, ColorBox { pixels: other })
+    }
+    fn average(&self) -> [u8; 3] {
+        let mut sum = [0_u64; 3];
+        for p in self.pixels.iter() {
+            for channel in 0..3 {
+                sum[channel] += p[channel] as u64;
+            }
+        }
+        let count = self.pixels.len().max(1) as u64;
+        [
+            (sum[0] / count) as u8,
+            (sum[1] / count) as u8,
+            (sum[2] / count) as u8,
+        ]
+    }
+}
+
+/// Kind of image content.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentKind {
+    Photo,
+    Graphic,
+}
+
+impl ContentKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ContentKind::Photo => "photo",
+            ContentKind::Graphic => "graphic",
+        }
+    }
+}
+
+// 分类时缩小图片的尺寸
+const CLASSIFY_SIZE: u32 = 256;
+
+/// Classify the image as flat graphic or photo,
+/// the graphic has few colors or most of the pixels are the same as the neighbor.
+pub fn classify_content(di: &DynamicImage) -> ContentKind {
+    // 使用nearest缩小，避免插值产生新的颜色
+    let img = if di.width() > CLASSIFY_SIZE || di.height() > CLASSIFY_SIZE {
+        di.resize(CLASSIFY_SIZE, CLASSIFY_SIZE, FilterType::Nearest)
+            .to_rgba8()
+    } else {
+        di.to_rgba8()
+    };
+    let mut colors = std::collections::HashSet::new();
+    let mut flat = 0;
+    let mut total = 0;
+    for (x, y, pixel) in img.enumerate_pixels() {
+        colors.insert(u32::from_be_bytes(pixel.0));
+        if x + 1 >= img.width() {
+            continue;
+        }
+        let next = img.get_pixel(x + 1, y);
+        let diff: u32 = (0..4)
+            .map(|i| (pixel[i] as i32 - next[i] as i32).unsigned_abs())
+            .sum();
+        total += 1;
+        // 与相邻像素基本一致则为平坦区域
+        if diff <= 3 {
+            flat += 1;
+        }
+    }
+    let flat_ratio = flat as f64 / total.max(1) as f64;
+    if colors.len() <= 256 || (flat_ratio >= 0.7 && colors.len() <= 8192) {
+        ContentKind::Graphic
+    } else {
+        ContentKind::Photo
+    }
+}
+
+/// Get the dominant colors of image by median cut,
+/// it runs on a downscaled copy and ignores the transparent pixels.
+pub fn get_dominant_colors(di: &DynamicImage, count: usize) -> Vec<DominantColor> {
+    let rgba = di
+        .resize(SAMPLE_SIZE, SAMPLE_SIZE, FilterType::Triangle)
+        .to_rgba8();
+    let pixels: Vec<[u8; 3]> = rgba
+        .pixels()
+        .filter(|p| p[3] >= 128)
+        .map(|p| [p[0], p[1], p[2]])
+        .collect();
+    let total = pixels.len();
+    if total == 0 || count == 0 {
+        return vec![];
+    }
+
+    let mut boxes = vec![ColorBox { pixels }];
+    while boxes.len() < count {
+        // 选择可拆分且跨度最大的分组
+        let found = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, item)| item.pixels.len() > 1)
+            .map(|(index, item)| (index, item.widest_channel().1))
+            .filter(|(_, range)| *range > 0)
+            .max_by_key(|(_, range)| *range);
+        let Some((index, _)) = found else {
+            break;
+        };
+        let (a, b) = boxes.swap_remove(index).split();
+        boxes.push(a);
+        boxes.push(b);
+    }
+    boxes.sort_by_key(|item| std::cmp::Reverse(item.pixels.len()));
+
+    boxes
+        .iter()
+        .map(|item| {
+            let [r, g, b] = item.average();
+            let percent = 100.0 * item.pixels.len() as f64 / total as f64;
+            DominantColor {
+                color: format!("#{r:02x}{g:02x}{b:02x}"),
+                percent: (percent * 100.0).round() / 100.0,
+            }
+        })
+        .collect()
+}
+
+#[derive(Serialize, Debug)]
+pub struct PerceptualHash {
+    pub ahash: String,
+    pub dhash: String,
+    pub phash: String,
+}
+
+// 缩放为指定尺寸的灰度值
+fn get_gray_values(di: &DynamicImage, width: u32, height: u32) -> Vec<f64> {
+    di.resize_exact(width, height, FilterType::Triangle)
+        .to_luma8()
+        .pixels()
+        .map(|p| p[0] as f64)
+        .collect()
+}
+
+// 按顺序将bool转换为64位的值
+fn to_hash(bits: impl Iterator<Item = bool>) -> u64 {
+    bits.take(64)
+        .fold(0_u64, |hash, bit| (hash << 1) | bit as u64)
+}
+
+// 平均值hash，像素值大于平均值则为1
+fn get_average_hash(di: &DynamicImage) -> u64 {
+    let values = get_gray_values(di, 8, 8);
+    let mean = values.iter().sum::<f64>() / values.len() as f64;
+    to_hash(values.iter().map(|v| *v > mean))
+}
+
+// 差异值hash，像素值大于右侧像素则为1
+fn get_difference_hash(di: &DynamicImage) -> u64 {
+    let values = get_gray_values(di, 9, 8);
+    let bits = (0..8).flat_map(|y| {
+        let row = &values[y * 9..(y + 1) * 9];
+        (0..8).map(move |x| row[x] > row[x + 1])
+    });
+    to_hash(bits)
+}
+
+// 感知hash，32x32的DCT变换后取左上角8x8低频部分，大于中位数则为1
+fn get_perceptual_hash(di: &DynamicImage) -> u64 {
+    const SIZE: usize = 32;
+    let values = get_gray_values(di, SIZE as u32, SIZE as u32);
+    let cos_table: Vec<f64> = (0..8 * SIZE)
+        .map(|i| {
+            let (u, x) = (i / SIZE, i % SIZE);
+            ((2 * x + 1) as f64 * u as f64 * std::f64::consts::PI / (2 * SIZE) as f64).cos()
+        })
+        .collect();
+    let mut coefficients = Vec::with_capacity(64);
+    for u in 0..8 {
+        for v in 0..8 {
+            let mut sum = 0.0;
+            for y in 0..SIZE {
+                for x in 0..SIZE {
+                    sum += values[y * SIZE + x] * cos_table[u * SIZE + y] * cos_table[v * SIZE + x];
+                }
+            }
+            coefficients.push(sum);
+        }
+    }
+    // 中位数不包括直流分量
+    let mut sorted = coefficients[1..].to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let median = sorted[sorted.len() / 2];
+    to_hash(coefficients.iter().map(|v| *v > median))
+}
+
+/// Get the average, difference and perceptual hash of image,
+/// the values are 64-bit hex strings.
+pub fn get_perceptual_hashes(di: &DynamicImage) -> PerceptualHash {
+    PerceptualHash {
+        ahash: format!("{:016x}", get_average_hash(di)),
+        dhash: format!("{:016x}", get_difference_hash(di)),
+        phash: format!("{:016x}", get_perceptual_hash(di)),
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct ExposureStats {
+    /// Count of pixels for each value(0-255) of the channels.
+    pub red: Vec<u64>,
+    pub green: Vec<u64>,
+    pub blue: Vec<u64>,
+    pub luminance: Vec<u64>,
+    pub mean_luminance: f64,
+    /// Percentage of pixels with luminance 0.
+    pub shadow_clipping: f64,
+    /// Percentage of pixels with luminance 255.
+    pub highlight_clipping: f64,
+}
+
+// 保留两位小数
+fn round2(value: f64) -> f64 {
+    (value * 100.0).round() / 100.0
+}
+
+/// Get the histograms of each channel and luminance(bt.601) with the clipping
+/// percentages, the transparent pixels are ignored.
+pub fn get_exposure_stats(di: &DynamicImage) -> ExposureStats {
+    let mut red = vec![0_u64; 256];
+    let mut green = vec![0_u64; 256];
+    let mut blue = vec![0_u64; 256];
+    let mut luminance = vec![0_u64; 256];
+    let mut total = 0_u64;
+    let mut sum = 0_u64;
+    for p in di.to_rgba8().pixels().filter(|p| p[3] >= 128) {
+        let [r, g, b, _] = p.0;
+        let luma = (r as u32 * 299 + g as u32 * 587 + b as u32 * 114 + 500) / 1000;
+        red[r as usize] += 1;
+        green[g as usize] += 1;
+        blue[b as usize] += 1;
+        luminance[luma as usize] += 1;
+        total += 1;
+        sum += luma as u64;
+    }
+    let percent = |count: u64| {
+        if total == 0 {
+            return 0.0;
+        }
+        round2(100.0 * count as f64 / total as f64)
+    };
+    ExposureStats {
+        mean_luminance: round2(sum as f64 / total.max(1) as f64),
+        shadow_clipping: percent(luminance[0]),
+        highlight_clipping: percent(luminance[255]),
+        red,
+        green,
+        blue,
+        luminance,
+    }
+}
+
+/// Get the dssim of two images with the same size, 0 means identical.
+pub fn get_dssim(a: &RgbaImage, b: &RgbaImage) -> Option<f64> {
+    if a.dimensions() != b.dimensions() {
+        return None;
+    }
+    let width = a.width() as usize;
+    let height = a.height() as usize;
+    let attr = Dssim::new();
+    let gp1 = attr.create_image_rgba(a.as_raw().as_rgba(), width, height)?;
+    let gp2 = attr.create_image_rgba(b.as_raw().as_rgba(), width, height)?;
+    let (diff, _) = attr.compare(&gp1, gp2);
+    Some(diff.into())
+}
+
+/// Get the psnr(db) of rgb channels of two images with the same size,
+/// none if the sizes are different or the images are identical.
+pub fn get_psnr(a: &RgbaImage, b: &RgbaImage) -> Option<f64> {
+    if a.dimensions() != b.dimensions() {
+        return None;
+    }
+    let mut sum = 0.0;
+    for (p1, p2) in a.pixels().zip(b.pixels()) {
+        for channel in 0..3 {
+            let diff = p1[channel] as f64 - p2[channel] as f64;
+            sum += diff * diff;
+        }
+    }
+    let count = (a.width() as f64) * (a.height() as f64) * 3.0;
+    if count == 0.0 || sum == 0.0 {
+        return None;
+    }
+    let mse = sum / count;
+    Some(10.0 * (255.0 * 255.0 / mse).log10())
+}
+
+// 标准的jpeg亮度量化表(Annex K)之和
+const STD_LUMINANCE_SUM: u32 = 3688;
+
+/// Estimate the quality of jpeg by its luminance quantization table,
+/// it compares the table with the standard table scaled by libjpeg.
+pub fn estimate_jpeg_quality(data: &[u8]) -> Option<u8> {
+    if data.len() < 4 || data[0] != 0xff || data[1] != 0xd8 {
+        return None;
+    }
+    let mut offset = 2;
+    while offset + 4 <= data.len() {
+        if data[offset] != 0xff {
+            return None;
+        }
+        let marker = data[offset + 1];
+        let length = u16::from_be_bytes([data[offset + 2], data[offset + 3]]) as usize;
+        // 扫描数据开始之后不再有量化表
+        if marker == 0xda || length < 2 {
+            return None;
+        }
+        let end = (offset + 2 + length).min(data.len());
+        let mut index = offset + 4;
+        while marker == 0xdb && index < end {
+            let precision = data[index] >> 4;
+            let id = data[index] & 0x0f;
+            let size = if precision == 0 { 64 } else { 128 };
+            let table = data.get(index + 1..index + 1 + size)?;
+            if id == 0 {
+                let sum: u32 = if precision == 0 {
+                    table.iter().map(|v| *v as u32).sum()
+                } else {
+                    table
+                        .chunks(2)
+                        .map(|v| u16::from_be_bytes([v[0], v[1]]) as u32)
+                        .sum()
+                };
+                // libjpeg的缩放比例：quality < 50时为5000/quality，否则为200-2*quality
+                let scale = sum as f64 * 100.0 / STD_LUMINANCE_SUM as f64;
+                let quality = if scale <= 100.0 {
+                    (200.0 - scale) / 2.0
+                } else {
+                    5000.0 / scale
+                };
+                return Some(quality.round().clamp(1.0, 100.0) as u8);
+            }
+            index += 1 + size;
+        }
+        offset = end;
+    }
+    None
+}
+
+#[derive(Serialize, Debug)]
+pub struct CompareResult {
+    pub width: u32,
+    pub height: u32,
+    pub target_width: u32,
+    pub target_height: u32,
+    pub dimension_mismatch: bool,
+    pub dssim: Option<f64>,
+    pub psnr: Option<f64>,
+}
+
+/// Compare two images, dssim and psnr are only calculated
+/// when the dimensions are the same.
+pub fn compare(di: &DynamicImage, target: &DynamicImage) -> CompareResult {
+    let mut result = CompareResult {
+        width: di.width(),
+        height: di.height(),
+        target_width: target.width(),
+        target_height: target.height(),
+        dimension_mismatch: di.width() != target.width() || di.height() != target.height(),
+        dssim: None,
+        psnr: None,
+    };
+    if !result.dimension_mismatch {
+        let a = di.to_rgba8();
+        let b = target.to_rgba8();
+        result.dssim = get_dssim(&a, &b);
+        result.psnr = get_psnr(&a, &b);
+    }
+    result
+}
+
+/// Region of the detected face.
+#[cfg(feature = "face-detection")]
+pub struct FaceRegion {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+#[cfg(feature = "face-detection")]
+thread_local! {
+    // 模型加载较耗时，每个线程仅加载一次(detector非Send，无法跨线程共享)
+    static FACE_DETECTOR: std::cell::RefCell<Option<Box<dyn rustface::Detector>>> =
+        const { std::cell::RefCell::new(None) };
+}
+
+/// Detect the largest face of image by the seeta model,
+/// it should be called in a blocking thread.
+#[cfg(feature = "face-detection")]
+pub fn detect_largest_face(di: &DynamicImage, model: &str) -> std::io::Result<Option<FaceRegion>> {
+    let gray = di.to_luma8();
+    let mut data = rustface::ImageData::new(&gray, gray.width(), gray.height());
+    let faces = FACE_DETECTOR.with(|cell| {
+        let mut detector = match cell.take() {
+            Some(detector) => detector,
+            None => {
+                let mut detector = rustface::create_detector(model)?;
+                detector.set_min_face_size(20);
+                detector.set_score_thresh(2.0);
+                detector.set_pyramid_scale_factor(0.8);
+                detector.set_slide_window_step(4, 4);
+                detector
+            }
+        };
+        let faces = detector.detect(&mut data);
+        cell.replace(Some(detector));
+        Ok::<_, std::io::Error>(faces)
+    })?;
+    let face = faces
+        .into_iter()
+        .max_by_key(|face| face.bbox().width() * face.bbox().height())
+        .map(|face| {
+            let bbox = face.bbox();
+            FaceRegion {
+                x: bbox.x().max(0) as u32,
+                y: bbox.y().max(0) as u32,
+                width: bbox.width(),
+                height: bbox.height(),
+            }
+        });
+    Ok(face)
+}
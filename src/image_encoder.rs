@@ -0,0 +1,641 @@
+use crate::config;
+use crate::state;
+use imageoptimize::ImageInfo;
+use once_cell::sync::Lazy;
+use rgb::{ComponentBytes, RGB8};
+use serde::{Deserialize, Serialize};
+use snafu::{ResultExt, Snafu};
+
+#[derive(Debug, Snafu)]
+pub enum ImageEncodeError {
+    #[snafu(display("Encode image fail, category:{category}, message:{source}"))]
+    ImageQuant {
+        category: String,
+        source: imagequant::Error,
+    },
+    #[snafu(display("Encode image fail, category:{category}, message:{source}"))]
+    LodePNG {
+        category: String,
+        source: lodepng::Error,
+    },
+    #[snafu(display("Encode image fail, category:mozjpeg, message:{source}"))]
+    Mozjpeg { source: std::io::Error },
+    #[snafu(display("Encode image fail, category:avif, message:{source}"))]
+    Ravif { source: ravif::Error },
+    #[snafu(display("Encode image fail, category:webp, message:{message}"))]
+    Webp { message: String },
+}
+
+type Result<T, E = ImageEncodeError> = std::result::Result<T, E>;
+
+/// Interlace method of png.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Interlace {
+    None,
+    Adam7,
+}
+
+/// Chroma subsampling of jpeg.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum ChromaSubsampling {
+    #[serde(rename = "444")]
+    S444,
+    #[serde(rename = "422")]
+    S422,
+    #[serde(rename = "420")]
+    S420,
+}
+
+/// Filter strategy of png.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PngFilter {
+    Zero,
+    Minsum,
+    Entropy,
+    BruteForce,
+}
+
+/// Frame used when the animated image is converted to static format.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AnimatedFrame {
+    #[default]
+    First,
+    Middle,
+    /// Reject the conversion.
+    Error,
+}
+
+impl AnimatedFrame {
+    /// Parse the frame from name, e.g. `middle`.
+    pub fn from_name(value: &str) -> Option<Self> {
+        match value {
+            "first" => Some(AnimatedFrame::First),
+            "middle" => Some(AnimatedFrame::Middle),
+            "error" => Some(AnimatedFrame::Error),
+            _ => None,
+        }
+    }
+}
+
+/// Named quality mapped to the quality of each format.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QualityPreset {
+    Low,
+    Medium,
+    High,
+    Lossless,
+}
+
+impl QualityPreset {
+    /// Parse the preset from name, e.g. `high`.
+    pub fn from_name(value: &str) -> Option<Self> {
+        match value {
+            "low" => Some(QualityPreset::Low),
+            "medium" => Some(QualityPreset::Medium),
+            "high" => Some(QualityPreset::High),
+            "lossless" => Some(QualityPreset::Lossless),
+            _ => None,
+        }
+    }
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            QualityPreset::Low => "low",
+            QualityPreset::Medium => "medium",
+            QualityPreset::High => "high",
+            QualityPreset::Lossless => "lossless",
+        }
+    }
+}
+
+/// Advanced options of encoder, none means the default of encoder.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct EncoderOptions {
+    /// Progressive(true) or baseline(false) jpeg.
+    pub progressive: Option<bool>,
+    /// Chroma subsampling of jpeg.
+    pub chroma_subsampling: Option<ChromaSubsampling>,
+    /// Interlace method of png.
+    pub interlace: Option<Interlace>,
+    /// Compression level of png, 0(none) to 9(best).
+    pub compression_level: Option<u8>,
+    /// Filter strategy of png.
+    pub png_filter: Option<PngFilter>,
+    /// Bit depth of avif, 8 or 10.
+    pub bit_depth: Option<u8>,
+    /// Quality of webp and avif alpha channel, 1-100.
+    pub alpha_quality: Option<u8>,
+    /// Constant rate factor of video, lower is better.
+    pub crf: Option<u8>,
+    /// Bitrate(kbps) of video.
+    pub bitrate: Option<u32>,
+    /// Keep the quality higher than the estimated quality of jpeg source.
+    pub force: Option<bool>,
+    /// Frame used when the animated image is converted to static format.
+    pub animated: Option<AnimatedFrame>,
+    /// Quality preset used instead of the quality number.
+    pub preset: Option<QualityPreset>,
+    /// Max diff(dssim*1000) of output, the quality is increased if exceeded.
+    pub max_diff: Option<f64>,
+}
+
+fn parse_option<T: std::str::FromStr>(key: &str, value: &str) -> std::result::Result<T, String> {
+    value
+        .parse::<T>()
+        .map_err(|_| format!("{key}({value}) is invalid"))
+}
+
+impl EncoderOptions {
+    /// Set the option by key and value, e.g. `progressive`, `true`.
+    pub fn set(&mut self, key: &str, value: &str) -> std::result::Result<(), String> {
+        match key {
+            "progressive" => {
+                self.progressive = Some(parse_option(key, value)?);
+            }
+            "chroma_subsampling" => {
+                let value = match value {
+                    "444" => ChromaSubsampling::S444,
+                    "422" => ChromaSubsampling::S422,
+                    "420" => ChromaSubsampling::S420,
+                    _ => return Err(format!("chroma_subsampling({value}) is invalid")),
+                };
+                self.chroma_subsampling = Some(value);
+            }
+            "compression_level" => {
+                self.compression_level = Some(parse_option(key, value)?);
+            }
+            "png_filter" => {
+                let value = match value {
+                    "zero" => PngFilter::Zero,
+                    "minsum" => PngFilter::Minsum,
+                    "entropy" => PngFilter::Entropy,
+                    "brute_force" => PngFilter::BruteForce,
+                    _ => return Err(format!("png_filter({value}) is invalid")),
+                };
+                self.png_filter = Some(value);
+            }
+            "bit_depth" => {
+                self.bit_depth = Some(parse_option(key, value)?);
+            }
+            "alpha_quality" => {
+                self.alpha_quality = Some(parse_option(key, value)?);
+            }
+            "crf" => {
+                self.crf = Some(parse_option(key, value)?);
+            }
+            "bitrate" => {
+                self.bitrate = Some(parse_option(key, value)?);
+            }
+            "force" => {
+                self.force = Some(parse_option(key, value)?);
+            }
+            "animated" => {
+                let value = AnimatedFrame::from_name(value)
+                    .ok_or_else(|| format!("animated({value}) is invalid"))?;
+                self.animated = Some(value);
+            }
+            "preset" => {
+                let value = QualityPreset::from_name(value)
+                    .ok_or_else(|| format!("preset({value}) is invalid"))?;
+                self.preset = Some(value);
+            }
+            "max_diff" => {
+                self.max_diff = Some(parse_option(key, value)?);
+            }
+            "interlace" => {
+                let value = match value {
+                    "none" => Interlace::None,
+                    "adam7" => Interlace::Adam7,
+                    _ => return Err(format!("interlace({value}) is invalid")),
+                };
+                self.interlace = Some(value);
+            }
+            _ => return Err(format!("encoder option({key}) is not support")),
+        }
+        self.validate()
+    }
+    /// Validate the range of options.
+    pub fn validate(&self) -> std::result::Result<(), String> {
+        if self.compression_level.is_some_and(|level| level > 9) {
+            return Err("compression_level should be 0-9".to_string());
+        }
+        if self
+            .bit_depth
+            .is_some_and(|depth| depth != 8 && depth != 10)
+        {
+            return Err("bit_depth should be 8 or 10".to_string());
+        }
+        if self
+            .alpha_quality
+            .is_some_and(|quality| quality == 0 || quality > 100)
+        {
+            return Err("alpha_quality should be 1-100".to_string());
+        }
+        if self
+            .max_diff
+            .is_some_and(|diff| diff.is_nan() || diff < 0.0)
+        {
+            return Err("max_diff should not be less than 0".to_string());
+        }
+        if self.crf.is_some_and(|crf| crf > 63) {
+            return Err("crf should be 0-63".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Optimize image to png, the quality is min 0, max 100, which means best effort,
+/// and never aborts the process.
+pub fn to_png(info: &ImageInfo, quality: u8, options: &EncoderOptions) -> Result<Vec<u8>> {
+    let mut liq = imagequant::new();
+    liq.set_quality(0, quality).context(ImageQuantSnafu {
+        category: "png_set_quality",
+    })?;
+
+    let mut img = liq
+        .new_image(info.buffer.as_ref(), info.width, info.height, 0.0)
+        .context(ImageQuantSnafu {
+            category: "png_new_image",
+        })?;
+
+    let mut res = liq.quantize(&mut img).context(ImageQuantSnafu {
+        category: "png_quantize",
+    })?;
+
+    res.set_dithering_level(1.0).context(ImageQuantSnafu {
+        category: "png_set_level",
+    })?;
+
+    let (palette, pixels) = res.remapped(&mut img).context(ImageQuantSnafu {
+        category: "png_remapped",
+    })?;
+    let mut enc = lodepng::Encoder::new();
+    enc.set_palette(&palette).context(LodePNGSnafu {
+        category: "png_encoder",
+    })?;
+    if options.interlace == Some(Interlace::Adam7) {
+        enc.info_png_mut().interlace_method = 1;
+    }
+    if let Some(level) = options.compression_level {
+        enc.settings_mut().zlibsettings.set_level(level);
+    }
+    if let Some(filter) = options.png_filter {
+        let strategy = match filter {
+            PngFilter::Zero => lodepng::FilterStrategy::ZERO,
+            PngFilter::Minsum => lodepng::FilterStrategy::MINSUM,
+            PngFilter::Entropy => lodepng::FilterStrategy::ENTROPY,
+            PngFilter::BruteForce => lodepng::FilterStrategy::BRUTE_FORCE,
+        };
+        enc.set_filter_strategy(strategy, true);
+    }
+
+    let buf = enc
+        .encode(&pixels, info.width, info.height)
+        .context(LodePNGSnafu {
+            category: "png_encode",
+        })?;
+
+    Ok(buf)
+}
+
+/// Optimize image to jpeg, the quality 60-80 are recommended.
+/// The default profile of mozjpeg is progressive,
+/// baseline jpeg uses the libjpeg defaults.
+pub fn to_mozjpeg(info: &ImageInfo, quality: u8, options: &EncoderOptions) -> Result<Vec<u8>> {
+    let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+    comp.set_size(info.width, info.height);
+    match options.progressive {
+        Some(true) => comp.set_progressive_mode(),
+        // 重置为libjpeg的默认配置，因此需要在设置质量之前
+        Some(false) => comp.set_fastest_defaults(),
+        None => {}
+    }
+    comp.set_quality(quality as f32);
+    if let Some(value) = options.chroma_subsampling {
+        let size = match value {
+            ChromaSubsampling::S444 => (1, 1),
+            ChromaSubsampling::S422 => (2, 1),
+            ChromaSubsampling::S420 => (2, 2),
+        };
+        comp.set_chroma_sampling_pixel_sizes(size, size);
+    }
+    let rgb: Vec<RGB8> = info.buffer.iter().map(|item| item.rgb()).collect();
+    let mut comp = comp.start_compress(Vec::new()).context(MozjpegSnafu {})?;
+    comp.write_scanlines(rgb.as_bytes())
+        .context(MozjpegSnafu {})?;
+    let data = comp.finish().context(MozjpegSnafu {})?;
+    Ok(data)
+}
+
+fn new_webp_config(quality: u8, speed: u8, options: &EncoderOptions) -> Result<webp::WebPConfig> {
+    let mut config = webp::WebPConfig::new().map_err(|_| ImageEncodeError::Webp {
+        message: "init config fail".to_string(),
+    })?;
+    let quality = quality.clamp(1, 100);
+    if quality == 100 {
+        config.lossless = 1;
+    } else {
+        config.quality = quality as f32;
+        config.alpha_quality = options.alpha_quality.unwrap_or(quality).clamp(1, 100) as i32;
+    }
+    // libwebp的method为0(最快)-6(最慢)
+    let speed = (speed.clamp(1, 10) + get_encoder_profile().speed_offset).min(10) as i32;
+    config.method = (10 - speed) * 6 / 9;
+    Ok(config)
+}
+
+/// Optimize image to webp, the quality 100 means lossless.
+/// `speed` accepts a value in the range 1-10, where 1 is the slowest and 10 is the fastest.
+pub fn to_webp(
+    info: &ImageInfo,
+    quality: u8,
+    speed: u8,
+    options: &EncoderOptions,
+) -> Result<Vec<u8>> {
+    let config = new_webp_config(quality, speed, options)?;
+    let data = webp::Encoder::from_rgba(
+        info.buffer.as_bytes(),
+        info.width as u32,
+        info.height as u32,
+    )
+    .encode_advanced(&config)
+    .map_err(|e| ImageEncodeError::Webp {
+        message: format!("{e:?}"),
+    })?;
+    Ok(data.to_vec())
+}
+
+// 动图的解码器与编码器，drop时释放
+struct WebpAnimDecoder(*mut libwebp_sys::WebPAnimDecoder);
+
+impl Drop for WebpAnimDecoder {
+    fn drop(&mut self) {
+        unsafe { libwebp_sys::WebPAnimDecoderDelete(self.0) };
+    }
+}
+
+struct WebpAnimEncoder(*mut libwebp_sys::WebPAnimEncoder);
+
+impl Drop for WebpAnimEncoder {
+    fn drop(&mut self) {
+        unsafe { libwebp_sys::WebPAnimEncoderDelete(self.0) };
+    }
+}
+
+fn new_webp_error(message: &str) -> ImageEncodeError {
+    ImageEncodeError::Webp {
+        message: message.to_string(),
+    }
+}
+
+impl WebpAnimEncoder {
+    // 添加开始于该时间的帧
+    fn add_frame(
+        &self,
+        rgba: &[u8],
+        width: u32,
+        height: u32,
+        timestamp: i32,
+        config: &webp::WebPConfig,
+    ) -> Result<()> {
+        let mut picture =
+            libwebp_sys::WebPPicture::new().map_err(|_| new_webp_error("init picture fail"))?;
+        picture.use_argb = 1;
+        picture.width = width as i32;
+        picture.height = height as i32;
+        let ok = unsafe {
+            libwebp_sys::WebPPictureImportRGBA(&mut picture, rgba.as_ptr(), width as i32 * 4) != 0
+                && libwebp_sys::WebPAnimEncoderAdd(self.0, &mut picture, timestamp, config) != 0
+        };
+        let error = picture.error_code;
+        unsafe { libwebp_sys::WebPPictureFree(&mut picture) };
+        if !ok {
+            return Err(ImageEncodeError::Webp {
+                message: format!("{error:?}"),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Re-encode the animated webp to the size frame by frame, the timing and loop count are kept.
+/// Returns none if the webp is not animated or has more frames than the max(0 means no limit).
+pub fn to_animated_webp(
+    data: &[u8],
+    width: u32,
+    height: u32,
+    quality: u8,
+    speed: u8,
+    options: &EncoderOptions,
+    max_frames: usize,
+) -> Result<Option<Vec<u8>>> {
+    let webp_data = libwebp_sys::WebPData {
+        bytes: data.as_ptr(),
+        size: data.len(),
+    };
+    let decoder = unsafe {
+        let mut decoder_options = std::mem::zeroed::<libwebp_sys::WebPAnimDecoderOptions>();
+        if libwebp_sys::WebPAnimDecoderOptionsInit(&mut decoder_options) == 0 {
+            return Err(new_webp_error("init decoder options fail"));
+        }
+        decoder_options.color_mode = libwebp_sys::WEBP_CSP_MODE::MODE_RGBA;
+        WebpAnimDecoder(libwebp_sys::WebPAnimDecoderNew(
+            &webp_data,
+            &decoder_options,
+        ))
+    };
+    if decoder.0.is_null() {
+        return Err(new_webp_error("init decoder fail"));
+    }
+    let mut info = libwebp_sys::WebPAnimInfo::default();
+    if unsafe { libwebp_sys::WebPAnimDecoderGetInfo(decoder.0, &mut info) } == 0 {
+        return Err(new_webp_error("get animation info fail"));
+    }
+    let frame_count = info.frame_count as usize;
+    if frame_count <= 1 || (max_frames != 0 && frame_count > max_frames) {
+        return Ok(None);
+    }
+    let (canvas_width, canvas_height) = (info.canvas_width, info.canvas_height);
+    // 逐帧解码并编码，同时仅有解码的画布与缩放后的帧
+    let _guard = state::start_pixels(
+        canvas_width as u64 * canvas_height as u64 + width as u64 * height as u64,
+    );
+    let config = new_webp_config(quality, speed, options)?;
+    let encoder = unsafe {
+        let mux_abi_version = libwebp_sys::WebPGetMuxABIVersion();
+        let mut encoder_options = std::mem::zeroed::<libwebp_sys::WebPAnimEncoderOptions>();
+        if libwebp_sys::WebPAnimEncoderOptionsInitInternal(&mut encoder_options, mux_abi_version)
+            == 0
+        {
+            return Err(new_webp_error("init encoder options fail"));
+        }
+        encoder_options.anim_params.loop_count = info.loop_count as i32;
+        WebpAnimEncoder(libwebp_sys::WebPAnimEncoderNewInternal(
+            width as i32,
+            height as i32,
+            &encoder_options,
+            mux_abi_version,
+        ))
+    };
+    if encoder.0.is_null() {
+        return Err(new_webp_error("init encoder fail"));
+    }
+    // 解码的时间为帧的结束时间，编码则为开始时间
+    let mut timestamp = 0;
+    while unsafe { libwebp_sys::WebPAnimDecoderHasMoreFrames(decoder.0) } > 0 {
+        let mut buf = std::ptr::null_mut();
+        let mut end = 0;
+        if unsafe { libwebp_sys::WebPAnimDecoderGetNext(decoder.0, &mut buf, &mut end) } == 0 {
+            return Err(new_webp_error("decode frame fail"));
+        }
+        // 画布的数据在解码下一帧之前有效
+        let canvas = unsafe {
+            std::slice::from_raw_parts(buf, canvas_width as usize * canvas_height as usize * 4)
+        };
+        if (canvas_width, canvas_height) == (width, height) {
+            encoder.add_frame(canvas, width, height, timestamp, &config)?;
+        } else {
+            let frame = image::ImageBuffer::<image::Rgba<u8>, _>::from_raw(
+                canvas_width,
+                canvas_height,
+                canvas,
+            )
+            .ok_or_else(|| new_webp_error("frame size is invalid"))?;
+            let frame = image::imageops::resize(
+                &frame,
+                width,
+                height,
+                image::imageops::FilterType::Lanczos3,
+            );
+            encoder.add_frame(frame.as_raw(), width, height, timestamp, &config)?;
+        }
+        timestamp = end;
+    }
+    // 以最后一帧的结束时间结束，保留最后一帧的时长
+    if unsafe {
+        libwebp_sys::WebPAnimEncoderAdd(
+            encoder.0,
+            std::ptr::null_mut(),
+            timestamp,
+            std::ptr::null(),
+        )
+    } == 0
+    {
+        return Err(new_webp_error("finalize animation fail"));
+    }
+    let mut output = libwebp_sys::WebPData::default();
+    if unsafe { libwebp_sys::WebPAnimEncoderAssemble(encoder.0, &mut output) } == 0 {
+        return Err(new_webp_error("assemble animation fail"));
+    }
+    let result = unsafe { std::slice::from_raw_parts(output.bytes, output.size) }.to_vec();
+    unsafe { libwebp_sys::WebPDataClear(&mut output) };
+    Ok(Some(result))
+}
+
+/// Optimize image to avif.
+/// `speed` accepts a value in the range 1-10, where 1 is the slowest and 10 is the fastest.
+/// `quality` accepts a value in the range 1-100, where 1 is the worst and 100 is the best.
+pub fn to_avif(
+    info: &ImageInfo,
+    quality: u8,
+    speed: u8,
+    options: &EncoderOptions,
+) -> Result<Vec<u8>> {
+    let mut speed = speed.min(10);
+    if speed == 0 {
+        speed = 3;
+    }
+    let speed = (speed + get_encoder_profile().speed_offset).min(10);
+    let quality = quality.clamp(1, 100);
+    let alpha_quality = options.alpha_quality.unwrap_or(quality).clamp(1, 100);
+    let img = ravif::Img::new(info.buffer.as_slice(), info.width, info.height);
+    let result = ravif::Encoder::new()
+        .with_quality(quality as f32)
+        .with_alpha_quality(alpha_quality as f32)
+        .with_speed(speed)
+        .with_depth(Some(options.bit_depth.unwrap_or(8)))
+        // 并发由编码线程池控制，不使用全局的rayon线程池
+        .with_num_threads(Some(1))
+        .encode_rgba(img)
+        .context(RavifSnafu {})?;
+    Ok(result.avif_file)
+}
+
+/// Encoder profile selected by the cpu features at startup.
+#[derive(Debug, Clone, Serialize)]
+pub struct EncoderProfile {
+    /// `simd` or `generic`.
+    pub name: &'static str,
+    pub arch: &'static str,
+    /// Detected cpu features used by the encoders.
+    pub features: Vec<&'static str>,
+    /// Whether the profile is forced by config.
+    pub forced: bool,
+    /// Speed added to the avif and webp encoding.
+    pub speed_offset: u8,
+}
+
+// 检测编码器可用的simd指令集，各编码库在运行时按指令集选择实现
+fn detect_cpu_features() -> Vec<&'static str> {
+    #[allow(unused_mut)]
+    let mut features = vec![];
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        if std::arch::is_x86_feature_detected!("sse4.1") {
+            features.push("sse4.1");
+        }
+        if std::arch::is_x86_feature_detected!("avx2") {
+            features.push("avx2");
+        }
+        if std::arch::is_x86_feature_detected!("avx512f") {
+            features.push("avx512f");
+        }
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        if std::arch::is_aarch64_feature_detected!("neon") {
+            features.push("neon");
+        }
+    }
+    features
+}
+
+static ENCODER_PROFILE: Lazy<EncoderProfile> = Lazy::new(|| {
+    let features = detect_cpu_features();
+    // avx2或neon才有较快的编码速度
+    let detected = features.contains(&"avx2") || features.contains(&"neon");
+    let value = config::get_encoder_profile();
+    let (simd, forced) = match value.as_str() {
+        "simd" => (true, true),
+        "generic" => (false, true),
+        _ => (detected, false),
+    };
+    let profile = EncoderProfile {
+        name: if simd { "simd" } else { "generic" },
+        arch: std::env::consts::ARCH,
+        features,
+        forced,
+        speed_offset: if simd {
+            0
+        } else {
+            config::get_generic_speed_offset().min(9)
+        },
+    };
+    tracing::info!(
+        profile = profile.name,
+        arch = profile.arch,
+        features = ?profile.features,
+        forced = profile.forced,
+        speed_offset = profile.speed_offset,
+        "Encoder profile is selected"
+    );
+    profile
+});
+
+/// Get the encoder profile, it is selected at the first call.
+pub fn get_encoder_profile() -> &'static EncoderProfile {
+    &ENCODER_PROFILE
+}
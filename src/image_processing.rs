@@ -0,0 +1,3980 @@
+use crate::config;
+use crate::encoder_pool;
+use crate::http_client;
+use crate::image_analysis::{classify_content, estimate_jpeg_quality, get_dssim, ContentKind};
+use crate::image_encoder::{self, AnimatedFrame, EncoderOptions, ImageEncodeError, QualityPreset};
+use crate::moderation::{self, ModerationError};
+use crate::state;
+use async_trait::async_trait;
+use base64::{engine::general_purpose, Engine as _};
+use image::codecs::gif::GifDecoder;
+use image::codecs::png::PngDecoder;
+use image::codecs::webp::WebPDecoder;
+use image::imageops::{blur, crop, crop_imm, grayscale, overlay, replace, resize, FilterType};
+use image::{
+    load, AnimationDecoder, DynamicImage, Frames, ImageBuffer, ImageFormat, Rgba, RgbaImage,
+};
+use imageoptimize::{avif_decode, to_gif, ImageError, ImageInfo};
+use lru::LruCache;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use snafu::{ensure, ResultExt, Snafu};
+use std::ffi::OsStr;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::Cursor;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use substring::Substring;
+use tiff::decoder::DecodingResult;
+use tokio::sync::Mutex;
+use urlencoding::{decode, encode};
+
+pub const PROCESS_LOAD: &str = "load";
+pub const PROCESS_RESIZE: &str = "resize";
+pub const PROCESS_OPTIM: &str = "optim";
+pub const PROCESS_CROP: &str = "crop";
+pub const PROCESS_GRAY: &str = "gray";
+pub const PROCESS_WATERMARK: &str = "watermark";
+pub const PROCESS_COMPOSITE: &str = "composite";
+pub const PROCESS_EXTEND: &str = "extend";
+pub const PROCESS_REDACT: &str = "redact";
+pub const PROCESS_NINE_PATCH: &str = "ninepatch";
+pub const PROCESS_EFFECT: &str = "effect";
+pub const PROCESS_ENHANCE: &str = "enhance";
+pub const PROCESS_DIFF: &str = "diff";
+
+/// Stages of pipeline with distinct timeouts.
+pub const STAGE_LOAD: &str = "load";
+pub const STAGE_DECODE: &str = "decode";
+pub const STAGE_ENCODE: &str = "encode";
+
+const FILE_PREFIX: &str = "file://";
+// 像素处理(如缩放、合成)在阻塞线程中执行，不限制超时
+pub(crate) const STAGE_PROCESS: &str = "process";
+const WATERMARK_SIZES_PREFIX: &str = "sizes:";
+
+const IMAGE_TYPE_GIF: &str = "gif";
+const IMAGE_TYPE_PNG: &str = "png";
+const IMAGE_TYPE_AVIF: &str = "avif";
+const IMAGE_TYPE_WEBP: &str = "webp";
+const IMAGE_TYPE_AUTO: &str = "auto";
+const IMAGE_TYPE_JPEG: &str = "jpeg";
+const IMAGE_TYPE_MP4: &str = "mp4";
+const IMAGE_TYPE_WEBM: &str = "webm";
+
+#[derive(Debug, Snafu)]
+pub enum ImageProcessingError {
+    #[snafu(display("Process image fail, message:{message}"))]
+    ParamsInvalid { message: String },
+    #[snafu(display("{source}"))]
+    Reqwest { source: reqwest::Error },
+    #[snafu(display("{source}"))]
+    HTTPHeaderToStr { source: reqwest::header::ToStrError },
+    #[snafu(display("{source}"))]
+    Base64Decode { source: base64::DecodeError },
+    #[snafu(display("{source}"))]
+    Image { source: image::ImageError },
+    #[snafu(display("{source}"))]
+    Images { source: ImageError },
+    #[snafu(display("{source}"))]
+    Encode { source: ImageEncodeError },
+    #[snafu(display("{source}"))]
+    ParseInt { source: std::num::ParseIntError },
+    #[snafu(display("{source}"))]
+    FromUtf { source: std::string::FromUtf8Error },
+    #[snafu(display("{source}"))]
+    Io { source: std::io::Error },
+    #[snafu(display("{source}"))]
+    Moderation { source: ModerationError },
+    #[snafu(display("{source}"))]
+    Join { source: tokio::task::JoinError },
+    #[snafu(display("Encoder thread fail, {source}"))]
+    Recv {
+        source: tokio::sync::oneshot::error::RecvError,
+    },
+    #[snafu(display("{source}"))]
+    Tiff { source: tiff::TiffError },
+    #[snafu(display("Output size({size}) is larger than max({max})"))]
+    OutputTooLarge { size: usize, max: usize },
+    #[snafu(display("Diff({diff:.2}) of output is larger than max({max})"))]
+    DiffTooLarge { diff: f64, max: f64 },
+    #[snafu(display("Image format({ext}) is not support"))]
+    UnsupportedFormat { ext: String },
+    #[snafu(display("{stage} timeout after {}ms", timeout.as_millis()))]
+    Timeout {
+        stage: &'static str,
+        timeout: Duration,
+    },
+    #[snafu(display("Params validate fail, {}", violations.join("; ")))]
+    Validation { violations: Vec<String> },
+    #[cfg(feature = "raw")]
+    #[snafu(display("Decode raw image fail, message:{message}"))]
+    Raw { message: String },
+    #[cfg(feature = "video")]
+    #[snafu(display("Transcode video fail, message:{message}"))]
+    Video { message: String },
+}
+type Result<T, E = ImageProcessingError> = std::result::Result<T, E>;
+
+/// Output type of optim task.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputType {
+    Png,
+    #[serde(alias = "jpg")]
+    Jpeg,
+    Webp,
+    Avif,
+    Gif,
+    Mp4,
+    Webm,
+    /// Selected by the content, png for graphic, jpeg(webp with alpha) for photo.
+    Auto,
+}
+
+impl OutputType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OutputType::Png => IMAGE_TYPE_PNG,
+            OutputType::Jpeg => IMAGE_TYPE_JPEG,
+            OutputType::Webp => IMAGE_TYPE_WEBP,
+            OutputType::Avif => IMAGE_TYPE_AVIF,
+            OutputType::Gif => IMAGE_TYPE_GIF,
+            OutputType::Mp4 => IMAGE_TYPE_MP4,
+            OutputType::Webm => IMAGE_TYPE_WEBM,
+            OutputType::Auto => IMAGE_TYPE_AUTO,
+        }
+    }
+}
+
+impl FromStr for OutputType {
+    type Err = ImageProcessingError;
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            IMAGE_TYPE_PNG => Ok(OutputType::Png),
+            IMAGE_TYPE_JPEG | "jpg" => Ok(OutputType::Jpeg),
+            IMAGE_TYPE_WEBP => Ok(OutputType::Webp),
+            IMAGE_TYPE_AVIF => Ok(OutputType::Avif),
+            IMAGE_TYPE_GIF => Ok(OutputType::Gif),
+            IMAGE_TYPE_MP4 => Ok(OutputType::Mp4),
+            IMAGE_TYPE_WEBM => Ok(OutputType::Webm),
+            IMAGE_TYPE_AUTO => Ok(OutputType::Auto),
+            _ => ParamsInvalidSnafu {
+                message: format!("output type({value}) is not support"),
+            }
+            .fail(),
+        }
+    }
+}
+
+/// Task of image pipeline.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "task", rename_all = "lowercase")]
+pub enum Task {
+    Load {
+        data: String,
+        #[serde(default)]
+        ext: String,
+        /// Page of the multi-page tiff starting from 1, 0 means the first page.
+        #[serde(default)]
+        page: u32,
+        /// Raw data of the image, the data is ignored if it is set.
+        #[serde(skip)]
+        bytes: Option<Arc<Vec<u8>>>,
+    },
+    Resize {
+        width: u32,
+        height: u32,
+        #[serde(default)]
+        aspect: ResizeAspect,
+        #[serde(default)]
+        rounding: ResizeRounding,
+    },
+    Gray,
+    Optim {
+        output_type: Option<OutputType>,
+        quality: u8,
+        speed: u8,
+        #[serde(default)]
+        options: EncoderOptions,
+    },
+    Crop {
+        #[serde(default)]
+        x: Length,
+        #[serde(default)]
+        y: Length,
+        #[serde(default)]
+        width: Length,
+        #[serde(default)]
+        height: Length,
+        #[serde(default)]
+        gravity: Option<CropGravity>,
+        #[serde(default)]
+        region: Option<CropRegion>,
+    },
+    Watermark {
+        url: String,
+        position: WatermarkPosition,
+        margin_left: Length,
+        margin_top: Length,
+    },
+    Watermarks {
+        items: Vec<WatermarkItem>,
+    },
+    Composite {
+        url: String,
+        x: i64,
+        y: i64,
+        #[serde(default)]
+        blend: BlendMode,
+        #[serde(default = "default_opacity")]
+        opacity: u8,
+    },
+    Extend {
+        top: u32,
+        right: u32,
+        bottom: u32,
+        left: u32,
+        #[serde(default)]
+        fill: ExtendFill,
+    },
+    Redact {
+        regions: Vec<RedactRegion>,
+        #[serde(default)]
+        mode: RedactMode,
+    },
+    NinePatch {
+        width: u32,
+        height: u32,
+        top: u32,
+        right: u32,
+        bottom: u32,
+        left: u32,
+    },
+    Effect {
+        effect: Effect,
+    },
+    Enhance {
+        levels: bool,
+        #[serde(default)]
+        gamma: Option<f32>,
+    },
+    Diff,
+}
+
+fn default_opacity() -> u8 {
+    100
+}
+
+impl Task {
+    /// Name of the task.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Task::Load { .. } => PROCESS_LOAD,
+            Task::Resize { .. } => PROCESS_RESIZE,
+            Task::Gray => PROCESS_GRAY,
+            Task::Optim { .. } => PROCESS_OPTIM,
+            Task::Crop { .. } => PROCESS_CROP,
+            Task::Watermark { .. } | Task::Watermarks { .. } => PROCESS_WATERMARK,
+            Task::Composite { .. } => PROCESS_COMPOSITE,
+            Task::Extend { .. } => PROCESS_EXTEND,
+            Task::Redact { .. } => PROCESS_REDACT,
+            Task::NinePatch { .. } => PROCESS_NINE_PATCH,
+            Task::Effect { .. } => PROCESS_EFFECT,
+            Task::Enhance { .. } => PROCESS_ENHANCE,
+            Task::Diff => PROCESS_DIFF,
+        }
+    }
+    /// Parse the task from description.
+    /// Load task: ["load", "url"]
+    /// Resize task: ["resize", "width", "height", "aspect", "rounding"]
+    /// Gray task: ["gray"]
+    /// Optim task: ["optim", "webp", "quality", "speed", "key:value"...]
+    /// Crop task: ["crop", "x", "y", "width", "height", "gravity"], the value can be
+    /// percentage of the image, e.g. 10%, or ["crop", "region"], e.g. top_half
+    /// Watermark task: ["watermark", "url", "position", "margin left", "margin top"],
+    /// the margin can be percentage of the image, e.g. 10%
+    /// Watermarks task: ["watermark", "[{\"url\":\"...\",\"position\":\"leftTop\"},...]"]
+    /// Composite task: ["composite", "url", "x", "y", "blend", "opacity"]
+    /// Extend task: ["extend", "top", "right", "bottom", "left", "fill"]
+    /// Redact task: ["redact", "x,y,width,height;...", "mode"]
+    /// Nine patch task: ["ninepatch", "width", "height", "top", "right", "bottom", "left"],
+    /// the insets are the same if only top is set
+    /// Effect task: ["effect", "grayscale|sepia|duotone:AABBCC,DDEEFF"]
+    /// Enhance task: ["enhance", "true", "gamma"]
+    /// Diff task: ["diff"]
+    pub fn parse(params: &[String]) -> Result<Self> {
+        let he = ParamsInvalidSnafu {
+            message: "params is invalid",
+        };
+        ensure!(!params.is_empty(), he);
+        let sub_params = &params[1..];
+        let task = match params[0].as_str() {
+            PROCESS_LOAD => {
+                // 参数不符合
+                ensure!(!sub_params.is_empty(), he);
+                Task::Load {
+                    data: sub_params[0].clone(),
+                    ext: sub_params.get(1).cloned().unwrap_or_default(),
+                    page: sub_params
+                        .get(2)
+                        .map(|value| value.parse())
+                        .transpose()
+                        .context(ParseIntSnafu)?
+                        .unwrap_or_default(),
+                    bytes: None,
+                }
+            }
+            PROCESS_RESIZE => {
+                // 参数不符合
+                ensure!(sub_params.len() >= 2, he);
+                Task::Resize {
+                    width: sub_params[0].parse::<u32>().context(ParseIntSnafu {})?,
+                    height: sub_params[1].parse::<u32>().context(ParseIntSnafu {})?,
+                    aspect: sub_params
+                        .get(2)
+                        .map(|value| value.parse())
+                        .transpose()?
+                        .unwrap_or_default(),
+                    rounding: sub_params
+                        .get(3)
+                        .map(|value| value.parse())
+                        .transpose()?
+                        .unwrap_or_default(),
+                }
+            }
+            PROCESS_GRAY => Task::Gray,
+            PROCESS_OPTIM => {
+                // 参数不符合
+                ensure!(!sub_params.is_empty(), he);
+                let output_type = if sub_params[0].is_empty() {
+                    None
+                } else {
+                    Some(sub_params[0].parse::<OutputType>()?)
+                };
+                let mut quality = 80;
+                // 质量可为预设名称，如high
+                let mut options = EncoderOptions::default();
+                if sub_params.len() > 1 {
+                    match QualityPreset::from_name(&sub_params[1]) {
+                        Some(preset) => options.preset = Some(preset),
+                        None => quality = sub_params[1].parse::<u8>().context(ParseIntSnafu {})?,
+                    }
+                }
+                let mut speed = 3;
+                if sub_params.len() > 2 {
+                    speed = sub_params[2].parse::<u8>().context(ParseIntSnafu {})?;
+                }
+                // 其它参数为编码器的配置，格式为key:value
+                for item in sub_params.iter().skip(3) {
+                    let (key, value) = item.split_once(':').unwrap_or((item, ""));
+                    options
+                        .set(key, value)
+                        .map_err(|message| ParamsInvalidSnafu { message }.build())?;
+                }
+                Task::Optim {
+                    output_type,
+                    quality,
+                    speed,
+                    options,
+                }
+            }
+            PROCESS_CROP => {
+                // 命名区域，如top_half
+                if sub_params.len() == 1 {
+                    return Ok(Task::Crop {
+                        x: Length::default(),
+                        y: Length::default(),
+                        width: Length::default(),
+                        height: Length::default(),
+                        gravity: None,
+                        region: Some(sub_params[0].parse()?),
+                    });
+                }
+                // 参数不符合
+                ensure!(sub_params.len() >= 4, he);
+                Task::Crop {
+                    x: sub_params[0].parse()?,
+                    y: sub_params[1].parse()?,
+                    width: sub_params[2].parse()?,
+                    height: sub_params[3].parse()?,
+                    gravity: sub_params.get(4).map(|value| value.parse()).transpose()?,
+                    region: None,
+                }
+            }
+            PROCESS_WATERMARK => {
+                // 参数不符合
+                ensure!(!sub_params.is_empty(), he);
+                // json数组则为多个水印，按顺序添加
+                if sub_params[0].starts_with('[') {
+                    let items: Vec<WatermarkItem> =
+                        serde_json::from_str(&sub_params[0]).map_err(|e| {
+                            ParamsInvalidSnafu {
+                                message: format!("watermarks is invalid, {e}"),
+                            }
+                            .build()
+                        })?;
+                    ensure!(!items.is_empty(), he);
+                    return Ok(Task::Watermarks { items });
+                }
+                let url = decode(sub_params[0].as_str())
+                    .context(FromUtfSnafu {})?
+                    .to_string();
+                let mut position = WatermarkPosition::RightBottom;
+                if sub_params.len() > 1 {
+                    position = sub_params[1].parse()?;
+                }
+                let mut margin_left = Length::default();
+                if sub_params.len() > 2 {
+                    margin_left = sub_params[2].parse()?;
+                }
+                let mut margin_top = Length::default();
+                if sub_params.len() > 3 {
+                    margin_top = sub_params[3].parse()?;
+                }
+                Task::Watermark {
+                    url,
+                    position,
+                    margin_left,
+                    margin_top,
+                }
+            }
+            PROCESS_COMPOSITE => {
+                // 参数不符合
+                ensure!(sub_params.len() >= 3, he);
+                let url = decode(sub_params[0].as_str())
+                    .context(FromUtfSnafu {})?
+                    .to_string();
+                let mut blend = BlendMode::Normal;
+                if sub_params.len() > 3 {
+                    blend = sub_params[3].parse()?;
+                }
+                let mut opacity = default_opacity();
+                if sub_params.len() > 4 {
+                    opacity = sub_params[4].parse::<u8>().context(ParseIntSnafu {})?;
+                }
+                ensure!(
+                    opacity <= 100,
+                    ParamsInvalidSnafu {
+                        message: "opacity should be 0-100",
+                    }
+                );
+                Task::Composite {
+                    url,
+                    x: sub_params[1].parse::<i64>().context(ParseIntSnafu {})?,
+                    y: sub_params[2].parse::<i64>().context(ParseIntSnafu {})?,
+                    blend,
+                    opacity,
+                }
+            }
+            PROCESS_EXTEND => {
+                // 参数不符合
+                ensure!(sub_params.len() >= 4, he);
+                Task::Extend {
+                    top: sub_params[0].parse::<u32>().context(ParseIntSnafu {})?,
+                    right: sub_params[1].parse::<u32>().context(ParseIntSnafu {})?,
+                    bottom: sub_params[2].parse::<u32>().context(ParseIntSnafu {})?,
+                    left: sub_params[3].parse::<u32>().context(ParseIntSnafu {})?,
+                    fill: sub_params
+                        .get(4)
+                        .map(|value| value.parse())
+                        .transpose()?
+                        .unwrap_or_default(),
+                }
+            }
+            PROCESS_NINE_PATCH => {
+                // 参数不符合
+                ensure!(sub_params.len() >= 3, he);
+                let insets = sub_params[2..]
+                    .iter()
+                    .take(4)
+                    .map(|value| value.parse::<u32>().context(ParseIntSnafu {}))
+                    .collect::<Result<Vec<u32>>>()?;
+                // 仅指定一个边距时四边一致
+                ensure!(insets.len() == 1 || insets.len() == 4, he);
+                let inset = |index: usize| insets.get(index).copied().unwrap_or(insets[0]);
+                Task::NinePatch {
+                    width: sub_params[0].parse::<u32>().context(ParseIntSnafu {})?,
+                    height: sub_params[1].parse::<u32>().context(ParseIntSnafu {})?,
+                    top: inset(0),
+                    right: inset(1),
+                    bottom: inset(2),
+                    left: inset(3),
+                }
+            }
+            PROCESS_REDACT => {
+                // 参数不符合
+                ensure!(!sub_params.is_empty(), he);
+                let regions = sub_params[0]
+                    .split(';')
+                    .filter(|item| !item.is_empty())
+                    .map(|item| item.parse())
+                    .collect::<Result<Vec<RedactRegion>>>()?;
+                ensure!(!regions.is_empty(), he);
+                Task::Redact {
+                    regions,
+                    mode: sub_params
+                        .get(1)
+                        .map(|value| value.parse())
+                        .transpose()?
+                        .unwrap_or_default(),
+                }
+            }
+            PROCESS_EFFECT => {
+                // 参数不符合
+                ensure!(!sub_params.is_empty(), he);
+                Task::Effect {
+                    effect: sub_params[0].parse()?,
+                }
+            }
+            PROCESS_ENHANCE => {
+                // 参数不符合
+                ensure!(!sub_params.is_empty(), he);
+                let levels = sub_params[0].parse::<bool>().map_err(|_| {
+                    ParamsInvalidSnafu {
+                        message: format!("enhance({}) is invalid", sub_params[0]),
+                    }
+                    .build()
+                })?;
+                let gamma = sub_params
+                    .get(1)
+                    .filter(|value| !value.is_empty())
+                    .map(|value| {
+                        value
+                            .parse::<f32>()
+                            .ok()
+                            .filter(|gamma| (0.1..=10.0).contains(gamma))
+                            .ok_or_else(|| {
+                                ParamsInvalidSnafu {
+                                    message: "gamma should be 0.1-10",
+                                }
+                                .build()
+                            })
+                    })
+                    .transpose()?;
+                Task::Enhance { levels, gamma }
+            }
+            PROCESS_DIFF => Task::Diff,
+            _ => {
+                return ParamsInvalidSnafu {
+                    message: format!("task({}) is not support", params[0]),
+                }
+                .fail()
+            }
+        };
+        Ok(task)
+    }
+}
+
+/// Parse the tasks from description, the empty item will be ignored.
+pub fn parse_tasks(desc: Vec<Vec<String>>) -> Result<Vec<Task>> {
+    desc.iter()
+        .filter(|params| !params.is_empty())
+        .map(|params| Task::parse(params))
+        .collect()
+}
+
+/// Validate the tasks without loading the image,
+/// only the constraints independent of the image size are checked.
+pub fn validate_tasks(tasks: &[Task]) -> Result<()> {
+    let max = config::get_max_dimension() as u64;
+    let mut violations = vec![];
+    if !matches!(tasks.first(), Some(Task::Load { .. })) {
+        violations.push("the first task should be load".to_string());
+    }
+    for task in tasks.iter().skip(1) {
+        let values = match task {
+            Task::Load { .. } => {
+                violations.push("load task should only be the first".to_string());
+                continue;
+            }
+            Task::Resize { width, height, .. } => {
+                vec![
+                    ("resize width", *width as u64),
+                    ("resize height", *height as u64),
+                ]
+            }
+            Task::NinePatch { width, height, .. } => {
+                vec![
+                    ("ninepatch width", *width as u64),
+                    ("ninepatch height", *height as u64),
+                ]
+            }
+            Task::Extend {
+                top,
+                right,
+                bottom,
+                left,
+                ..
+            } => vec![
+                ("extended width", *left as u64 + *right as u64),
+                ("extended height", *top as u64 + *bottom as u64),
+            ],
+            _ => continue,
+        };
+        for (name, value) in values {
+            if value > max {
+                violations.push(format!("{name} {value} should not be larger than {max}"));
+            }
+        }
+    }
+    ensure_valid(violations)
+}
+
+/// Builder of the pipeline tasks, the load task should be the first.
+#[derive(Debug, Clone, Default)]
+pub struct PipelineBuilder {
+    tasks: Vec<Task>,
+}
+
+impl PipelineBuilder {
+    /// Load the image from http url, file url or base64 data,
+    /// the ext is the type of data, empty means detected from the source.
+    pub fn load(data: &str, ext: &str) -> Self {
+        PipelineBuilder {
+            tasks: vec![Task::Load {
+                data: data.to_string(),
+                ext: ext.to_string(),
+                page: 0,
+                bytes: None,
+            }],
+        }
+    }
+    /// Add a task to the pipeline.
+    pub fn task(mut self, task: Task) -> Self {
+        self.tasks.push(task);
+        self
+    }
+    /// Resize the image, width or height is 0 means keeping the aspect ratio.
+    pub fn resize(self, width: u32, height: u32) -> Self {
+        self.task(Task::Resize {
+            width,
+            height,
+            aspect: ResizeAspect::default(),
+            rounding: ResizeRounding::default(),
+        })
+    }
+    /// Crop the image.
+    pub fn crop(self, x: u32, y: u32, width: u32, height: u32) -> Self {
+        self.task(Task::Crop {
+            x: Length::Pixel(x.into()),
+            y: Length::Pixel(y.into()),
+            width: Length::Pixel(width.into()),
+            height: Length::Pixel(height.into()),
+            gravity: None,
+            region: None,
+        })
+    }
+    /// Convert the image to gray.
+    pub fn gray(self) -> Self {
+        self.task(Task::Gray)
+    }
+    /// Add a watermark over the image.
+    pub fn watermark(
+        self,
+        url: &str,
+        position: WatermarkPosition,
+        margin_left: Length,
+        margin_top: Length,
+    ) -> Self {
+        self.task(Task::Watermark {
+            url: url.to_string(),
+            position,
+            margin_left,
+            margin_top,
+        })
+    }
+    /// Optimize the image, none output type means the original type.
+    pub fn optim(self, output_type: Option<OutputType>, quality: u8, speed: u8) -> Self {
+        self.optim_with_options(output_type, quality, speed, EncoderOptions::default())
+    }
+    /// Optimize the image with advanced options of encoder.
+    pub fn optim_with_options(
+        self,
+        output_type: Option<OutputType>,
+        quality: u8,
+        speed: u8,
+        options: EncoderOptions,
+    ) -> Self {
+        self.task(Task::Optim {
+            output_type,
+            quality,
+            speed,
+            options,
+        })
+    }
+    /// Calculate the dssim diff with the original image.
+    pub fn diff(self) -> Self {
+        self.task(Task::Diff)
+    }
+    /// Get the tasks of pipeline.
+    pub fn build(self) -> Vec<Task> {
+        self.tasks
+    }
+    /// Run the pipeline.
+    pub async fn run(self) -> Result<ProcessImage> {
+        run(self.tasks).await
+    }
+}
+
+/// Run process image task.
+pub async fn run(tasks: Vec<Task>) -> Result<ProcessImage> {
+    run_with(ProcessImage::default(), tasks).await
+}
+
+/// Run process image task on a loaded image.
+pub async fn run_with(mut img: ProcessImage, tasks: Vec<Task>) -> Result<ProcessImage> {
+    // 原始图片仅用于计算差异值，无diff则无需保留
+    let keep_original = tasks.iter().any(|task| matches!(task, Task::Diff));
+    let animated = get_animated_frame(&tasks);
+    let keep_animation = is_animation_kept(&tasks);
+    let mut _pixels_guard = None;
+    let mut pixels_counted = false;
+    for (index, task) in tasks.into_iter().enumerate() {
+        // 原始数据直接返回，不再处理
+        if img.passthrough {
+            break;
+        }
+        // 各任务间让出执行，客户端断开连接时处理的future可及时被drop，不再执行后续任务
+        if index != 0 {
+            tokio::task::yield_now().await;
+        }
+        let started_at = Instant::now();
+        let name = task.name();
+        // 加载后根据当前图片的尺寸校验参数
+        if !matches!(task, Task::Load { .. }) {
+            ensure_valid(validate_task(&task, img.di.width(), img.di.height()))?;
+        }
+        match task {
+            Task::Load {
+                data,
+                ext,
+                page,
+                bytes,
+            } => {
+                img = LoaderProcess::new(&data, &ext)
+                    .with_bytes(bytes)
+                    .with_page(page)
+                    .with_original(keep_original)
+                    .with_animated(animated)
+                    .with_animation(keep_animation)
+                    .process(img)
+                    .await?;
+                // 配置了审核服务则提交审核
+                let url = config::get_moderation_url();
+                if !url.is_empty() {
+                    let data = img.get_original_buffer().await?;
+                    moderation::check(&url, data, &img.ext, &mut img.di)
+                        .await
+                        .context(ModerationSnafu)?;
+                }
+            }
+            Task::Resize {
+                width,
+                height,
+                aspect,
+                rounding,
+            } => {
+                img = ResizeProcess::new(width, height)
+                    .with_aspect(aspect)
+                    .with_rounding(rounding)
+                    .process(img)
+                    .await?;
+            }
+            Task::Gray => {
+                img = GrayProcess::new().process(img).await?;
+            }
+            Task::Optim {
+                output_type,
+                quality,
+                speed,
+                options,
+            } => {
+                img.report.options = Some(options.clone());
+                let max_diff = options.max_diff.unwrap_or_else(config::get_max_diff);
+                // 需要校验差异或限制输出大小时保留编码前的图片用于重新编码
+                let mut before =
+                    (max_diff > 0.0 || config::get_max_output_bytes() != 0).then(|| img.clone());
+                img = OptimProcess::new(output_type, quality, speed)
+                    .with_options(options.clone())
+                    .process(img)
+                    .await?;
+                if let Some(before) = before.as_mut().filter(|_| max_diff > 0.0) {
+                    img = guard_output_diff(
+                        img,
+                        before,
+                        max_diff,
+                        output_type,
+                        quality,
+                        speed,
+                        &options,
+                    )
+                    .await?;
+                }
+                if let Some(before) = before {
+                    img = fit_output_budget(img, before, output_type, quality, speed, options)
+                        .await?;
+                }
+            }
+            Task::Crop {
+                x,
+                y,
+                width,
+                height,
+                gravity,
+                region,
+            } => {
+                let rect = CropRect {
+                    x,
+                    y,
+                    width,
+                    height,
+                }
+                .resolve(region, &img.di);
+                // 已校验区域与图片相交
+                let [x, y, width, height] = rect.map(|value| value.max(0) as u32);
+                img = CropProcess::new(x, y, width, height)
+                    .with_gravity(gravity)
+                    .process(img)
+                    .await?;
+            }
+            Task::Watermark {
+                url,
+                position,
+                margin_left,
+                margin_top,
+            } => {
+                let item = WatermarkItem {
+                    url,
+                    position,
+                    margin_left,
+                    margin_top,
+                };
+                img = add_watermark(img, &item).await?;
+            }
+            Task::Watermarks { items } => {
+                for item in items.iter() {
+                    img = add_watermark(img, item).await?;
+                }
+            }
+            Task::Composite {
+                url,
+                x,
+                y,
+                blend,
+                opacity,
+            } => {
+                // 叠加图片与水印使用同样的缓存
+                let layer = load_watermark(&url).await?;
+                ensure_valid(validate_overlay(
+                    PROCESS_COMPOSITE,
+                    (x, y, layer.width(), layer.height()),
+                    (img.di.width(), img.di.height()),
+                ))?;
+
+                let pro = CompositeProcess::new(layer, x, y)
+                    .with_blend(blend)
+                    .with_opacity(opacity);
+                img = pro.process(img).await?;
+            }
+            Task::Extend {
+                top,
+                right,
+                bottom,
+                left,
+                fill,
+            } => {
+                img = ExtendProcess::new(top, right, bottom, left)
+                    .with_fill(fill)
+                    .process(img)
+                    .await?;
+            }
+            Task::NinePatch {
+                width,
+                height,
+                top,
+                right,
+                bottom,
+                left,
+            } => {
+                img = NinePatchProcess::new(width, height)
+                    .with_insets(top, right, bottom, left)
+                    .process(img)
+                    .await?;
+            }
+            Task::Redact { regions, mode } => {
+                img = RedactProcess::new(regions)
+                    .with_mode(mode)
+                    .process(img)
+                    .await?;
+            }
+            Task::Effect { effect } => {
+                img = EffectProcess::new(effect).process(img).await?;
+            }
+            Task::Enhance { levels, gamma } => {
+                img = EnhanceProcess::new(levels)
+                    .with_gamma(gamma)
+                    .process(img)
+                    .await?;
+            }
+            Task::Diff => {
+                img.update_diff().await?;
+            }
+        }
+        // 记录处理中的像素，用于判断是否过载
+        if !pixels_counted && img.source_pixels != 0 {
+            pixels_counted = true;
+            _pixels_guard = state::start_job_pixels(img.source_pixels);
+        }
+        img.report.stages.push(StageReport {
+            task: name,
+            cost: started_at.elapsed().as_millis() as u64,
+            width: img.di.width(),
+            height: img.di.height(),
+        });
+        img.update_peak_memory();
+    }
+    Ok(img)
+}
+
+enum LoaderCredential {
+    Bearer(String),
+    Basic(String, Option<String>),
+    Header(String, String),
+}
+
+// 各host的认证配置，格式有误则忽略
+static LOADER_HOSTS: Lazy<Vec<(String, LoaderCredential)>> = Lazy::new(|| {
+    let mut hosts = vec![];
+    for item in config::get_loader_hosts() {
+        let Some((host, value)) = item.split_once('=') else {
+            tracing::error!(item, "Loader host is invalid");
+            continue;
+        };
+        let credential = match value.split_once(':') {
+            Some(("bearer", token)) => LoaderCredential::Bearer(token.to_string()),
+            Some(("basic", value)) => {
+                let (user, password) = value.split_once(':').unwrap_or((value, ""));
+                LoaderCredential::Basic(
+                    user.to_string(),
+                    Some(password.to_string()).filter(|value| !value.is_empty()),
+                )
+            }
+            Some(("header", value)) => {
+                let Some((name, value)) = value.split_once(':') else {
+                    tracing::error!(host, "Loader header is invalid");
+                    continue;
+                };
+                LoaderCredential::Header(name.to_string(), value.to_string())
+            }
+            _ => {
+                tracing::error!(host, "Loader credential is not support");
+                continue;
+            }
+        };
+        hosts.push((host.trim().to_lowercase(), credential));
+    }
+    hosts
+});
+
+// 根据url的host添加认证信息，host以.开头则匹配其子域名，
+// 重定向仅限同一host(见http_client)，因此认证信息不会发送至其它host
+fn with_loader_credentials(mut req: reqwest::RequestBuilder, url: &str) -> reqwest::RequestBuilder {
+    if LOADER_HOSTS.is_empty() {
+        return req;
+    }
+    let Ok(url) = reqwest::Url::parse(url) else {
+        return req;
+    };
+    let Some(host) = url.host_str() else {
+        return req;
+    };
+    let host_port = url.port().map(|port| format!("{host}:{port}"));
+    for (name, credential) in LOADER_HOSTS.iter() {
+        let matched = name == host
+            || Some(name) == host_port.as_ref()
+            || (name.starts_with('.') && host.ends_with(name.as_str()));
+        if !matched {
+            continue;
+        }
+        req = match credential {
+            LoaderCredential::Bearer(token) => req.bearer_auth(token),
+            LoaderCredential::Basic(user, password) => req.basic_auth(user, password.as_ref()),
+            LoaderCredential::Header(name, value) => req.header(name, value),
+        };
+    }
+    req
+}
+
+// 水印为sizes:name时，根据图片宽度选择对应尺寸的水印
+fn get_watermark_url(url: &str, width: u32) -> Result<String> {
+    let Some(name) = url.strip_prefix(WATERMARK_SIZES_PREFIX) else {
+        return Ok(url.to_string());
+    };
+    let sizes = config::get_watermark_sizes(name);
+    // 宽度小于所有阈值则使用最小的水印
+    let url = sizes
+        .iter()
+        .rev()
+        .find(|(min_width, _)| *min_width <= width)
+        .or(sizes.first())
+        .map(|(_, url)| url.clone())
+        .ok_or_else(|| {
+            ParamsInvalidSnafu {
+                message: format!("watermark sizes({name}) is not configured"),
+            }
+            .build()
+        })?;
+    Ok(url)
+}
+
+// 编码后与编码前的差异值超过阈值时提高质量(每次+10)重新编码，多次尝试后仍超过则出错
+// 根据类型解码数据
+fn decode_data(data: Vec<u8>, ext: &str) -> Result<DynamicImage> {
+    if ext == IMAGE_TYPE_AVIF {
+        return avif_decode(&data).context(ImagesSnafu {});
+    }
+    let format = ImageFormat::from_extension(OsStr::new(ext))
+        .ok_or_else(|| UnsupportedFormatSnafu { ext }.build())?;
+    load(Cursor::new(data), format).context(ImageSnafu {})
+}
+
+async fn guard_output_diff(
+    pi: ProcessImage,
+    before: &mut ProcessImage,
+    max_diff: f64,
+    output_type: Option<OutputType>,
+    quality: u8,
+    speed: u8,
+    options: &EncoderOptions,
+) -> Result<ProcessImage> {
+    let mut img = pi;
+    let di = std::mem::take(&mut before.di);
+    let (di, source) = run_blocking(STAGE_PROCESS, Duration::ZERO, move || {
+        let source = di.to_rgba8();
+        (di, source)
+    })
+    .await?;
+    before.di = di;
+    let source = Arc::new(source);
+    let retries = config::get_max_diff_retries();
+    let mut quality = img.encoding.map(|value| value.quality).unwrap_or(quality);
+    // 重新编码时使用指定的质量，不再根据预设或原图质量调整
+    let mut options = options.clone();
+    options.preset = None;
+    options.force = Some(true);
+    let mut diff = 0.0;
+    for attempt in 0..=retries {
+        if img.passthrough || !img.support_dssim() {
+            return Ok(img);
+        }
+        let data = img.get_buffer().await?;
+        let ext = img.ext.clone();
+        let result = run_blocking(STAGE_DECODE, config::get_decode_timeout(), move || {
+            decode_data(data, &ext).map(|di| di.to_rgba8())
+        })
+        .await
+        .and_then(|result| result);
+        // 无法解码则无法校验差异，使用当前的输出
+        let decoded = match result {
+            Ok(decoded) => decoded,
+            Err(e) => {
+                tracing::warn!(ext = img.ext, "Decode output for diff fail, {e}");
+                return Ok(img);
+            }
+        };
+        if decoded.dimensions() != source.dimensions() {
+            return Ok(img);
+        }
+        let original = source.clone();
+        diff = run_blocking(STAGE_PROCESS, Duration::ZERO, move || {
+            get_dssim(&original, &decoded)
+        })
+        .await?
+        .unwrap_or_default()
+            * 1000.0;
+        if diff <= max_diff {
+            return Ok(img);
+        }
+        if attempt == retries || quality >= 100 {
+            break;
+        }
+        quality = quality.saturating_add(10).min(100);
+        img = OptimProcess::new(output_type, quality, speed)
+            .with_options(options.clone())
+            .process(before.clone())
+            .await?;
+        img.encoding = Some(AppliedEncoding { quality, speed });
+    }
+    DiffTooLargeSnafu {
+        diff,
+        max: max_diff,
+    }
+    .fail()
+}
+
+// 输出超过限制时降低质量或缩小尺寸，基于编码前的图片重新编码，多次尝试后仍超过则出错
+async fn fit_output_budget(
+    pi: ProcessImage,
+    before: ProcessImage,
+    output_type: Option<OutputType>,
+    quality: u8,
+    speed: u8,
+    options: EncoderOptions,
+) -> Result<ProcessImage> {
+    let max = config::get_max_output_bytes();
+    let mut img = pi;
+    // gif与视频使用原始数据转换，无法通过质量调整
+    if max == 0
+        || img.passthrough
+        || [IMAGE_TYPE_GIF, IMAGE_TYPE_MP4, IMAGE_TYPE_WEBM].contains(&img.ext.as_str())
+    {
+        return Ok(img);
+    }
+    let strategy = config::get_output_budget_strategy();
+    let mut quality = quality;
+    let mut width = before.di.width();
+    for _ in 0..5 {
+        if img.buffer_len() <= max {
+            return Ok(img);
+        }
+        let lower_quality = strategy != "resize" && quality > 20;
+        if lower_quality {
+            quality = quality.saturating_sub(15).max(20);
+        } else if strategy != "quality" {
+            width = (width * 4 / 5).max(1);
+        } else {
+            break;
+        }
+        let mut source = before.clone();
+        if width != before.di.width() {
+            source = ResizeProcess::new(width, 0).process(source).await?;
+        }
+        img = OptimProcess::new(output_type, quality, speed)
+            .with_options(options.clone())
+            .process(source)
+            .await?;
+        img.encoding = Some(AppliedEncoding { quality, speed });
+    }
+    let size = img.buffer_len();
+    ensure!(size <= max, OutputTooLargeSnafu { size, max });
+    Ok(img)
+}
+
+async fn add_watermark(img: ProcessImage, item: &WatermarkItem) -> Result<ProcessImage> {
+    let url = get_watermark_url(&item.url, img.di.width())?;
+    let watermark = load_watermark(&url).await?;
+
+    let pro = WatermarkProcess::new(watermark, item.position, item.margin_left, item.margin_top);
+    let (x, y) = pro.get_position(img.di.width(), img.di.height());
+    ensure_valid(validate_overlay(
+        PROCESS_WATERMARK,
+        (x, y, pro.watermark.width(), pro.watermark.height()),
+        (img.di.width(), img.di.height()),
+    ))?;
+    pro.process(img).await
+}
+
+fn ensure_valid(violations: Vec<String>) -> Result<()> {
+    ensure!(violations.is_empty(), ValidationSnafu { violations });
+    Ok(())
+}
+
+// 根据当前图片的尺寸校验任务的参数，返回所有不符合的约束
+fn validate_task(task: &Task, width: u32, height: u32) -> Vec<String> {
+    let max = config::get_max_dimension();
+    let mut violations = vec![];
+    let mut check_max = |name: &str, value: u64| {
+        if value > max as u64 {
+            violations.push(format!("{name} {value} should not be larger than {max}"));
+        }
+    };
+    match task {
+        Task::Resize {
+            width: w,
+            height: h,
+            aspect,
+            rounding,
+        } => {
+            check_max("resize width", *w as u64);
+            check_max("resize height", *h as u64);
+            // 按比例计算的宽高不能为0
+            let (new_width, new_height) = ResizeProcess::new(*w, *h)
+                .with_aspect(*aspect)
+                .with_rounding(*rounding)
+                .get_size(width.max(1), height.max(1));
+            if new_width == 0 {
+                violations.push("resize width calculated by ratio is 0".to_string());
+            }
+            if new_height == 0 {
+                violations.push("resize height calculated by ratio is 0".to_string());
+            }
+        }
+        Task::Crop {
+            x,
+            y,
+            width: w,
+            height: h,
+            gravity,
+            region,
+        } => {
+            let [x, y, w, h] = CropRect {
+                x: *x,
+                y: *y,
+                width: *w,
+                height: *h,
+            }
+            .resolve_size(*region, width, height);
+            if w <= 0 || h <= 0 {
+                violations.push("crop width and height should be larger than 0".to_string());
+            }
+            // 指定gravity时起始位置根据图片计算
+            if gravity.is_none() && (x < 0 || y < 0 || x >= width as i64 || y >= height as i64) {
+                violations.push(format!(
+                    "crop rectangle({x},{y}) should intersect the image({width}x{height})"
+                ));
+            }
+        }
+        Task::Extend {
+            top,
+            right,
+            bottom,
+            left,
+            ..
+        } => {
+            check_max(
+                "extended width",
+                width as u64 + *left as u64 + *right as u64,
+            );
+            check_max(
+                "extended height",
+                height as u64 + *top as u64 + *bottom as u64,
+            );
+        }
+        Task::NinePatch {
+            width: w,
+            height: h,
+            top,
+            right,
+            bottom,
+            left,
+        } => {
+            check_max("ninepatch width", *w as u64);
+            check_max("ninepatch height", *h as u64);
+            let horizontal = *left as u64 + *right as u64;
+            let vertical = *top as u64 + *bottom as u64;
+            // 中间区域需保留至少1像素用于拉伸
+            if horizontal >= width as u64 || vertical >= height as u64 {
+                violations.push(format!(
+                    "ninepatch insets({top},{right},{bottom},{left}) should be smaller than the image({width}x{height})"
+                ));
+            }
+            // 四角保持原尺寸，因此不能小于边距之和
+            if horizontal > *w as u64 || vertical > *h as u64 {
+                violations.push(format!(
+                    "ninepatch size({w}x{h}) should not be smaller than the insets({top},{right},{bottom},{left})"
+                ));
+            }
+        }
+        _ => (),
+    }
+    violations
+}
+
+// 叠加的图层不能完全在图片之外
+fn validate_overlay(name: &str, layer: (i64, i64, u32, u32), size: (u32, u32)) -> Vec<String> {
+    let (x, y, w, h) = layer;
+    if x >= size.0 as i64 || y >= size.1 as i64 || x + w as i64 <= 0 || y + h as i64 <= 0 {
+        return vec![format!(
+            "{name}({x},{y} {w}x{h}) should not be fully off the image({}x{})",
+            size.0, size.1
+        )];
+    }
+    vec![]
+}
+
+/// Counters of the cache.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CacheStats {
+    pub size: usize,
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+}
+
+#[derive(Default)]
+struct CacheCounter {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+impl CacheCounter {
+    fn stats(&self, size: usize) -> CacheStats {
+        CacheStats {
+            size,
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// http水印的验证信息，用于条件请求
+#[derive(Clone)]
+struct Validator {
+    // 条件请求的请求头，If-None-Match或If-Modified-Since
+    name: reqwest::header::HeaderName,
+    value: reqwest::header::HeaderValue,
+}
+
+impl Validator {
+    // etag优先，无则使用last-modified
+    fn from_headers(headers: &reqwest::header::HeaderMap) -> Option<Self> {
+        use reqwest::header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+        headers
+            .get(ETAG)
+            .map(|value| (IF_NONE_MATCH, value))
+            .or_else(|| {
+                headers
+                    .get(LAST_MODIFIED)
+                    .map(|value| (IF_MODIFIED_SINCE, value))
+            })
+            .map(|(name, value)| Validator {
+                name,
+                value: value.clone(),
+            })
+    }
+}
+
+// 水印缓存，记录缓存的时间用于判断是否过期
+struct WatermarkCache {
+    cache: LruCache<u64, (DynamicImage, Instant)>,
+    // http水印最近一次的验证信息
+    validators: LruCache<String, Validator>,
+    counter: CacheCounter,
+}
+
+impl WatermarkCache {
+    // 过期的则删除
+    fn get(&mut self, key: u64) -> Option<DynamicImage> {
+        let ttl = config::get_watermark_cache_ttl();
+        match self.cache.get(&key) {
+            Some((_, cached_at)) if !ttl.is_zero() && cached_at.elapsed() > ttl => {
+                self.cache.pop(&key);
+                self.counter.evictions.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+            Some((di, _)) => {
+                self.counter.hits.fetch_add(1, Ordering::Relaxed);
+                Some(di.clone())
+            }
+            None => None,
+        }
+    }
+    fn put(&mut self, key: u64, di: &DynamicImage, size: usize) {
+        let max_bytes = config::get_watermark_cache_max_bytes();
+        // 超过大小限制的不缓存
+        if max_bytes != 0 && di.as_bytes().len() > max_bytes {
+            return;
+        }
+        let evicted = self
+            .cache
+            .push(key, (di.clone(), Instant::now()))
+            .is_some_and(|(evicted_key, _)| evicted_key != key);
+        // 容量按配置限制
+        while self.cache.len() > size {
+            self.cache.pop_lru();
+            self.counter.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+        if evicted {
+            self.counter.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+static WATERMARK_CACHE: Lazy<Mutex<WatermarkCache>> = Lazy::new(|| {
+    let size = NonZeroUsize::new(config::get_watermark_cache_size()).unwrap_or(NonZeroUsize::MIN);
+    Mutex::new(WatermarkCache {
+        cache: LruCache::new(size),
+        validators: LruCache::new(size),
+        counter: CacheCounter::default(),
+    })
+});
+
+// 图片来源的key，文件则加上大小与修改时间，http则加上etag或last-modified，来源更新后缓存失效
+async fn get_source_key(url: &str, version: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    url.hash(&mut hasher);
+    version.hash(&mut hasher);
+    if let Some(file) = url.strip_prefix(FILE_PREFIX) {
+        if let Ok(meta) = tokio::fs::metadata(file).await {
+            meta.len().hash(&mut hasher);
+            if let Ok(modified) = meta.modified() {
+                modified.hash(&mut hasher);
+            }
+        }
+    }
+    hasher.finish()
+}
+
+// 加载http水印，有验证信息时发送条件请求，未修改(304)则返回None
+async fn fetch_watermark(
+    url: &str,
+    validator: Option<&Validator>,
+) -> Result<Option<(DynamicImage, Option<Validator>)>> {
+    let _permit = http_client::acquire(url).await;
+    let timeout = config::get_load_timeout();
+    let mut req = http_client::get_client().get(url).timeout(timeout);
+    if let Some(validator) = validator {
+        req = req.header(validator.name.clone(), validator.value.clone());
+    }
+    let to_error = |e: reqwest::Error| {
+        if e.is_timeout() {
+            TimeoutSnafu {
+                stage: STAGE_LOAD,
+                timeout,
+            }
+            .build()
+        } else {
+            ImageProcessingError::Reqwest { source: e }
+        }
+    };
+    let resp = with_loader_credentials(req, url)
+        .send()
+        .await
+        .and_then(|resp| resp.error_for_status())
+        .map_err(to_error)?;
+    if resp.status() == reqwest::StatusCode::NOT_MODIFIED {
+        return Ok(None);
+    }
+    let validator = Validator::from_headers(resp.headers());
+    let ext = resp
+        .headers()
+        .get("Content-Type")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split_once('/'))
+        .map(|(_, ext)| ext.to_string())
+        .unwrap_or_default();
+    let data: Vec<u8> = resp.bytes().await.map_err(to_error)?.into();
+    let ext = sniff_ext(&data, &ext)?;
+    validate_header(&data, &ext)?;
+    let di = run_blocking(STAGE_DECODE, config::get_decode_timeout(), move || {
+        ProcessImage::decode(&data, &ext)
+    })
+    .await??;
+    Ok(Some((di, validator)))
+}
+
+// http水印的缓存以url与验证信息为key，每次使用时发送条件请求，因此水印更新后即可生效。
+// 无验证信息的则仅以url为key，在过期前不再请求
+async fn load_http_watermark(url: &str, size: usize) -> Result<DynamicImage> {
+    let key = get_source_key(url, &[]).await;
+    let validator = {
+        let mut watermarks = WATERMARK_CACHE.lock().await;
+        if let Some(di) = watermarks.get(key) {
+            return Ok(di);
+        }
+        watermarks.validators.get(url).cloned()
+    };
+    let mut fetched = None;
+    if let Some(validator) = validator {
+        fetched = fetch_watermark(url, Some(&validator)).await?;
+        if fetched.is_none() {
+            let key = get_source_key(url, validator.value.as_bytes()).await;
+            if let Some(di) = WATERMARK_CACHE.lock().await.get(key) {
+                return Ok(di);
+            }
+        }
+    }
+    // 未缓存(或未修改但缓存已被淘汰)则重新加载
+    let (di, validator) = match fetched {
+        Some(fetched) => fetched,
+        None => fetch_watermark(url, None).await?.ok_or_else(|| {
+            ParamsInvalidSnafu {
+                message: format!("watermark({url}) is not modified but not cached"),
+            }
+            .build()
+        })?,
+    };
+    let version = validator
+        .as_ref()
+        .map(|validator| validator.value.as_bytes().to_vec())
+        .unwrap_or_default();
+    let key = get_source_key(url, &version).await;
+    let mut watermarks = WATERMARK_CACHE.lock().await;
+    watermarks.counter.misses.fetch_add(1, Ordering::Relaxed);
+    watermarks.put(key, &di, size);
+    match validator {
+        Some(validator) => {
+            watermarks.validators.put(url.to_string(), validator);
+        }
+        None => {
+            watermarks.validators.pop(url);
+        }
+    }
+    Ok(di)
+}
+
+/// Load the watermark image, it is cached by the hash of url and version,
+/// the version is the size and modified time of file, or the etag(last-modified) of http.
+pub async fn load_watermark(url: &str) -> Result<DynamicImage> {
+    let size = config::get_watermark_cache_size();
+    if size != 0 && url.starts_with("http") {
+        return load_http_watermark(url, size).await;
+    }
+    let key = get_source_key(url, &[]).await;
+    if size != 0 {
+        let mut watermarks = WATERMARK_CACHE.lock().await;
+        if let Some(di) = watermarks.get(key) {
+            return Ok(di);
+        }
+        watermarks.counter.misses.fetch_add(1, Ordering::Relaxed);
+    }
+    let watermark = LoaderProcess::new(url, "")
+        .process(ProcessImage {
+            ..Default::default()
+        })
+        .await?;
+    if size != 0 {
+        WATERMARK_CACHE.lock().await.put(key, &watermark.di, size);
+    }
+    Ok(watermark.di)
+}
+
+// 解码后的图片缓存，按像素总数限制
+struct DecodedCache {
+    cache: LruCache<u64, ProcessImage>,
+    pixels: u64,
+    counter: CacheCounter,
+}
+
+static DECODED_CACHE: Lazy<Mutex<DecodedCache>> = Lazy::new(|| {
+    Mutex::new(DecodedCache {
+        cache: LruCache::unbounded(),
+        pixels: 0,
+        counter: CacheCounter::default(),
+    })
+});
+
+async fn get_decoded_cache(key: u64) -> Option<ProcessImage> {
+    if config::get_decoded_cache_pixels() == 0 {
+        return None;
+    }
+    let mut decoded = DECODED_CACHE.lock().await;
+    let img = decoded.cache.get(&key).cloned();
+    let counter = if img.is_some() {
+        &decoded.counter.hits
+    } else {
+        &decoded.counter.misses
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+    // 报告为每次请求的数据，命中时重置
+    img.map(|mut img| {
+        img.report = ProcessReport {
+            decoded_cache: Some("hit"),
+            ..Default::default()
+        };
+        img
+    })
+}
+
+async fn put_decoded_cache(key: u64, img: &ProcessImage) {
+    let limit = config::get_decoded_cache_pixels();
+    // 无法解码、超过限制或原始数据已写入临时文件的不缓存
+    if limit == 0 || img.passthrough || img.spill.is_some() || img.source_pixels > limit {
+        return;
+    }
+    let mut decoded = DECODED_CACHE.lock().await;
+    if let Some(prev) = decoded.cache.put(key, img.clone()) {
+        decoded.pixels -= prev.source_pixels;
+    }
+    decoded.pixels += img.source_pixels;
+    while decoded.pixels > limit {
+        let Some((_, item)) = decoded.cache.pop_lru() else {
+            break;
+        };
+        decoded.pixels -= item.source_pixels;
+        decoded.counter.evictions.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Clear the watermark cache, returns the count of removed images.
+pub async fn clear_watermark_cache() -> usize {
+    let mut watermarks = WATERMARK_CACHE.lock().await;
+    let count = watermarks.cache.len();
+    watermarks.cache.clear();
+    watermarks.validators.clear();
+    count
+}
+
+/// Stats of the watermark cache and the decoded image cache.
+pub async fn get_cache_stats() -> (CacheStats, CacheStats) {
+    let watermarks = WATERMARK_CACHE.lock().await;
+    let decoded = DECODED_CACHE.lock().await;
+    (
+        watermarks.counter.stats(watermarks.cache.len()),
+        decoded.counter.stats(decoded.cache.len()),
+    )
+}
+
+// 超过阈值写入临时文件的原始数据，在drop时删除
+#[derive(Debug)]
+struct SpillFile {
+    path: PathBuf,
+    size: usize,
+}
+
+impl SpillFile {
+    async fn new(data: &[u8]) -> Result<Self> {
+        let path = std::env::temp_dir().join(format!("image-optim-{}", nanoid::nanoid!(16)));
+        tokio::fs::write(&path, data).await.context(IoSnafu)?;
+        Ok(SpillFile {
+            path,
+            size: data.len(),
+        })
+    }
+    async fn read(&self) -> Result<Vec<u8>> {
+        tokio::fs::read(&self.path).await.context(IoSnafu)
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        let path = std::mem::take(&mut self.path);
+        let remove = move || {
+            if let Err(e) = std::fs::remove_file(&path) {
+                tracing::warn!(file = path.to_string_lossy().to_string(), "{e}");
+            }
+        };
+        // 在tokio中则使用阻塞线程删除
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => {
+                handle.spawn_blocking(remove);
+            }
+            Err(_) => remove(),
+        }
+    }
+}
+
+// 相机raw格式
+#[cfg(feature = "raw")]
+const RAW_EXTS: [&str; 7] = ["dng", "cr2", "nef", "arw", "orf", "rw2", "raf"];
+
+// raw数据解码，使用imagepipe的默认处理(去马赛克、白平衡等)
+#[cfg(feature = "raw")]
+fn decode_raw(data: &[u8]) -> Result<DynamicImage> {
+    let raw = rawloader::decode(&mut Cursor::new(data)).map_err(|e| {
+        RawSnafu {
+            message: e.to_string(),
+        }
+        .build()
+    })?;
+    let mut pipeline = imagepipe::Pipeline::new_from_source(imagepipe::ImageSource::Raw(raw))
+        .map_err(|message| RawSnafu { message }.build())?;
+    let img = pipeline
+        .output_8bit(None)
+        .map_err(|message| RawSnafu { message }.build())?;
+    let buffer = image::RgbImage::from_raw(img.width as u32, img.height as u32, img.data)
+        .ok_or_else(|| {
+            RawSnafu {
+                message: "image size is invalid",
+            }
+            .build()
+        })?;
+    Ok(DynamicImage::ImageRgb8(buffer))
+}
+
+#[derive(Default, Clone)]
+pub struct ProcessImage {
+    original: Option<RgbaImage>,
+    di: DynamicImage,
+    pub diff: f64,
+    pub original_size: usize,
+    pub source_pixels: u64,
+    buffer: Vec<u8>,
+    spill: Option<Arc<SpillFile>>,
+    pub ext: String,
+    /// Format of the source image.
+    pub source_ext: String,
+    pub peak_memory: usize,
+    /// The original data is responded without processing.
+    pub passthrough: bool,
+    /// Quality and speed actually used by the encoder.
+    pub encoding: Option<AppliedEncoding>,
+    pub report: ProcessReport,
+    /// Headers of http source passed through to the response.
+    pub source_headers: Vec<(String, String)>,
+    /// Content kind classified for the auto output type.
+    pub content: Option<ContentKind>,
+    /// Data of the animated webp source re-encoded frame by frame.
+    animation: Option<Arc<Vec<u8>>>,
+}
+
+impl ProcessImage {
+    fn decode(data: &[u8], ext: &str) -> Result<DynamicImage> {
+        #[cfg(feature = "raw")]
+        if RAW_EXTS.contains(&ext.to_lowercase().as_str()) {
+            return decode_raw(data);
+        }
+        let format = ImageFormat::from_extension(OsStr::new(ext))
+            .ok_or_else(|| UnsupportedFormatSnafu { ext }.build())?;
+        load(Cursor::new(data), format).context(ImageSnafu {})
+    }
+    fn from_decoded(data: Vec<u8>, ext: &str, di: DynamicImage) -> Self {
+        let mut img = ProcessImage {
+            original_size: data.len(),
+            source_pixels: di.width() as u64 * di.height() as u64,
+            di,
+            buffer: data,
+            diff: -1.0,
+            ext: ext.to_string(),
+            source_ext: ext.to_string(),
+            ..Default::default()
+        };
+        img.update_peak_memory();
+        img
+    }
+    // 无法解码的图片，直接使用原始数据
+    fn new_passthrough(data: Vec<u8>, ext: &str) -> Self {
+        ProcessImage {
+            original_size: data.len(),
+            buffer: data,
+            diff: -1.0,
+            ext: ext.to_string(),
+            source_ext: ext.to_string(),
+            passthrough: true,
+            ..Default::default()
+        }
+    }
+    // 原始数据超过阈值则写入临时文件，减少内存占用
+    async fn spill(&mut self) -> Result<()> {
+        let limit = config::get_spill_size();
+        if limit == 0 || self.buffer.len() <= limit {
+            return Ok(());
+        }
+        self.spill = Some(Arc::new(SpillFile::new(&self.buffer).await?));
+        self.buffer = vec![];
+        Ok(())
+    }
+    async fn get_original_buffer(&self) -> Result<Vec<u8>> {
+        if let Some(spill) = &self.spill {
+            return spill.read().await;
+        }
+        Ok(self.buffer.clone())
+    }
+    // 设置新的数据，原有的临时文件不再需要
+    fn set_buffer(&mut self, data: Vec<u8>) {
+        self.buffer = data;
+        self.spill = None;
+    }
+    fn buffer_len(&self) -> usize {
+        if let Some(spill) = &self.spill {
+            return spill.size;
+        }
+        self.buffer.len()
+    }
+    // 当前占用的内存：图像数据、原始图像以及编码数据
+    fn update_peak_memory(&mut self) {
+        let mut size = self.di.as_bytes().len() + self.buffer.len();
+        if let Some(original) = &self.original {
+            size += original.as_raw().len();
+        }
+        self.peak_memory = self.peak_memory.max(size);
+    }
+    /// Get the decoded image.
+    pub fn get_image(&self) -> &DynamicImage {
+        &self.di
+    }
+    pub async fn get_buffer(&self) -> Result<Vec<u8>> {
+        if self.buffer_len() == 0 {
+            let mut bytes: Vec<u8> = Vec::new();
+            let format =
+                ImageFormat::from_extension(self.ext.as_str()).unwrap_or(ImageFormat::Jpeg);
+            self.di
+                .write_to(&mut Cursor::new(&mut bytes), format)
+                .context(ImageSnafu {})?;
+            Ok(bytes)
+        } else {
+            self.get_original_buffer().await
+        }
+    }
+    // 解码编码后的数据
+    async fn decode_buffer(&self) -> Result<DynamicImage> {
+        let data = self.get_buffer().await?;
+        let ext = self.ext.clone();
+        run_blocking(STAGE_DECODE, config::get_decode_timeout(), move || {
+            decode_data(data, &ext)
+        })
+        .await?
+    }
+    fn support_dssim(&self) -> bool {
+        ![IMAGE_TYPE_GIF, IMAGE_TYPE_MP4, IMAGE_TYPE_WEBM].contains(&self.ext.as_str())
+    }
+    // 在其它线程中计算差异值，按配置抽样计算，图片较大时可缩小后计算(结果为近似值)
+    async fn update_diff(&mut self) -> Result<()> {
+        self.diff = -1.0;
+        // 如果是gif或者禁用了dssim
+        if !self.support_dssim() || !is_diff_sampled() {
+            return Ok(());
+        }
+        // 如果无数据
+        let Some(original) = self.original.take() else {
+            return Ok(());
+        };
+        // 如果宽高不一致，则不比对
+        if original.width() != self.di.width() || original.height() != self.di.height() {
+            self.original = Some(original);
+            return Ok(());
+        }
+        let max_width = config::get_diff_max_width();
+        let approximate = max_width != 0 && original.width() > max_width;
+        let di = std::mem::take(&mut self.di);
+        let (di, original, value) = run_blocking(STAGE_PROCESS, Duration::ZERO, move || {
+            let current = di.to_rgba8();
+            let value = if approximate {
+                let height = (original.height() as u64 * max_width as u64 / original.width() as u64)
+                    .max(1) as u32;
+                get_dssim(
+                    &resize(&original, max_width, height, FilterType::Triangle),
+                    &resize(&current, max_width, height, FilterType::Triangle),
+                )
+            } else {
+                get_dssim(&original, &current)
+            };
+            (di, original, value)
+        })
+        .await?;
+        self.di = di;
+        self.original = Some(original);
+        if let Some(value) = value {
+            // 放大1千倍
+            self.diff = value * 1000.0;
+            self.report.diff_approximate = approximate;
+        }
+        Ok(())
+    }
+}
+
+static DIFF_COUNT: AtomicU64 = AtomicU64::new(0);
+
+// 按百分比抽样，每100个请求中计算指定数量
+fn is_diff_sampled() -> bool {
+    let rate = config::get_diff_sample_rate();
+    if rate >= 100 {
+        return true;
+    }
+    DIFF_COUNT.fetch_add(1, Ordering::Relaxed) % 100 < rate as u64
+}
+
+#[async_trait]
+pub trait Process {
+    async fn process(&self, pi: ProcessImage) -> Result<ProcessImage>;
+}
+
+/// Loader process loads the image data from http, file or base64.
+pub struct LoaderProcess {
+    data: String,
+    bytes: Option<Arc<Vec<u8>>>,
+    ext: String,
+    keep_original: bool,
+    animated: AnimatedFrame,
+    keep_animation: bool,
+    page: u32,
+}
+
+impl LoaderProcess {
+    pub fn new(data: &str, ext: &str) -> Self {
+        LoaderProcess {
+            data: data.to_string(),
+            bytes: None,
+            ext: ext.to_string(),
+            keep_original: false,
+            animated: AnimatedFrame::default(),
+            keep_animation: false,
+            page: 0,
+        }
+    }
+    /// Load from the raw data instead of the data(url or base64).
+    pub fn with_bytes(mut self, bytes: Option<Arc<Vec<u8>>>) -> Self {
+        self.bytes = bytes;
+        self
+    }
+    /// Page of the multi-page tiff to load, starting from 1.
+    pub fn with_page(mut self, page: u32) -> Self {
+        self.page = page;
+        self
+    }
+    /// Keep the original image for diff.
+    pub fn with_original(mut self, keep_original: bool) -> Self {
+        self.keep_original = keep_original;
+        self
+    }
+    /// Set the frame of animated image decoded for static output, default is first.
+    pub fn with_animated(mut self, animated: AnimatedFrame) -> Self {
+        self.animated = animated;
+        self
+    }
+    /// Keep all frames of the animated webp for the webp output.
+    pub fn with_animation(mut self, keep_animation: bool) -> Self {
+        self.keep_animation = keep_animation;
+        self
+    }
+    // 动图选择的帧或tiff的页不同则解码结果不同，因此加入缓存的key
+    async fn get_cache_key(&self, url: &str, version: &[u8]) -> u64 {
+        let key = get_source_key(url, version).await;
+        if self.animated == AnimatedFrame::First && !self.keep_animation && self.page <= 1 {
+            return key;
+        }
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        self.animated.hash(&mut hasher);
+        self.keep_animation.hash(&mut hasher);
+        self.page.hash(&mut hasher);
+        hasher.finish()
+    }
+    async fn fetch_data(&self) -> Result<ProcessImage> {
+        // 图片目录中不存在的文件从源站加载
+        let origin = if self.bytes.is_some() {
+            None
+        } else {
+            get_origin_source(&self.data).await
+        };
+        let data = origin.as_ref().map(|(url, _)| url).unwrap_or(&self.data);
+        let mut ext = self.ext.clone();
+        let from_http = data.starts_with("http");
+        let from_file = data.starts_with(FILE_PREFIX);
+        let mut cache_key = None;
+        let mut source_headers = vec![];
+        let mut retries = 0;
+        let original_data = if let Some(bytes) = &self.bytes {
+            bytes.to_vec()
+        } else if from_http {
+            let client = http_client::get_client();
+            // 限制同一host的并发请求，读取完数据后释放
+            let _permit = http_client::acquire(data).await;
+            let max_retries = config::get_loader_retries();
+            // 加载(包括重试与读取数据)的超时，超时则不再重试
+            let load_timeout = config::get_load_timeout();
+            let deadline = Instant::now() + load_timeout;
+            let to_error = |e: reqwest::Error| {
+                if e.is_timeout() {
+                    TimeoutSnafu {
+                        stage: STAGE_LOAD,
+                        timeout: load_timeout,
+                    }
+                    .build()
+                } else {
+                    ImageProcessingError::Reqwest { source: e }
+                }
+            };
+            let resp = loop {
+                let req = client
+                    .get(data)
+                    .timeout(deadline.saturating_duration_since(Instant::now()));
+                // 非成功的响应(如404)则出错，避免错误页面被当作图片
+                let result = with_loader_credentials(req, data)
+                    .send()
+                    .await
+                    .and_then(|resp| resp.error_for_status());
+                let backoff = get_retry_backoff(retries);
+                match result {
+                    Err(e)
+                        if retries < max_retries
+                            && is_transient_error(&e)
+                            && Instant::now() + backoff < deadline =>
+                    {
+                        tokio::time::sleep(backoff).await;
+                        retries += 1;
+                    }
+                    result => break result.map_err(to_error)?,
+                }
+            };
+
+            // 有etag(或last-modified)的才缓存
+            let version = resp
+                .headers()
+                .get("ETag")
+                .or_else(|| resp.headers().get("Last-Modified"));
+            if let Some(version) = version {
+                let key = self.get_cache_key(data, version.as_bytes()).await;
+                if let Some(mut img) = get_decoded_cache(key).await {
+                    img.source_headers = get_pass_headers(resp.headers());
+                    img.report.retries = retries;
+                    return Ok(img);
+                }
+                cache_key = Some(key);
+            }
+            source_headers = get_pass_headers(resp.headers());
+            if let Some(content_type) = resp.headers().get("Content-Type") {
+                let str = content_type.to_str().context(HTTPHeaderToStrSnafu {})?;
+                let arr: Vec<_> = str.split('/').collect();
+                if arr.len() == 2 {
+                    ext = arr[1].to_string();
+                }
+            }
+            let buf: Vec<u8> = resp.bytes().await.map_err(to_error)?.into();
+            if let Some((_, file)) = &origin {
+                store_origin_file(file, &buf).await;
+            }
+            buf
+        } else if from_file {
+            let key = self.get_cache_key(data, &[]).await;
+            if let Some(img) = get_decoded_cache(key).await {
+                return Ok(img);
+            }
+            cache_key = Some(key);
+            ext = data.split('.').next_back().unwrap_or_default().to_string();
+            tokio::fs::read(data.substring(FILE_PREFIX.len(), data.len()))
+                .await
+                .context(IoSnafu)?
+        } else {
+            general_purpose::STANDARD
+                .decode(data.as_bytes())
+                .context(Base64DecodeSnafu {})?
+        };
+        let ext = sniff_ext(&original_data, &ext)?;
+        validate_header(&original_data, &ext)?;
+        // webp动图保留所有帧时无需选择帧
+        let animation = (self.keep_animation
+            && ext == IMAGE_TYPE_WEBP
+            && WebPDecoder::new(Cursor::new(&original_data))
+                .is_ok_and(|decoder| decoder.has_animation()))
+        .then(|| Arc::new(original_data.clone()));
+        let animated = if animation.is_some() {
+            AnimatedFrame::First
+        } else {
+            self.animated
+        };
+        let page = self.page;
+        let decode_ext = ext.clone();
+        let (original_data, decoded) =
+            run_blocking(STAGE_DECODE, config::get_decode_timeout(), move || {
+                let ext = decode_ext;
+                // 多页tiff指定了页则仅解码该页
+                let decoded = select_tiff_page(&original_data, &ext, page)
+                    .transpose()
+                    .unwrap_or_else(|| ProcessImage::decode(&original_data, &ext))
+                    .map(|di| {
+                        select_animated_frame(&original_data, &ext, animated)
+                            .map(|frame| frame.unwrap_or(di))
+                    });
+                (original_data, decoded)
+            })
+            .await?;
+        let mut img = match decoded {
+            Ok(di) => {
+                let di = di?;
+                let mut img = ProcessImage::from_decoded(original_data, &ext, di);
+                img.animation = animation;
+                img
+            }
+            // 仅内容为已知的图片格式时透传，避免返回非图片的数据
+            Err(e) if config::is_passthrough() && image::guess_format(&original_data).is_ok() => {
+                tracing::warn!(ext, "Decode image fail, passthrough the original, {e}");
+                ProcessImage::new_passthrough(original_data, &ext)
+            }
+            Err(e) => return Err(e),
+        };
+        img.spill().await?;
+        img.source_headers = source_headers;
+        img.report.retries = retries;
+        if let Some(key) = cache_key {
+            if config::get_decoded_cache_pixels() != 0 {
+                img.report.decoded_cache = Some("miss");
+            }
+            put_decoded_cache(key, &img).await;
+        }
+        Ok(img)
+    }
+}
+
+// 在阻塞线程中执行解码或编码，超时则出错(线程中的处理仍会继续至完成)，0表示不限制。
+// 配置了编码线程时编码在编码线程中执行
+pub(crate) async fn run_blocking<T, F>(stage: &'static str, timeout: Duration, f: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    // 阻塞处理持有任务的guard，future被drop后仍计数至处理完成
+    let job = state::current_job();
+    let f = move || {
+        let _job = job;
+        f()
+    };
+    let task = async move {
+        if stage == STAGE_ENCODE && encoder_pool::is_enabled() {
+            encoder_pool::spawn(f).await.context(RecvSnafu)
+        } else {
+            tokio::task::spawn_blocking(f).await.context(JoinSnafu)
+        }
+    };
+    if timeout.is_zero() {
+        return task.await;
+    }
+    match tokio::time::timeout(timeout, task).await {
+        Ok(result) => result,
+        Err(_) => TimeoutSnafu { stage, timeout }.fail(),
+    }
+}
+
+// 仅缩放并输出为webp(或不指定格式)时，保留webp动图的所有帧
+fn is_animation_kept(tasks: &[Task]) -> bool {
+    tasks.iter().all(|task| match task {
+        Task::Load { .. } | Task::Resize { .. } | Task::Diff => true,
+        Task::Optim { output_type, .. } => {
+            matches!(output_type, None | Some(OutputType::Webp))
+        }
+        _ => false,
+    })
+}
+
+// 压缩为指定的静态格式时，动图使用的帧(任务的配置优先)
+fn get_animated_frame(tasks: &[Task]) -> AnimatedFrame {
+    let optim = tasks.iter().rev().find_map(|task| match task {
+        Task::Optim {
+            output_type,
+            options,
+            ..
+        } => Some((*output_type, options.animated)),
+        _ => None,
+    });
+    let Some((Some(output_type), animated)) = optim else {
+        return AnimatedFrame::First;
+    };
+    if matches!(
+        output_type,
+        OutputType::Gif | OutputType::Mp4 | OutputType::Webm
+    ) {
+        return AnimatedFrame::First;
+    }
+    animated
+        .or_else(|| AnimatedFrame::from_name(&config::get_animated_frame()))
+        .unwrap_or_default()
+}
+
+// 解码多页tiff中指定的页(从1开始)，第一页则无需处理
+fn select_tiff_page(data: &[u8], ext: &str, page: u32) -> Result<Option<DynamicImage>> {
+    if page <= 1 || ImageFormat::from_extension(OsStr::new(ext)) != Some(ImageFormat::Tiff) {
+        return Ok(None);
+    }
+    let mut decoder = tiff::decoder::Decoder::new(Cursor::new(data)).context(TiffSnafu)?;
+    decoder.seek_to_image(page as usize - 1).map_err(|_| {
+        ParamsInvalidSnafu {
+            message: format!("page({page}) of tiff is out of range"),
+        }
+        .build()
+    })?;
+    let (width, height) = decoder.dimensions().context(TiffSnafu)?;
+    let color_type = decoder.colortype().context(TiffSnafu)?;
+    let di = match (color_type, decoder.read_image().context(TiffSnafu)?) {
+        (tiff::ColorType::Gray(8), DecodingResult::U8(buf)) => {
+            ImageBuffer::from_raw(width, height, buf).map(DynamicImage::ImageLuma8)
+        }
+        (tiff::ColorType::GrayA(8), DecodingResult::U8(buf)) => {
+            ImageBuffer::from_raw(width, height, buf).map(DynamicImage::ImageLumaA8)
+        }
+        (tiff::ColorType::RGB(8), DecodingResult::U8(buf)) => {
+            ImageBuffer::from_raw(width, height, buf).map(DynamicImage::ImageRgb8)
+        }
+        (tiff::ColorType::RGBA(8), DecodingResult::U8(buf)) => {
+            ImageBuffer::from_raw(width, height, buf).map(DynamicImage::ImageRgba8)
+        }
+        (tiff::ColorType::Gray(16), DecodingResult::U16(buf)) => {
+            ImageBuffer::from_raw(width, height, buf).map(DynamicImage::ImageLuma16)
+        }
+        (tiff::ColorType::RGB(16), DecodingResult::U16(buf)) => {
+            ImageBuffer::from_raw(width, height, buf).map(DynamicImage::ImageRgb16)
+        }
+        (tiff::ColorType::RGBA(16), DecodingResult::U16(buf)) => {
+            ImageBuffer::from_raw(width, height, buf).map(DynamicImage::ImageRgba16)
+        }
+        _ => None,
+    };
+    let di = di.ok_or_else(|| {
+        UnsupportedFormatSnafu {
+            ext: format!("tiff({color_type:?})"),
+        }
+        .build()
+    })?;
+    Ok(Some(di))
+}
+
+// 动图转换为静态格式时，选择中间帧或出错，第一帧则无需处理
+fn select_animated_frame(
+    data: &[u8],
+    ext: &str,
+    animated: AnimatedFrame,
+) -> Result<Option<DynamicImage>> {
+    if animated == AnimatedFrame::First {
+        return Ok(None);
+    }
+    let format = ImageFormat::from_extension(OsStr::new(ext));
+    let frames = || -> Result<Option<Frames>> {
+        let frames = match format {
+            Some(ImageFormat::Gif) => GifDecoder::new(Cursor::new(data))
+                .context(ImageSnafu)?
+                .into_frames(),
+            Some(ImageFormat::WebP) => {
+                let decoder = WebPDecoder::new(Cursor::new(data)).context(ImageSnafu)?;
+                if !decoder.has_animation() {
+                    return Ok(None);
+                }
+                decoder.into_frames()
+            }
+            Some(ImageFormat::Png) => {
+                let decoder = PngDecoder::new(Cursor::new(data)).context(ImageSnafu)?;
+                if !decoder.is_apng().context(ImageSnafu)? {
+                    return Ok(None);
+                }
+                decoder.apng().context(ImageSnafu)?.into_frames()
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(frames))
+    };
+    let Some(count) = frames()?.map(|frames| frames.count()) else {
+        return Ok(None);
+    };
+    if count <= 1 {
+        return Ok(None);
+    }
+    ensure!(
+        animated != AnimatedFrame::Error,
+        ParamsInvalidSnafu {
+            message: "animated image can not be converted to static format",
+        }
+    );
+    // 再次解码获取中间帧，避免所有帧同时占用内存
+    let frame = frames()?
+        .and_then(|mut frames| frames.nth(count / 2))
+        .transpose()
+        .context(ImageSnafu)?;
+    Ok(frame.map(|frame| DynamicImage::ImageRgba8(frame.into_buffer())))
+}
+
+// 图片目录中的文件不存在且配置了源站，则返回源站的地址与本地文件
+async fn get_origin_source(data: &str) -> Option<(String, String)> {
+    let file = data.strip_prefix(FILE_PREFIX)?;
+    let origin = config::get_origin_url();
+    let root = config::get_optim_path();
+    if origin.is_empty() || root.is_empty() || tokio::fs::try_exists(file).await.unwrap_or_default()
+    {
+        return None;
+    }
+    let relative = file.strip_prefix(&root)?.trim_start_matches('/');
+    // 避免通过..访问源站的其它路径
+    if relative.is_empty() || relative.split('/').any(|item| item == "..") {
+        return None;
+    }
+    // 文件路径未编码，空格、+、中文等按路径段编码后请求源站
+    let relative = relative
+        .split('/')
+        .map(|item| encode(item).to_string())
+        .collect::<Vec<_>>()
+        .join("/");
+    Some((
+        format!("{}/{relative}", origin.trim_end_matches('/')),
+        file.to_string(),
+    ))
+}
+
+// 源站加载的文件写入图片目录，失败则仅输出日志
+async fn store_origin_file(file: &str, data: &[u8]) {
+    if !config::is_origin_store() {
+        return;
+    }
+    let path = std::path::Path::new(file);
+    let result = async {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::write(path, data).await
+    }
+    .await;
+    if let Err(e) = result {
+        tracing::warn!(file, "Store origin file fail, {e}");
+    }
+}
+
+// 超时、连接失败以及5xx(或429)的响应可重试
+fn is_transient_error(e: &reqwest::Error) -> bool {
+    if e.is_timeout() || e.is_connect() {
+        return true;
+    }
+    e.status()
+        .map(|status| status.is_server_error() || status.as_u16() == 429)
+        .unwrap_or_default()
+}
+
+// 指数退避并加上随机抖动(0-50%)
+fn get_retry_backoff(retries: u32) -> Duration {
+    let delay = config::get_loader_retry_backoff().as_millis() as u64 * (1 << retries.min(10));
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos() as u64;
+    Duration::from_millis(delay + nanos % (delay / 2 + 1))
+}
+
+/// Format and dimensions of the image, read from the header without decoding the pixels.
+#[derive(Debug, Clone, Serialize)]
+pub struct ImageHeader {
+    pub format: String,
+    pub width: u32,
+    pub height: u32,
+}
+
+fn read_header<R: std::io::BufRead + std::io::Seek>(
+    reader: image::ImageReader<R>,
+) -> Result<ImageHeader> {
+    let format = reader
+        .format()
+        .ok_or_else(|| UnsupportedFormatSnafu { ext: "" }.build())?;
+    let (width, height) = reader.into_dimensions().context(ImageSnafu {})?;
+    Ok(ImageHeader {
+        format: get_format_ext(format),
+        width,
+        height,
+    })
+}
+
+/// Probe the header of image data, the ext is used if the format can not be guessed.
+pub fn probe_header(data: &[u8], ext: &str) -> Result<ImageHeader> {
+    let mut reader = image::ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .context(IoSnafu)?;
+    if reader.format().is_none() {
+        reader.set_format(
+            ImageFormat::from_extension(OsStr::new(ext))
+                .ok_or_else(|| UnsupportedFormatSnafu { ext }.build())?,
+        );
+    }
+    read_header(reader)
+}
+
+/// Probe the header of image file, only the header is read from the file.
+pub fn probe_file(file: &str) -> Result<ImageHeader> {
+    let reader = image::ImageReader::open(file)
+        .context(IoSnafu)?
+        .with_guessed_format()
+        .context(IoSnafu)?;
+    read_header(reader)
+}
+
+// 解码前根据文件头校验尺寸，避免解码超大的图片
+fn validate_header(data: &[u8], ext: &str) -> Result<()> {
+    let max = config::get_max_dimension();
+    // 无法读取文件头(如raw格式)则在解码后处理
+    let Ok(header) = probe_header(data, ext) else {
+        return Ok(());
+    };
+    let mut violations = vec![];
+    for (name, value) in [("width", header.width), ("height", header.height)] {
+        if value > max {
+            violations.push(format!(
+                "source {name} {value} should not be larger than {max}"
+            ));
+        }
+    }
+    ensure_valid(violations)
+}
+
+// 以文件头识别的格式为准，无法识别时才使用扩展名(或Content-Type)
+fn sniff_ext(data: &[u8], ext: &str) -> Result<String> {
+    #[cfg(feature = "raw")]
+    if RAW_EXTS.contains(&ext.to_lowercase().as_str()) {
+        return Ok(ext.to_string());
+    }
+    let declared = ImageFormat::from_extension(OsStr::new(ext));
+    let Ok(format) = image::guess_format(data) else {
+        // html或svg等标记内容伪装为图片，拒绝处理(避免透传时返回)
+        let head = &data[..data.len().min(512)];
+        ensure!(
+            !head.trim_ascii_start().starts_with(b"<"),
+            ParamsInvalidSnafu {
+                message: "image content is markup, not image",
+            }
+        );
+        return Ok(ext.to_string());
+    };
+    if declared == Some(format) {
+        return Ok(get_format_ext(format));
+    }
+    if declared.is_some() {
+        ensure!(
+            !config::is_strict_format(),
+            ParamsInvalidSnafu {
+                message: format!("image format({ext}) does not match the content"),
+            }
+        );
+        tracing::warn!(ext, "Image format does not match the content");
+    }
+    Ok(get_format_ext(format))
+}
+
+// 格式对应的类型，与输出的类型一致(如jpg为jpeg)
+fn get_format_ext(format: ImageFormat) -> String {
+    match format {
+        ImageFormat::Jpeg => IMAGE_TYPE_JPEG,
+        ImageFormat::Png => IMAGE_TYPE_PNG,
+        ImageFormat::Gif => IMAGE_TYPE_GIF,
+        ImageFormat::WebP => IMAGE_TYPE_WEBP,
+        ImageFormat::Avif => IMAGE_TYPE_AVIF,
+        _ => format.extensions_str()[0],
+    }
+    .to_string()
+}
+
+// 根据配置获取需要透传的响应头
+fn get_pass_headers(headers: &reqwest::header::HeaderMap) -> Vec<(String, String)> {
+    let names = config::get_pass_headers();
+    if names.is_empty() {
+        return vec![];
+    }
+    headers
+        .iter()
+        .filter(|(name, _)| {
+            let name = name.as_str();
+            names.iter().any(|item| match item.strip_suffix('*') {
+                Some(prefix) => name.starts_with(prefix),
+                None => name == item,
+            })
+        })
+        .filter_map(|(name, value)| {
+            let value = value.to_str().ok()?;
+            Some((name.to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+// 图片加载
+#[async_trait]
+impl Process for LoaderProcess {
+    async fn process(&self, _: ProcessImage) -> Result<ProcessImage> {
+        let mut img = self.fetch_data().await?;
+        // 原始图片仅用于计算差异值
+        if self.keep_original && !img.passthrough {
+            img.original = Some(img.di.to_rgba8());
+        }
+        img.update_peak_memory();
+        Ok(img)
+    }
+}
+
+/// Aspect ratio of resize when both width and height are specified.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResizeAspect {
+    /// Resize to the specified size exactly.
+    #[default]
+    Free,
+    /// Keep the aspect ratio and fit within the specified size.
+    Lock,
+}
+
+impl FromStr for ResizeAspect {
+    type Err = ImageProcessingError;
+    fn from_str(value: &str) -> Result<Self> {
+        let aspect = match value {
+            "" | "free" => ResizeAspect::Free,
+            "lock" => ResizeAspect::Lock,
+            _ => {
+                return ParamsInvalidSnafu {
+                    message: format!("resize aspect({value}) is not support"),
+                }
+                .fail()
+            }
+        };
+        Ok(aspect)
+    }
+}
+
+/// Rounding of the size calculated by aspect ratio.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResizeRounding {
+    #[default]
+    Floor,
+    Round,
+    Ceil,
+    /// Round to the nearest even number.
+    Even,
+}
+
+impl FromStr for ResizeRounding {
+    type Err = ImageProcessingError;
+    fn from_str(value: &str) -> Result<Self> {
+        let rounding = match value {
+            "" | "floor" => ResizeRounding::Floor,
+            "round" => ResizeRounding::Round,
+            "ceil" => ResizeRounding::Ceil,
+            "even" => ResizeRounding::Even,
+            _ => {
+                return ParamsInvalidSnafu {
+                    message: format!("resize rounding({value}) is not support"),
+                }
+                .fail()
+            }
+        };
+        Ok(rounding)
+    }
+}
+
+impl ResizeRounding {
+    // 使用整数计算num/den，避免浮点数误差导致尺寸不一致
+    fn apply(self, num: u64, den: u64) -> u32 {
+        let value = match self {
+            ResizeRounding::Floor => num / den,
+            ResizeRounding::Round => (2 * num + den) / (2 * den),
+            ResizeRounding::Ceil => num.div_ceil(den),
+            ResizeRounding::Even => (num + den) / (2 * den) * 2,
+        };
+        value as u32
+    }
+}
+
+/// Resize process resizes the image size.
+#[derive(Clone)]
+pub struct ResizeProcess {
+    width: u32,
+    height: u32,
+    aspect: ResizeAspect,
+    rounding: ResizeRounding,
+}
+
+impl ResizeProcess {
+    pub fn new(width: u32, height: u32) -> Self {
+        ResizeProcess {
+            width,
+            height,
+            aspect: ResizeAspect::default(),
+            rounding: ResizeRounding::default(),
+        }
+    }
+    /// Set the aspect ratio when both width and height are specified, default is free.
+    pub fn with_aspect(mut self, aspect: ResizeAspect) -> Self {
+        self.aspect = aspect;
+        self
+    }
+    /// Set the rounding of the calculated size, default is floor.
+    pub fn with_rounding(mut self, rounding: ResizeRounding) -> Self {
+        self.rounding = rounding;
+        self
+    }
+    // 根据原图尺寸计算缩放后的宽高
+    fn get_size(&self, width: u32, height: u32) -> (u32, u32) {
+        let (w, h) = (self.width as u64, self.height as u64);
+        let (width, height) = (width as u64, height as u64);
+        let round = |num, den| self.rounding.apply(num, den);
+        match (w, h) {
+            (0, 0) => (width as u32, height as u32),
+            // 如果宽或者高为0，则计算对应的宽高
+            (0, _) => (round(width * h, height), h as u32),
+            (_, 0) => (w as u32, round(height * w, width)),
+            // 锁定比例则以缩放比例较小的一边为准
+            _ if self.aspect == ResizeAspect::Lock => {
+                if w * height <= h * width {
+                    (w as u32, round(height * w, width))
+                } else {
+                    (round(width * h, height), h as u32)
+                }
+            }
+            _ => (w as u32, h as u32),
+        }
+    }
+    fn apply(&self, pi: ProcessImage) -> Result<ProcessImage> {
+        let mut img = pi;
+        if self.width == 0 && self.height == 0 {
+            return Ok(img);
+        }
+        let (w, h) = self.get_size(img.di.width(), img.di.height());
+        let result = resize(&img.di, w, h, FilterType::Lanczos3);
+        img.set_buffer(vec![]);
+        img.di = DynamicImage::ImageRgba8(result);
+        Ok(img)
+    }
+}
+
+#[async_trait]
+impl Process for ResizeProcess {
+    async fn process(&self, pi: ProcessImage) -> Result<ProcessImage> {
+        let process = self.clone();
+        run_blocking(STAGE_PROCESS, Duration::ZERO, move || process.apply(pi)).await?
+    }
+}
+
+/// Gray process changes the image to gray mode.
+#[derive(Default, Clone)]
+pub struct GrayProcess {}
+
+impl GrayProcess {
+    pub fn new() -> Self {
+        GrayProcess {}
+    }
+    fn apply(&self, pi: ProcessImage) -> Result<ProcessImage> {
+        let mut img = pi;
+        img.di = DynamicImage::ImageLuma8(grayscale(&img.di));
+        img.set_buffer(vec![]);
+        Ok(img)
+    }
+}
+
+#[async_trait]
+impl Process for GrayProcess {
+    async fn process(&self, pi: ProcessImage) -> Result<ProcessImage> {
+        let process = self.clone();
+        run_blocking(STAGE_PROCESS, Duration::ZERO, move || process.apply(pi)).await?
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum WatermarkPosition {
+    LeftTop,
+    Top,
+    RightTop,
+    Left,
+    Center,
+    Right,
+    LeftBottom,
+    Bottom,
+    RightBottom,
+}
+
+impl FromStr for WatermarkPosition {
+    type Err = ImageProcessingError;
+    fn from_str(value: &str) -> Result<Self> {
+        let position = match value {
+            "leftTop" => WatermarkPosition::LeftTop,
+            "top" => WatermarkPosition::Top,
+            "rightTop" => WatermarkPosition::RightTop,
+            "left" => WatermarkPosition::Left,
+            "center" => WatermarkPosition::Center,
+            "right" => WatermarkPosition::Right,
+            "leftBottom" => WatermarkPosition::LeftBottom,
+            "bottom" => WatermarkPosition::Bottom,
+            "rightBottom" => WatermarkPosition::RightBottom,
+            _ => {
+                return ParamsInvalidSnafu {
+                    message: format!("watermark position({value}) is not support"),
+                }
+                .fail()
+            }
+        };
+        Ok(position)
+    }
+}
+
+/// Length of pixels or percentage of the image size, e.g. 10 or 10%.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Length {
+    Pixel(i64),
+    Percent(f64),
+}
+
+impl Default for Length {
+    fn default() -> Self {
+        Length::Pixel(0)
+    }
+}
+
+impl From<i64> for Length {
+    fn from(value: i64) -> Self {
+        Length::Pixel(value)
+    }
+}
+
+impl Length {
+    fn to_pixel(self, size: u32) -> i64 {
+        match self {
+            Length::Pixel(value) => value,
+            Length::Percent(value) => (size as f64 * value / 100.0).round() as i64,
+        }
+    }
+}
+
+impl FromStr for Length {
+    type Err = ImageProcessingError;
+    fn from_str(value: &str) -> Result<Self> {
+        if let Some(percent) = value.strip_suffix('%') {
+            let percent = percent.parse::<f64>().ok().filter(|v| v.is_finite());
+            return percent.map(Length::Percent).ok_or_else(|| {
+                ParamsInvalidSnafu {
+                    message: format!("length({value}) is invalid"),
+                }
+                .build()
+            });
+        }
+        Ok(Length::Pixel(
+            value.parse::<i64>().context(ParseIntSnafu {})?,
+        ))
+    }
+}
+
+// 像素值序列化为数字，百分比则为字符串，如"10%"
+impl Serialize for Length {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        match self {
+            Length::Pixel(value) => serializer.serialize_i64(*value),
+            Length::Percent(value) => serializer.serialize_str(&format!("{value}%")),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Length {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Value {
+            Pixel(i64),
+            Text(String),
+        }
+        match Value::deserialize(deserializer)? {
+            Value::Pixel(value) => Ok(Length::Pixel(value)),
+            Value::Text(value) => value.parse().map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+/// Watermark of the watermarks task.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct WatermarkItem {
+    pub url: String,
+    #[serde(default = "default_watermark_position")]
+    pub position: WatermarkPosition,
+    #[serde(default)]
+    pub margin_left: Length,
+    #[serde(default)]
+    pub margin_top: Length,
+}
+
+fn default_watermark_position() -> WatermarkPosition {
+    WatermarkPosition::RightBottom
+}
+
+/// Watermark process adds a watermark over the image.
+#[derive(Clone)]
+pub struct WatermarkProcess {
+    // 在阻塞线程中处理时无需复制水印图片
+    watermark: Arc<DynamicImage>,
+    position: WatermarkPosition,
+    margin_left: Length,
+    margin_top: Length,
+}
+
+impl WatermarkProcess {
+    pub fn new(
+        watermark: DynamicImage,
+        position: WatermarkPosition,
+        margin_left: Length,
+        margin_top: Length,
+    ) -> Self {
+        WatermarkProcess {
+            watermark: Arc::new(watermark),
+            position,
+            margin_left,
+            margin_top,
+        }
+    }
+    // 水印左上角在图片中的位置
+    fn get_position(&self, width: u32, height: u32) -> (i64, i64) {
+        let w = width as i64;
+        let h = height as i64;
+        let ww = self.watermark.width() as i64;
+        let wh = self.watermark.height() as i64;
+        let mut x: i64 = 0;
+        let mut y: i64 = 0;
+        match self.position {
+            WatermarkPosition::Top => {
+                x = (w - ww) >> 1;
+            }
+            WatermarkPosition::RightTop => {
+                x = w - ww;
+            }
+            WatermarkPosition::Left => {
+                y = (h - wh) >> 1;
+            }
+            WatermarkPosition::Center => {
+                x = (w - ww) >> 1;
+                y = (h - wh) >> 1;
+            }
+            WatermarkPosition::Right => {
+                x = w - ww;
+                y = (h - wh) >> 1;
+            }
+            WatermarkPosition::LeftBottom => {
+                y = h - wh;
+            }
+            WatermarkPosition::Bottom => {
+                x = (w - ww) >> 1;
+                y = h - wh;
+            }
+            WatermarkPosition::RightBottom => {
+                x = w - ww;
+                y = h - wh;
+            }
+            _ => (),
+        }
+        (
+            x + self.margin_left.to_pixel(width),
+            y + self.margin_top.to_pixel(height),
+        )
+    }
+    fn apply(&self, pi: ProcessImage) -> Result<ProcessImage> {
+        let mut img = pi;
+        let di = std::mem::take(&mut img.di);
+        let (x, y) = self.get_position(di.width(), di.height());
+        let mut bottom: DynamicImage = di;
+        overlay(&mut bottom, self.watermark.as_ref(), x, y);
+        img.set_buffer(vec![]);
+        img.di = bottom;
+        Ok(img)
+    }
+}
+
+#[async_trait]
+impl Process for WatermarkProcess {
+    async fn process(&self, pi: ProcessImage) -> Result<ProcessImage> {
+        let process = self.clone();
+        run_blocking(STAGE_PROCESS, Duration::ZERO, move || process.apply(pi)).await?
+    }
+}
+
+/// Blend mode of composite.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BlendMode {
+    #[default]
+    Normal,
+    Multiply,
+    Screen,
+    Overlay,
+}
+
+impl FromStr for BlendMode {
+    type Err = ImageProcessingError;
+    fn from_str(value: &str) -> Result<Self> {
+        let mode = match value {
+            "" | "normal" => BlendMode::Normal,
+            "multiply" => BlendMode::Multiply,
+            "screen" => BlendMode::Screen,
+            "overlay" => BlendMode::Overlay,
+            _ => {
+                return ParamsInvalidSnafu {
+                    message: format!("blend mode({value}) is not support"),
+                }
+                .fail()
+            }
+        };
+        Ok(mode)
+    }
+}
+
+impl BlendMode {
+    // 颜色值为0-1
+    fn blend(&self, base: f32, src: f32) -> f32 {
+        match self {
+            BlendMode::Normal => src,
+            BlendMode::Multiply => base * src,
+            BlendMode::Screen => 1.0 - (1.0 - base) * (1.0 - src),
+            BlendMode::Overlay => {
+                if base < 0.5 {
+                    2.0 * base * src
+                } else {
+                    1.0 - 2.0 * (1.0 - base) * (1.0 - src)
+                }
+            }
+        }
+    }
+}
+
+/// Composite process overlays an image at the exact position
+/// with blend mode and opacity.
+#[derive(Clone)]
+pub struct CompositeProcess {
+    layer: Arc<DynamicImage>,
+    x: i64,
+    y: i64,
+    blend: BlendMode,
+    opacity: u8,
+}
+
+impl CompositeProcess {
+    pub fn new(layer: DynamicImage, x: i64, y: i64) -> Self {
+        CompositeProcess {
+            layer: Arc::new(layer),
+            x,
+            y,
+            blend: BlendMode::Normal,
+            opacity: 100,
+        }
+    }
+    /// Set the blend mode, default is normal.
+    pub fn with_blend(mut self, blend: BlendMode) -> Self {
+        self.blend = blend;
+        self
+    }
+    /// Set the opacity(0-100) of layer, default is 100.
+    pub fn with_opacity(mut self, opacity: u8) -> Self {
+        self.opacity = opacity.min(100);
+        self
+    }
+    fn apply(&self, pi: ProcessImage) -> Result<ProcessImage> {
+        let mut img = pi;
+        let mut bottom = std::mem::take(&mut img.di).to_rgba8();
+        let layer = self.layer.to_rgba8();
+        let opacity = self.opacity as f32 / 100.0;
+        for (lx, ly, pixel) in layer.enumerate_pixels() {
+            let x = self.x + lx as i64;
+            let y = self.y + ly as i64;
+            // 超出范围的部分忽略
+            if x < 0 || y < 0 || x >= bottom.width() as i64 || y >= bottom.height() as i64 {
+                continue;
+            }
+            let src_alpha = pixel[3] as f32 / 255.0 * opacity;
+            if src_alpha == 0.0 {
+                continue;
+            }
+            let base = bottom.get_pixel_mut(x as u32, y as u32);
+            let base_alpha = base[3] as f32 / 255.0;
+            let alpha = src_alpha + base_alpha * (1.0 - src_alpha);
+            for i in 0..3 {
+                let b = base[i] as f32 / 255.0;
+                let s = pixel[i] as f32 / 255.0;
+                // 底图透明部分直接使用叠加图片的颜色
+                let mixed = (1.0 - base_alpha) * s + base_alpha * self.blend.blend(b, s);
+                let value = (mixed * src_alpha + b * base_alpha * (1.0 - src_alpha)) / alpha;
+                base[i] = (value * 255.0).round().clamp(0.0, 255.0) as u8;
+            }
+            base[3] = (alpha * 255.0).round() as u8;
+        }
+        img.set_buffer(vec![]);
+        img.di = DynamicImage::ImageRgba8(bottom);
+        Ok(img)
+    }
+}
+
+#[async_trait]
+impl Process for CompositeProcess {
+    async fn process(&self, pi: ProcessImage) -> Result<ProcessImage> {
+        let process = self.clone();
+        run_blocking(STAGE_PROCESS, Duration::ZERO, move || process.apply(pi)).await?
+    }
+}
+
+/// Position of the image in the sprite sheet.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct SpritePosition {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Composite the images into a sprite sheet with transparent background,
+/// each column is as wide as its widest image and each row as high as its highest image.
+pub async fn new_sprite(
+    images: Vec<ProcessImage>,
+    columns: usize,
+    padding: u32,
+) -> Result<(ProcessImage, Vec<SpritePosition>)> {
+    run_blocking(STAGE_PROCESS, Duration::ZERO, move || {
+        let images: Vec<_> = images.iter().map(|img| &img.di).collect();
+        build_sprite(&images, columns, padding)
+    })
+    .await?
+}
+
+fn build_sprite(
+    images: &[&DynamicImage],
+    columns: usize,
+    padding: u32,
+) -> Result<(ProcessImage, Vec<SpritePosition>)> {
+    let columns = columns.clamp(1, images.len().max(1));
+    let rows = images.len().div_ceil(columns);
+    let mut widths = vec![0; columns];
+    let mut heights = vec![0; rows];
+    for (index, image) in images.iter().enumerate() {
+        let (column, row) = (index % columns, index / columns);
+        widths[column] = widths[column].max(image.width());
+        heights[row] = heights[row].max(image.height());
+    }
+    let width = widths.iter().sum::<u32>() + padding * (columns as u32 + 1);
+    let height = heights.iter().sum::<u32>() + padding * (rows as u32 + 1);
+    let max = config::get_max_dimension();
+    ensure!(
+        width <= max && height <= max,
+        ParamsInvalidSnafu {
+            message: format!("sprite size({width}x{height}) should not be larger than {max}"),
+        }
+    );
+    let mut sheet = RgbaImage::new(width, height);
+    let mut positions = Vec::with_capacity(images.len());
+    for (index, image) in images.iter().enumerate() {
+        let (column, row) = (index % columns, index / columns);
+        let x = widths[..column].iter().sum::<u32>() + padding * (column as u32 + 1);
+        let y = heights[..row].iter().sum::<u32>() + padding * (row as u32 + 1);
+        overlay(&mut sheet, &image.to_rgba8(), x as i64, y as i64);
+        positions.push(SpritePosition {
+            x,
+            y,
+            width: image.width(),
+            height: image.height(),
+        });
+    }
+    let mut img = ProcessImage {
+        di: DynamicImage::ImageRgba8(sheet),
+        diff: -1.0,
+        ext: IMAGE_TYPE_PNG.to_string(),
+        source_ext: IMAGE_TYPE_PNG.to_string(),
+        ..Default::default()
+    };
+    img.source_pixels = width as u64 * height as u64;
+    img.update_peak_memory();
+    Ok((img, positions))
+}
+
+/// Anchor of crop, the x and y are ignored if it is set.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CropGravity {
+    Center,
+    Face,
+}
+
+impl FromStr for CropGravity {
+    type Err = ImageProcessingError;
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "center" => Ok(CropGravity::Center),
+            "face" => Ok(CropGravity::Face),
+            _ => ParamsInvalidSnafu {
+                message: format!("crop gravity({value}) is not support"),
+            }
+            .fail(),
+        }
+    }
+}
+
+// 以指定中心点计算裁剪的起始位置，不超出图片范围
+fn get_crop_start(center: u32, size: u32, max: u32) -> u32 {
+    center
+        .saturating_sub(size / 2)
+        .min(max.saturating_sub(size))
+}
+
+// 最大人脸的中心点，未检测到人脸则为图片中心
+#[cfg(feature = "face-detection")]
+fn get_face_center(di: &DynamicImage) -> Result<(u32, u32)> {
+    let model = config::get_face_model();
+    let face = crate::image_analysis::detect_largest_face(di, &model).context(IoSnafu)?;
+    let center = face
+        .map(|face| (face.x + face.width / 2, face.y + face.height / 2))
+        .unwrap_or((di.width() / 2, di.height() / 2));
+    Ok(center)
+}
+
+#[cfg(not(feature = "face-detection"))]
+fn get_face_center(_di: &DynamicImage) -> Result<(u32, u32)> {
+    ParamsInvalidSnafu {
+        message: "face detection is not enabled",
+    }
+    .fail()
+}
+
+/// Crop process crops the image.
+#[derive(Clone)]
+pub struct CropProcess {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+    gravity: Option<CropGravity>,
+}
+
+impl CropProcess {
+    pub fn new(x: u32, y: u32, width: u32, height: u32) -> Self {
+        Self {
+            x,
+            y,
+            width,
+            height,
+            gravity: None,
+        }
+    }
+    /// Set the anchor of crop.
+    pub fn with_gravity(mut self, gravity: Option<CropGravity>) -> Self {
+        self.gravity = gravity;
+        self
+    }
+    // 裁剪的起始位置
+    fn get_start(&self, di: &DynamicImage) -> Result<(u32, u32)> {
+        let Some(gravity) = self.gravity else {
+            return Ok((self.x, self.y));
+        };
+        let center = match gravity {
+            CropGravity::Center => (di.width() / 2, di.height() / 2),
+            CropGravity::Face => get_face_center(di)?,
+        };
+        Ok((
+            get_crop_start(center.0, self.width, di.width()),
+            get_crop_start(center.1, self.height, di.height()),
+        ))
+    }
+    fn apply(&self, pi: ProcessImage) -> Result<ProcessImage> {
+        let mut img = pi;
+        let (x, y) = self.get_start(&img.di)?;
+        let mut r = std::mem::take(&mut img.di);
+        let result = crop(&mut r, x, y, self.width, self.height);
+        img.di = DynamicImage::ImageRgba8(result.to_image());
+        img.set_buffer(vec![]);
+        Ok(img)
+    }
+}
+
+#[async_trait]
+impl Process for CropProcess {
+    async fn process(&self, pi: ProcessImage) -> Result<ProcessImage> {
+        // 人脸检测与裁剪均为耗时的像素处理
+        let process = self.clone();
+        run_blocking(STAGE_PROCESS, Duration::ZERO, move || process.apply(pi)).await?
+    }
+}
+
+/// Named region of crop.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CropRegion {
+    TopHalf,
+    BottomHalf,
+    LeftHalf,
+    RightHalf,
+    /// The largest square in the center.
+    CenterSquare,
+}
+
+impl FromStr for CropRegion {
+    type Err = ImageProcessingError;
+    fn from_str(value: &str) -> Result<Self> {
+        let region = match value {
+            "top_half" => CropRegion::TopHalf,
+            "bottom_half" => CropRegion::BottomHalf,
+            "left_half" => CropRegion::LeftHalf,
+            "right_half" => CropRegion::RightHalf,
+            "center_square" => CropRegion::CenterSquare,
+            _ => {
+                return ParamsInvalidSnafu {
+                    message: format!("crop region({value}) is not support"),
+                }
+                .fail()
+            }
+        };
+        Ok(region)
+    }
+}
+
+struct CropRect {
+    x: Length,
+    y: Length,
+    width: Length,
+    height: Length,
+}
+
+impl CropRect {
+    fn resolve(self, region: Option<CropRegion>, di: &DynamicImage) -> [i64; 4] {
+        self.resolve_size(region, di.width(), di.height())
+    }
+    // 转换为像素，命名区域则根据图片尺寸计算
+    fn resolve_size(self, region: Option<CropRegion>, width: u32, height: u32) -> [i64; 4] {
+        let (w, h) = (width as i64, height as i64);
+        match region {
+            Some(CropRegion::TopHalf) => [0, 0, w, h / 2],
+            Some(CropRegion::BottomHalf) => [0, h - h / 2, w, h / 2],
+            Some(CropRegion::LeftHalf) => [0, 0, w / 2, h],
+            Some(CropRegion::RightHalf) => [w - w / 2, 0, w / 2, h],
+            Some(CropRegion::CenterSquare) => {
+                let size = w.min(h);
+                [(w - size) / 2, (h - size) / 2, size, size]
+            }
+            None => [
+                self.x.to_pixel(width),
+                self.y.to_pixel(height),
+                self.width.to_pixel(width),
+                self.height.to_pixel(height),
+            ],
+        }
+    }
+}
+
+/// Fill of the extended canvas.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExtendFill {
+    /// Rgba color.
+    Color([u8; 4]),
+    /// Blurred image stretched to the canvas.
+    Blur,
+}
+
+impl Default for ExtendFill {
+    fn default() -> Self {
+        ExtendFill::Color([255, 255, 255, 255])
+    }
+}
+
+impl FromStr for ExtendFill {
+    type Err = ImageProcessingError;
+    fn from_str(value: &str) -> Result<Self> {
+        if value == "blur" {
+            return Ok(ExtendFill::Blur);
+        }
+        let color = parse_hex_color(value).ok_or_else(|| {
+            ParamsInvalidSnafu {
+                message: format!("extend fill({value}) is invalid"),
+            }
+            .build()
+        })?;
+        Ok(ExtendFill::Color(color))
+    }
+}
+
+// 颜色格式为rrggbb或rrggbbaa，可带#前缀
+pub(crate) fn parse_hex_color(value: &str) -> Option<[u8; 4]> {
+    let value = value.trim_start_matches('#');
+    if value.len() != 6 && value.len() != 8 {
+        return None;
+    }
+    let mut color = [255; 4];
+    for (i, item) in color.iter_mut().enumerate().take(value.len() / 2) {
+        *item = value
+            .get(i * 2..i * 2 + 2)
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())?;
+    }
+    Some(color)
+}
+
+/// Extend process extends the canvas with border of each side.
+#[derive(Clone)]
+pub struct ExtendProcess {
+    top: u32,
+    right: u32,
+    bottom: u32,
+    left: u32,
+    fill: ExtendFill,
+}
+
+impl ExtendProcess {
+    pub fn new(top: u32, right: u32, bottom: u32, left: u32) -> Self {
+        Self {
+            top,
+            right,
+            bottom,
+            left,
+            fill: ExtendFill::default(),
+        }
+    }
+    /// Set the fill of the extended canvas, default is white.
+    pub fn with_fill(mut self, fill: ExtendFill) -> Self {
+        self.fill = fill;
+        self
+    }
+    fn apply(&self, pi: ProcessImage) -> Result<ProcessImage> {
+        let mut img = pi;
+        let di = std::mem::take(&mut img.di);
+        let width = di.width() + self.left + self.right;
+        let height = di.height() + self.top + self.bottom;
+        let mut canvas = match self.fill {
+            ExtendFill::Color(color) => RgbaImage::from_pixel(width, height, Rgba(color)),
+            ExtendFill::Blur => {
+                // 缩小后再模糊，减少处理时间
+                let small = resize(&di, width / 4 + 1, height / 4 + 1, FilterType::Triangle);
+                let small = blur(&small, 5.0);
+                resize(&small, width, height, FilterType::Triangle)
+            }
+        };
+        overlay(&mut canvas, &di, self.left as i64, self.top as i64);
+        img.di = DynamicImage::ImageRgba8(canvas);
+        img.set_buffer(vec![]);
+        Ok(img)
+    }
+}
+
+#[async_trait]
+impl Process for ExtendProcess {
+    async fn process(&self, pi: ProcessImage) -> Result<ProcessImage> {
+        let process = self.clone();
+        run_blocking(STAGE_PROCESS, Duration::ZERO, move || process.apply(pi)).await?
+    }
+}
+
+/// Nine patch process scales the image to the size with the corners unchanged,
+/// the edges are stretched along one axis and the center is stretched to fill.
+#[derive(Clone)]
+pub struct NinePatchProcess {
+    width: u32,
+    height: u32,
+    top: u32,
+    right: u32,
+    bottom: u32,
+    left: u32,
+}
+
+impl NinePatchProcess {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            top: 0,
+            right: 0,
+            bottom: 0,
+            left: 0,
+        }
+    }
+    /// Set the insets of the fixed border, default is 0(same as resize).
+    pub fn with_insets(mut self, top: u32, right: u32, bottom: u32, left: u32) -> Self {
+        self.top = top;
+        self.right = right;
+        self.bottom = bottom;
+        self.left = left;
+        self
+    }
+    fn apply(&self, pi: ProcessImage) -> Result<ProcessImage> {
+        let mut img = pi;
+        let src = img.di.to_rgba8();
+        let (width, height) = src.dimensions();
+        // 各区域的(原图起始位置, 原图尺寸, 目标尺寸)
+        let slices = |size: u32, target: u32, start: u32, end: u32| {
+            let start = start.min(size);
+            let end = end.min(size - start);
+            [
+                (0, start, start),
+                (
+                    start,
+                    size - start - end,
+                    target.saturating_sub(start + end),
+                ),
+                (size - end, end, end),
+            ]
+        };
+        let columns = slices(width, self.width, self.left, self.right);
+        let rows = slices(height, self.height, self.top, self.bottom);
+        let mut canvas = RgbaImage::new(self.width, self.height);
+        let mut dy = 0;
+        for (sy, sh, dh) in rows {
+            let mut dx = 0;
+            for (sx, sw, dw) in columns {
+                if sw != 0 && sh != 0 && dw != 0 && dh != 0 {
+                    let part = crop_imm(&src, sx, sy, sw, sh).to_image();
+                    let part = if sw == dw && sh == dh {
+                        part
+                    } else {
+                        resize(&part, dw, dh, FilterType::Lanczos3)
+                    };
+                    replace(&mut canvas, &part, dx as i64, dy as i64);
+                }
+                dx += dw;
+            }
+            dy += dh;
+        }
+        img.di = DynamicImage::ImageRgba8(canvas);
+        img.set_buffer(vec![]);
+        Ok(img)
+    }
+}
+
+#[async_trait]
+impl Process for NinePatchProcess {
+    async fn process(&self, pi: ProcessImage) -> Result<ProcessImage> {
+        let process = self.clone();
+        run_blocking(STAGE_PROCESS, Duration::ZERO, move || process.apply(pi)).await?
+    }
+}
+
+/// Region of redaction.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct RedactRegion {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl FromStr for RedactRegion {
+    type Err = ImageProcessingError;
+    fn from_str(value: &str) -> Result<Self> {
+        // 格式为x,y,width,height
+        let values = value
+            .split(',')
+            .map(|item| item.trim().parse::<u32>().context(ParseIntSnafu {}))
+            .collect::<Result<Vec<u32>>>()?;
+        ensure!(
+            values.len() == 4,
+            ParamsInvalidSnafu {
+                message: format!("redact region({value}) is invalid"),
+            }
+        );
+        Ok(RedactRegion {
+            x: values[0],
+            y: values[1],
+            width: values[2],
+            height: values[3],
+        })
+    }
+}
+
+/// Mode of redaction.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RedactMode {
+    #[default]
+    Pixelate,
+    Black,
+}
+
+impl FromStr for RedactMode {
+    type Err = ImageProcessingError;
+    fn from_str(value: &str) -> Result<Self> {
+        let mode = match value {
+            "" | "pixelate" => RedactMode::Pixelate,
+            "black" => RedactMode::Black,
+            _ => {
+                return ParamsInvalidSnafu {
+                    message: format!("redact mode({value}) is not support"),
+                }
+                .fail()
+            }
+        };
+        Ok(mode)
+    }
+}
+
+/// Redact process pixelates or blacks out the regions.
+#[derive(Clone)]
+pub struct RedactProcess {
+    regions: Vec<RedactRegion>,
+    mode: RedactMode,
+}
+
+impl RedactProcess {
+    pub fn new(regions: Vec<RedactRegion>) -> Self {
+        Self {
+            regions,
+            mode: RedactMode::default(),
+        }
+    }
+    /// Set the mode of redaction, default is pixelate.
+    pub fn with_mode(mut self, mode: RedactMode) -> Self {
+        self.mode = mode;
+        self
+    }
+    fn apply(&self, pi: ProcessImage) -> Result<ProcessImage> {
+        let mut img = pi;
+        let mut di = std::mem::take(&mut img.di).to_rgba8();
+        let (width, height) = di.dimensions();
+        for region in self.regions.iter() {
+            // 超出图片的部分忽略
+            if region.x >= width || region.y >= height {
+                continue;
+            }
+            let w = region.width.min(width - region.x);
+            let h = region.height.min(height - region.y);
+            if w == 0 || h == 0 {
+                continue;
+            }
+            let area = match self.mode {
+                RedactMode::Black => RgbaImage::from_pixel(w, h, Rgba([0, 0, 0, 255])),
+                RedactMode::Pixelate => {
+                    // 马赛克块的大小为区域的1/10(最小8像素)
+                    let size = (w.max(h) / 10).max(8);
+                    let area = crop(&mut di, region.x, region.y, w, h).to_image();
+                    let small = resize(
+                        &area,
+                        w.div_ceil(size),
+                        h.div_ceil(size),
+                        FilterType::Triangle,
+                    );
+                    resize(&small, w, h, FilterType::Nearest)
+                }
+            };
+            // 直接替换，避免透明像素时原图可见
+            replace(&mut di, &area, region.x as i64, region.y as i64);
+        }
+        img.di = DynamicImage::ImageRgba8(di);
+        img.set_buffer(vec![]);
+        Ok(img)
+    }
+}
+
+#[async_trait]
+impl Process for RedactProcess {
+    async fn process(&self, pi: ProcessImage) -> Result<ProcessImage> {
+        let process = self.clone();
+        run_blocking(STAGE_PROCESS, Duration::ZERO, move || process.apply(pi)).await?
+    }
+}
+
+/// Report of a processing stage.
+#[derive(Debug, Clone, Serialize)]
+pub struct StageReport {
+    pub task: &'static str,
+    /// Cost of the stage in milliseconds.
+    pub cost: u64,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Report of the processing for debugging.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ProcessReport {
+    pub stages: Vec<StageReport>,
+    /// Status of the decoded image cache, hit or miss.
+    pub decoded_cache: Option<&'static str>,
+    /// Encoder options of the optim task.
+    pub options: Option<EncoderOptions>,
+    /// Retry count of loading the http source.
+    pub retries: u32,
+    /// The diff is calculated from the downscaled images.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub diff_approximate: bool,
+}
+
+/// Quality and speed actually used by the encoder.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct AppliedEncoding {
+    pub quality: u8,
+    pub speed: u8,
+}
+
+// 根据处理中的avif数量调整，每个级别速度+2，质量-10(不低于50)
+fn get_avif_adaptive(quality: u8, speed: u8) -> AppliedEncoding {
+    // 0为默认速度
+    let speed = if speed == 0 { 3 } else { speed };
+    let count = config::get_avif_adaptive_count();
+    if count == 0 {
+        return AppliedEncoding { quality, speed };
+    }
+    let level = (state::get_avif_encoding() / count).min(5) as u8;
+    let mut result = AppliedEncoding {
+        quality,
+        speed: (speed + level * 2).min(10),
+    };
+    if config::is_avif_adaptive_quality() {
+        result.quality = quality.saturating_sub(level * 10).max(quality.min(50));
+    }
+    result
+}
+
+// 动图转换为视频，使用原始数据
+#[cfg(feature = "video")]
+async fn to_video(data: &[u8], ext: &str, options: &EncoderOptions) -> Result<Vec<u8>> {
+    crate::video::transcode(data, ext, options)
+        .await
+        .map_err(|message| VideoSnafu { message }.build())
+}
+
+#[cfg(not(feature = "video"))]
+async fn to_video(_data: &[u8], _ext: &str, _options: &EncoderOptions) -> Result<Vec<u8>> {
+    ParamsInvalidSnafu {
+        message: "video output is not enabled",
+    }
+    .fail()
+}
+
+/// Optim process optimizes the image of multi format.
+pub struct OptimProcess {
+    output_type: Option<OutputType>,
+    quality: u8,
+    speed: u8,
+    options: EncoderOptions,
+}
+
+impl OptimProcess {
+    pub fn new(output_type: Option<OutputType>, quality: u8, speed: u8) -> Self {
+        Self {
+            output_type,
+            quality,
+            speed,
+            options: EncoderOptions::default(),
+        }
+    }
+    /// Set the advanced options of encoder.
+    pub fn with_options(mut self, options: EncoderOptions) -> Self {
+        self.options = options;
+        self
+    }
+    // 指定了质量预设则根据输出格式获取对应的质量
+    fn get_preset_quality(&self, output_type: &str) -> u8 {
+        let output_type = output_type
+            .parse::<OutputType>()
+            .map(|value| value.as_str())
+            .unwrap_or(output_type);
+        self.options
+            .preset
+            .and_then(|preset| config::get_quality_preset(preset.as_str(), output_type))
+            .unwrap_or(self.quality)
+    }
+    // jpeg重新压缩为jpeg时，质量不高于原图的估算质量(除非指定force)，避免数据变大且增加失真
+    async fn get_quality(&self, img: &ProcessImage, output_type: &str) -> Result<u8> {
+        let quality = self.get_preset_quality(output_type);
+        let is_jpeg = img.ext.parse::<OutputType>().ok() == Some(OutputType::Jpeg);
+        if !is_jpeg
+            || img.buffer_len() == 0
+            || self.options.force.unwrap_or_default()
+            || output_type.parse::<OutputType>().ok() != Some(OutputType::Jpeg)
+        {
+            return Ok(quality);
+        }
+        let data = img.get_original_buffer().await?;
+        Ok(estimate_jpeg_quality(&data)
+            .map(|value| value.min(quality))
+            .unwrap_or(quality))
+    }
+    // 原图未处理且格式不变时，数据较小或质量不高于指定质量的无需再次压缩
+    async fn should_skip(&self, img: &ProcessImage) -> Result<bool> {
+        if img.buffer_len() == 0 {
+            return Ok(false);
+        }
+        let Ok(original_type) = img.ext.parse::<OutputType>() else {
+            return Ok(false);
+        };
+        if self.output_type.is_some_and(|value| value != original_type) {
+            return Ok(false);
+        }
+        let skip_size = config::get_skip_size();
+        if skip_size != 0 && img.buffer_len() <= skip_size {
+            return Ok(true);
+        }
+        if original_type == OutputType::Jpeg && config::is_skip_by_quality() {
+            let data = img.get_original_buffer().await?;
+            if let Some(quality) = estimate_jpeg_quality(&data) {
+                return Ok(self.get_preset_quality(IMAGE_TYPE_JPEG) >= quality);
+            }
+        }
+        Ok(false)
+    }
+}
+
+#[async_trait]
+impl Process for OptimProcess {
+    async fn process(&self, pi: ProcessImage) -> Result<ProcessImage> {
+        let mut img = pi;
+        self.options
+            .validate()
+            .map_err(|message| ParamsInvalidSnafu { message }.build())?;
+        if self.should_skip(&img).await? {
+            img.passthrough = true;
+            return Ok(img);
+        }
+
+        let speed = self.speed;
+        let original_type = img.ext.clone();
+
+        let original_size = img.buffer_len();
+        // 如果未指定输出，则保持原有
+        let mut output_type = self
+            .output_type
+            .map(|value| value.as_str().to_string())
+            .unwrap_or_else(|| original_type.clone());
+        // 像素转换与内容识别均在其它线程中执行
+        let classify = output_type == IMAGE_TYPE_AUTO;
+        let di = std::mem::take(&mut img.di);
+        let (di, info, content) = run_blocking(STAGE_PROCESS, Duration::ZERO, move || {
+            let info: ImageInfo = di.to_rgba8().into();
+            let content = classify.then(|| classify_content(&di));
+            (di, info, content)
+        })
+        .await?;
+        img.di = di;
+        // 根据内容选择格式，图形使用png，照片使用jpeg(有透明则使用webp)
+        if let Some(content) = content {
+            output_type = match content {
+                ContentKind::Graphic => IMAGE_TYPE_PNG,
+                ContentKind::Photo if img.di.color().has_alpha() => IMAGE_TYPE_WEBP,
+                ContentKind::Photo => IMAGE_TYPE_JPEG,
+            }
+            .to_string();
+            img.content = Some(content);
+        }
+        let quality = self.get_quality(&img, &output_type).await?;
+        if quality != self.quality || self.options.preset.is_some() {
+            img.encoding = Some(AppliedEncoding { quality, speed });
+        }
+
+        img.ext.clone_from(&output_type);
+
+        let data = match output_type.as_str() {
+            IMAGE_TYPE_GIF => {
+                let data = img.get_original_buffer().await?;
+                run_blocking(STAGE_ENCODE, config::get_encode_timeout(), move || {
+                    to_gif(Cursor::new(data), 10)
+                })
+                .await?
+                .context(ImagesSnafu {})?
+            }
+            IMAGE_TYPE_MP4 | IMAGE_TYPE_WEBM => {
+                to_video(
+                    &img.get_original_buffer().await?,
+                    &output_type,
+                    &self.options,
+                )
+                .await?
+            }
+            _ => {
+                let applied = (output_type == IMAGE_TYPE_AVIF).then(|| {
+                    let applied = get_avif_adaptive(quality, speed);
+                    img.encoding = Some(applied);
+                    applied
+                });
+                // 其它的全部使用jpeg
+                if ![IMAGE_TYPE_PNG, IMAGE_TYPE_AVIF, IMAGE_TYPE_WEBP]
+                    .contains(&output_type.as_str())
+                {
+                    img.ext = IMAGE_TYPE_JPEG.to_string();
+                }
+                let output_type = img.ext.clone();
+                let options = self.options.clone();
+                let animation = img.animation.clone();
+                let (width, height) = (img.di.width(), img.di.height());
+                let max_frames = config::get_animated_max_frames();
+                run_blocking(STAGE_ENCODE, config::get_encode_timeout(), move || {
+                    match (output_type.as_str(), applied) {
+                        (IMAGE_TYPE_PNG, _) => image_encoder::to_png(&info, quality, &options),
+                        (IMAGE_TYPE_AVIF, Some(applied)) => {
+                            let _guard = state::start_avif_encoding();
+                            image_encoder::to_avif(&info, applied.quality, applied.speed, &options)
+                        }
+                        (IMAGE_TYPE_WEBP, _) => {
+                            // webp动图逐帧缩放后编码，超过帧数限制则仅使用第一帧
+                            let animated = match &animation {
+                                Some(data) => image_encoder::to_animated_webp(
+                                    data, width, height, quality, speed, &options, max_frames,
+                                )?,
+                                None => None,
+                            };
+                            match animated {
+                                Some(data) => Ok(data),
+                                None => image_encoder::to_webp(&info, quality, speed, &options),
+                            }
+                        }
+                        _ => image_encoder::to_mozjpeg(&info, quality, &options),
+                    }
+                })
+                .await?
+                .context(EncodeSnafu {})?
+            }
+        };
+        // 转换格式后数据更大且原始数据未变化，则直接使用原始数据
+        if config::is_passthrough()
+            && img.ext != original_type
+            && img.buffer_len() != 0
+            && data.len() >= original_size
+        {
+            img.ext = original_type;
+            img.passthrough = true;
+            return Ok(img);
+        }
+        // 类型不一样
+        // 或者类型一样但是数据最小
+        // 或者无原始数据
+        if img.ext != original_type || data.len() < original_size || original_size == 0 {
+            img.set_buffer(data);
+            // 支持dssim且需要计算差异值时再根据数据生成image
+            // 否则无此必要
+            if img.support_dssim() && img.original.is_some() {
+                // image 的avif decoder有其它依赖
+                // 暂使用其它模块
+                // decode如果失败则忽略
+                // 因为只用于计算dssim
+                if let Ok(value) = img.decode_buffer().await {
+                    img.di = value;
+                }
+            }
+        }
+
+        Ok(img)
+    }
+}
+
+/// Color effect of the image.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Effect {
+    Grayscale,
+    Sepia,
+    /// Luminance mapped from the shadow color to the highlight color.
+    Duotone {
+        shadow: [u8; 3],
+        highlight: [u8; 3],
+    },
+}
+
+impl FromStr for Effect {
+    type Err = ImageProcessingError;
+    fn from_str(value: &str) -> Result<Self> {
+        let invalid = || {
+            ParamsInvalidSnafu {
+                message: format!("effect({value}) is not support"),
+            }
+            .build()
+        };
+        let effect = match value {
+            "grayscale" => Effect::Grayscale,
+            "sepia" => Effect::Sepia,
+            _ => {
+                // 双色调格式为duotone:暗部颜色,亮部颜色
+                let colors = value.strip_prefix("duotone:").ok_or_else(invalid)?;
+                let (shadow, highlight) = colors.split_once(',').ok_or_else(invalid)?;
+                let shadow = parse_hex_color(shadow).ok_or_else(invalid)?;
+                let highlight = parse_hex_color(highlight).ok_or_else(invalid)?;
+                Effect::Duotone {
+                    shadow: [shadow[0], shadow[1], shadow[2]],
+                    highlight: [highlight[0], highlight[1], highlight[2]],
+                }
+            }
+        };
+        Ok(effect)
+    }
+}
+
+/// Effect process maps the colors of image, the alpha is kept.
+#[derive(Clone)]
+pub struct EffectProcess {
+    effect: Effect,
+}
+
+impl EffectProcess {
+    pub fn new(effect: Effect) -> Self {
+        Self { effect }
+    }
+    fn apply(&self, pi: ProcessImage) -> Result<ProcessImage> {
+        let mut img = pi;
+        let mut di = std::mem::take(&mut img.di).to_rgba8();
+        for pixel in di.pixels_mut() {
+            let [r, g, b, a] = pixel.0.map(|v| v as f32);
+            let luma = 0.299 * r + 0.587 * g + 0.114 * b;
+            let rgb = match self.effect {
+                Effect::Grayscale => [luma; 3],
+                Effect::Sepia => [
+                    0.393 * r + 0.769 * g + 0.189 * b,
+                    0.349 * r + 0.686 * g + 0.168 * b,
+                    0.272 * r + 0.534 * g + 0.131 * b,
+                ],
+                Effect::Duotone { shadow, highlight } => {
+                    let t = luma / 255.0;
+                    [0, 1, 2].map(|i| shadow[i] as f32 * (1.0 - t) + highlight[i] as f32 * t)
+                }
+            };
+            let [r, g, b] = rgb.map(|v| v.round().clamp(0.0, 255.0) as u8);
+            pixel.0 = [r, g, b, a as u8];
+        }
+        img.di = DynamicImage::ImageRgba8(di);
+        img.set_buffer(vec![]);
+        Ok(img)
+    }
+}
+
+#[async_trait]
+impl Process for EffectProcess {
+    async fn process(&self, pi: ProcessImage) -> Result<ProcessImage> {
+        let process = self.clone();
+        run_blocking(STAGE_PROCESS, Duration::ZERO, move || process.apply(pi)).await?
+    }
+}
+
+/// Enhance process stretches the histogram(auto levels) and corrects the gamma.
+#[derive(Clone)]
+pub struct EnhanceProcess {
+    levels: bool,
+    gamma: Option<f32>,
+}
+
+impl EnhanceProcess {
+    pub fn new(levels: bool) -> Self {
+        Self {
+            levels,
+            gamma: None,
+        }
+    }
+    /// Set the gamma, larger than 1 brightens the image.
+    pub fn with_gamma(mut self, gamma: Option<f32>) -> Self {
+        self.gamma = gamma;
+        self
+    }
+}
+
+// 根据亮度直方图获取色阶的范围，两端各忽略0.5%的像素
+fn get_levels(di: &RgbaImage) -> (f32, f32) {
+    let mut histogram = [0u64; 256];
+    let mut total = 0;
+    for pixel in di.pixels() {
+        // 忽略透明像素
+        if pixel[3] == 0 {
+            continue;
+        }
+        let luma = 0.299 * pixel[0] as f32 + 0.587 * pixel[1] as f32 + 0.114 * pixel[2] as f32;
+        histogram[luma.round() as usize] += 1;
+        total += 1;
+    }
+    let clip = total / 200;
+    let find = |iter: Vec<usize>| {
+        let mut count = 0;
+        iter.into_iter().find(|i| {
+            count += histogram[*i];
+            count > clip
+        })
+    };
+    let low = find((0..256).collect()).unwrap_or(0);
+    let high = find((0..256).rev().collect()).unwrap_or(255);
+    if high <= low {
+        return (0.0, 255.0);
+    }
+    (low as f32, high as f32)
+}
+
+impl EnhanceProcess {
+    fn apply(&self, pi: ProcessImage) -> Result<ProcessImage> {
+        if !self.levels && self.gamma.is_none() {
+            return Ok(pi);
+        }
+        let mut img = pi;
+        let mut di = std::mem::take(&mut img.di).to_rgba8();
+        let (low, high) = if self.levels {
+            get_levels(&di)
+        } else {
+            (0.0, 255.0)
+        };
+        let gamma = self.gamma.unwrap_or(1.0).clamp(0.1, 10.0);
+        // 色阶与gamma合并为查找表
+        let mut table = [0u8; 256];
+        for (i, item) in table.iter_mut().enumerate() {
+            let value = ((i as f32 - low) / (high - low)).clamp(0.0, 1.0);
+            *item = (value.powf(1.0 / gamma) * 255.0).round() as u8;
+        }
+        for pixel in di.pixels_mut() {
+            for channel in pixel.0.iter_mut().take(3) {
+                *channel = table[*channel as usize];
+            }
+        }
+        img.di = DynamicImage::ImageRgba8(di);
+        img.set_buffer(vec![]);
+        Ok(img)
+    }
+}
+
+#[async_trait]
+impl Process for EnhanceProcess {
+    async fn process(&self, pi: ProcessImage) -> Result<ProcessImage> {
+        let process = self.clone();
+        run_blocking(STAGE_PROCESS, Duration::ZERO, move || process.apply(pi)).await?
+    }
+}
@@ -1,18 +1,143 @@
+use crate::config;
+use crate::error::{HTTPError, ERROR_CODE_HEADER};
+use crate::image_processing::AppliedEncoding;
 use axum::body::Body;
-use axum::http::{header, HeaderValue};
+use axum::http::{header, HeaderValue, StatusCode};
 use axum::response::{IntoResponse, Response};
+use image::{ImageFormat, Rgba, RgbaImage};
+use once_cell::sync::Lazy;
+use sha2::{Digest, Sha256};
+use std::io::Cursor;
 
 pub struct ImagePreview {
     pub diff: f64,
     pub ratio: usize,
     pub data: Vec<u8>,
     pub image_type: String,
+    pub passthrough: bool,
+    pub encoding: Option<AppliedEncoding>,
+    /// The `Range` header of request.
+    pub range: Option<String>,
+    /// The `If-Range` header of request.
+    pub if_range: Option<String>,
+    /// The `Cache-Control` header of response.
+    pub cache_control: String,
+    /// Content kind classified for the auto output type.
+    pub content: Option<&'static str>,
+}
+
+/// Default cache control of image.
+pub fn get_default_cache_control() -> String {
+    format!("public, max-age={}", config::get_cache_max_age())
+}
+
+/// Default cache control of the proxied image.
+pub fn get_proxy_cache_control() -> String {
+    format!("public, max-age={}", config::get_proxy_cache_max_age())
+}
+
+/// Parse the cache control of request, only max-age, s-maxage,
+/// immutable and no-transform are supported,
+/// and the ages are limited by the config.
+pub fn parse_cache_control(value: &str) -> Result<String, String> {
+    let (min, max) = config::get_cache_max_age_range();
+    let mut directives = vec!["public".to_string()];
+    let mut has_max_age = false;
+    for item in value.split(',').map(|item| item.trim()) {
+        if item.is_empty() {
+            continue;
+        }
+        let (key, value) = item.split_once('=').unwrap_or((item, ""));
+        match key {
+            "max-age" | "s-maxage" => {
+                let age = value
+                    .parse::<u64>()
+                    .map_err(|_| format!("{key}({value}) is invalid"))?;
+                has_max_age = has_max_age || key == "max-age";
+                directives.push(format!("{key}={}", age.clamp(min, max)));
+            }
+            "immutable" | "no-transform" => directives.push(key.to_string()),
+            _ => return Err(format!("cache control({key}) is not support")),
+        }
+    }
+    if !has_max_age {
+        directives.insert(1, format!("max-age={}", config::get_cache_max_age()));
+    }
+    Ok(directives.join(", "))
+}
+
+/// Parse the single byte range of request,
+/// returns none if the range should be ignored,
+/// and error if the range is not satisfiable.
+fn parse_range(value: &str, size: usize) -> Option<Result<(usize, usize), ()>> {
+    let value = value.trim().strip_prefix("bytes=")?;
+    // 多个范围时忽略，直接返回完整数据
+    if value.contains(',') {
+        return None;
+    }
+    let (start, end) = value.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+    let range = if start.is_empty() {
+        // 最后的n个字节
+        let count = end.parse::<usize>().ok()?;
+        if count == 0 {
+            return Some(Err(()));
+        }
+        (size.saturating_sub(count), size.saturating_sub(1))
+    } else {
+        let start = start.parse::<usize>().ok()?;
+        let end = if end.is_empty() {
+            size.saturating_sub(1)
+        } else {
+            let end = end.parse::<usize>().ok()?;
+            if end < start {
+                return None;
+            }
+            end.min(size.saturating_sub(1))
+        };
+        (start, end)
+    };
+    if range.0 >= size {
+        return Some(Err(()));
+    }
+    Some(Ok(range))
 }
 
 // 图片预览转换为response
 impl IntoResponse for ImagePreview {
     fn into_response(self) -> Response {
-        let mut res = Body::from(self.data).into_response();
+        let size = self.data.len();
+        // 使用数据的hash作为etag，用于If-Range的判断
+        let etag = format!("\"{}\"", &format!("{:x}", Sha256::digest(&self.data))[..16]);
+        // If-Range不一致(或为时间)则返回完整数据
+        let range = self
+            .range
+            .filter(|_| self.if_range.is_none() || self.if_range.as_deref() == Some(&etag))
+            .and_then(|value| parse_range(&value, size));
+
+        let mut res = match range {
+            Some(Ok((start, end))) => {
+                let mut res = Body::from(self.data[start..=end].to_vec()).into_response();
+                *res.status_mut() = StatusCode::PARTIAL_CONTENT;
+                if let Ok(value) = HeaderValue::from_str(&format!("bytes {start}-{end}/{size}")) {
+                    res.headers_mut().insert(header::CONTENT_RANGE, value);
+                }
+                res
+            }
+            Some(Err(())) => {
+                let mut res = StatusCode::RANGE_NOT_SATISFIABLE.into_response();
+                if let Ok(value) = HeaderValue::from_str(&format!("bytes */{size}")) {
+                    res.headers_mut().insert(header::CONTENT_RANGE, value);
+                }
+                return res;
+            }
+            None => Body::from(self.data).into_response(),
+        };
+        res.headers_mut()
+            .insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+        if let Ok(value) = HeaderValue::from_str(&etag) {
+            res.headers_mut().insert(header::ETAG, value);
+        }
 
         // 设置content type
         let result = mime_guess::from_ext(self.image_type.as_str()).first_or(mime::IMAGE_JPEG);
@@ -20,17 +145,92 @@ impl IntoResponse for ImagePreview {
             res.headers_mut().insert(header::CONTENT_TYPE, value);
         }
 
-        // 图片设置为缓存30天
-        res.headers_mut().insert(
-            header::CACHE_CONTROL,
-            HeaderValue::from_static("public, max-age=2592000"),
-        );
+        // 缓存控制，默认缓存30天
+        if let Ok(value) = HeaderValue::from_str(&self.cache_control) {
+            res.headers_mut().insert(header::CACHE_CONTROL, value);
+        }
         if let Ok(value) = HeaderValue::from_str(&format!("{:.2}", self.diff)) {
             res.headers_mut().insert("X-Dssim-Diff", value);
         }
         if let Ok(value) = HeaderValue::from_str(self.ratio.to_string().as_str()) {
             res.headers_mut().insert("X-Ratio", value);
         }
+        // 编码时实际使用的质量与速度
+        if let Some(encoding) = self.encoding {
+            res.headers_mut().insert(
+                "X-Optim-Quality",
+                HeaderValue::from(encoding.quality as u16),
+            );
+            res.headers_mut()
+                .insert("X-Optim-Speed", HeaderValue::from(encoding.speed as u16));
+        }
+        // 自动选择格式时的内容分类
+        if let Some(content) = self.content {
+            res.headers_mut()
+                .insert("X-Optim-Content", HeaderValue::from_static(content));
+        }
+        if self.passthrough {
+            res.headers_mut()
+                .insert("X-Passthrough", HeaderValue::from_static("1"));
+        }
+
+        res
+    }
+}
+
+// 1x1的透明png
+fn get_transparent_png() -> Vec<u8> {
+    let mut data = vec![];
+    let img = RgbaImage::from_pixel(1, 1, Rgba([0, 0, 0, 0]));
+    if let Err(e) = img.write_to(&mut Cursor::new(&mut data), ImageFormat::Png) {
+        tracing::error!("Create transparent png fail, {e}");
+    }
+    data
+}
+
+// 出错时返回的图片数据与类型，未配置或读取失败则使用透明png
+static FALLBACK_IMAGE: Lazy<(Vec<u8>, String)> = Lazy::new(|| {
+    let file = config::get_fallback_image();
+    if !file.is_empty() {
+        match std::fs::read(&file) {
+            Ok(data) => {
+                let ext = file.split('.').next_back().unwrap_or_default().to_string();
+                return (data, ext);
+            }
+            Err(e) => tracing::error!(file, "Read fallback image fail, {e}"),
+        }
+    }
+    (get_transparent_png(), "png".to_string())
+});
+
+/// Fallback image is responded with 200 instead of the processing error,
+/// the error message is set to the `X-Optim-Error` header.
+pub struct FallbackImage {
+    pub error: HTTPError,
+}
+
+impl IntoResponse for FallbackImage {
+    fn into_response(self) -> Response {
+        let (data, ext) = &*FALLBACK_IMAGE;
+        let mut res = Body::from(data.clone()).into_response();
+
+        let result = mime_guess::from_ext(ext).first_or(mime::IMAGE_PNG);
+        if let Ok(value) = HeaderValue::from_str(result.as_ref()) {
+            res.headers_mut().insert(header::CONTENT_TYPE, value);
+        }
+        // 出错的图片不缓存
+        res.headers_mut()
+            .insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+        // 出错信息可能包含非法字符，则使用分类
+        let value = HeaderValue::from_str(&self.error.message)
+            .or_else(|_| HeaderValue::from_str(&self.error.category));
+        if let Ok(value) = value {
+            res.headers_mut().insert("X-Optim-Error", value);
+        }
+        res.headers_mut().insert(
+            ERROR_CODE_HEADER,
+            HeaderValue::from_static(self.error.code.as_str()),
+        );
 
         res
     }
@@ -0,0 +1,29 @@
+//! Image processing of image-optim, which can be embedded into other services
+//! without the http server.
+//!
+//! ```no_run
+//! use image_optim::image_processing::{OutputType, PipelineBuilder};
+//!
+//! # async fn run() -> Result<(), image_optim::image_processing::ImageProcessingError> {
+//! let img = PipelineBuilder::load("file:///images/photo.jpg", "")
+//!     .resize(800, 0)
+//!     .optim(Some(OutputType::Webp), 80, 3)
+//!     .run()
+//!     .await?;
+//! let data = img.get_buffer().await?;
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod barcode;
+pub mod card;
+pub mod config;
+mod encoder_pool;
+mod http_client;
+pub mod image_analysis;
+pub mod image_encoder;
+pub mod image_processing;
+pub mod moderation;
+pub mod state;
+#[cfg(feature = "video")]
+mod video;
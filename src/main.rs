@@ -1,4 +1,6 @@
 use axum::{error_handling::HandleErrorLayer, middleware::from_fn, routing::get, Router};
+use clap::Parser;
+use error::{HTTPError, HTTPResult};
 use std::time::Duration;
 use std::{env, net::SocketAddr, str::FromStr};
 use tokio::signal;
@@ -6,11 +8,24 @@ use tower::ServiceBuilder;
 use tracing::Level;
 use tracing_subscriber::FmtSubscriber;
 
+use image_optim::{
+    barcode, card, config, image_analysis, image_encoder, image_processing, moderation, state,
+};
+
+mod admin;
+mod api_key;
+mod audit;
+mod cli;
 mod error;
+#[cfg(feature = "grpc")]
+mod grpc;
 mod images;
 mod middleware;
 mod optim;
+mod proxy;
 mod response;
+mod server;
+mod stats;
 mod task_local;
 
 fn init_logger() {
@@ -47,31 +62,107 @@ async fn run() {
     let app = Router::new()
         .route("/ping", get(ping))
         .merge(optim::new_router())
+        .merge(admin::new_router())
         .layer(
             ServiceBuilder::new()
                 .layer(HandleErrorLayer::new(error::handle_error))
-                .timeout(Duration::from_secs(30)),
+                // 需要大于加载、解码与编码的超时之和(差异校验会重新编码)，由各阶段的超时控制
+                .timeout(Duration::from_secs(5 * 60)),
         )
         // 后面的layer先执行
+        .layer(from_fn(middleware::storage))
+        .layer(from_fn(middleware::api_key))
         .layer(from_fn(middleware::access_log))
         .layer(from_fn(middleware::entry));
 
+    // 启动时选择编码器的配置
+    image_encoder::get_encoder_profile();
+
+    // 预先加载水印至缓存
+    tokio::spawn(async {
+        for url in config::get_watermark_preload() {
+            if let Err(e) = image_processing::load_watermark(&url).await {
+                tracing::error!(url, "Preload watermark fail, {e}");
+            }
+        }
+    });
+
+    // grpc服务使用单独的端口
+    #[cfg(feature = "grpc")]
+    tokio::spawn(async {
+        if let Err(e) = grpc::serve(config::get_grpc_port()).await {
+            tracing::error!("Grpc server fail, {e}");
+        }
+    });
+
+    // api key的使用量定时保存
+    let usage_file = config::get_usage_file();
+    if api_key::is_enabled() && !usage_file.is_empty() {
+        if let Err(e) = api_key::load_usages(&usage_file) {
+            tracing::error!(file = usage_file, "Load usages fail, {e}");
+        }
+        let file = usage_file.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(60));
+            loop {
+                interval.tick().await;
+                if let Err(e) = api_key::save_usages(&file) {
+                    tracing::error!(file, "Save usages fail, {e}");
+                }
+            }
+        });
+    }
+
+    // 处理统计定时保存
+    let stats_file = config::get_stats_file();
+    if !stats_file.is_empty() {
+        if let Err(e) = stats::load(&stats_file) {
+            tracing::error!(file = stats_file, "Load stats fail, {e}");
+        }
+        let file = stats_file.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(60));
+            loop {
+                interval.tick().await;
+                if let Err(e) = stats::save(&file) {
+                    tracing::error!(file, "Save stats fail, {e}");
+                }
+            }
+        });
+    }
+
     let port = 3000;
     let addr = SocketAddr::from(([0, 0, 0, 0], port));
-    tracing::info!(port, "Server is starting");
+    let tls = !config::get_tls_cert().is_empty();
+    tracing::info!(port, tls, "Server is starting");
     let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
 
-    axum::serve(
-        listener,
-        app.into_make_service_with_connect_info::<SocketAddr>(),
-    )
-    // .with_graceful_shutdown(shutdown_signal())
-    .await
-    .unwrap();
+    server::serve(listener, app, shutdown_signal())
+        .await
+        .unwrap();
+
+    if api_key::is_enabled() && !usage_file.is_empty() {
+        if let Err(e) = api_key::save_usages(&usage_file) {
+            tracing::error!(file = usage_file, "Save usages fail, {e}");
+        }
+    }
+    if !stats_file.is_empty() {
+        if let Err(e) = stats::save(&stats_file) {
+            tracing::error!(file = stats_file, "Save stats fail, {e}");
+        }
+    }
 }
 
-async fn ping() -> &'static str {
-    "pong"
+async fn ping() -> HTTPResult<&'static str> {
+    // 停止中则返回出错，让负载均衡不再转发请求
+    if state::is_stopping() {
+        return Err(HTTPError::new_with_category_status(
+            "server is stopping",
+            "stopping",
+            503,
+        ));
+    }
+    Ok("pong")
 }
 
 async fn shutdown_signal() {
@@ -95,14 +186,37 @@ async fn shutdown_signal() {
     tokio::select! {
         _ = ctrl_c => {},
         _ = terminate => {},
+        // drain时已等待处理中的任务完成
+        _ = state::drained() => {
+            tracing::info!("drain completed, starting graceful shutdown");
+            return;
+        },
     }
 
     tracing::info!("signal received, starting graceful shutdown");
+    state::set_stopping();
+    let count = state::wait_for_idle(config::get_drain_timeout()).await;
+    if count != 0 {
+        tracing::warn!(processing = count, "Drain timeout exceeded");
+    }
 }
 
 fn main() {
     // Because we need to get the local offset before Tokio spawns any threads, our `main`
     // function cannot use `tokio::main`.
+    let args = cli::Cli::parse();
     init_logger();
-    run();
+    match args.command {
+        Some(cli::Command::Convert(args)) => {
+            if !cli::convert(args) {
+                std::process::exit(1);
+            }
+        }
+        Some(cli::Command::Watch(args)) => {
+            if !cli::watch(args) {
+                std::process::exit(1);
+            }
+        }
+        None => run(),
+    }
 }
@@ -5,8 +5,10 @@ use chrono::Utc;
 use nanoid::nanoid;
 use tracing::info;
 
-use crate::error::HTTPResult;
-use crate::task_local::{clone_value_from_task_local, STARTED_AT, TRACE_ID};
+use crate::api_key;
+use crate::config;
+use crate::error::{HTTPError, HTTPResult};
+use crate::task_local::{clone_value_from_task_local, API_KEY, STARTED_AT, STORAGE, TRACE_ID};
 
 pub async fn entry(req: Request<Body>, next: Next) -> Response {
     // 设置请求处理开始时间
@@ -19,6 +21,97 @@ pub async fn entry(req: Request<Body>, next: Next) -> Response {
         .await
 }
 
+// 管理接口的token，按字节比较且不提前返回
+pub fn is_admin_request<B>(req: &Request<B>) -> bool {
+    let token = config::get_admin_token();
+    let value = req
+        .headers()
+        .get("X-Admin-Token")
+        .map(|value| value.as_bytes())
+        .unwrap_or_default();
+    !token.is_empty()
+        && token.len() == value.len()
+        && token
+            .bytes()
+            .zip(value.iter())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+pub async fn admin(req: Request<Body>, next: Next) -> HTTPResult<Response> {
+    if !is_admin_request(&req) {
+        return Err(HTTPError::new_with_category_status(
+            "admin token is invalid",
+            "admin",
+            403,
+        ));
+    }
+    Ok(next.run(req).await)
+}
+
+pub async fn api_key(req: Request<Body>, next: Next) -> HTTPResult<Response> {
+    let path = req.uri().path();
+    // 未配置api key或ping则不校验，管理接口使用管理token而非api key
+    if !api_key::is_enabled()
+        || path == "/ping"
+        || (path.starts_with("/admin/") && is_admin_request(&req))
+    {
+        return Ok(next.run(req).await);
+    }
+    let key = req
+        .headers()
+        .get("X-Api-Key")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    let Some(api_key) = api_key::get(key) else {
+        return Err(HTTPError::new_with_category_status(
+            "api key is invalid",
+            "api_key",
+            401,
+        ));
+    };
+    api_key::check_request(api_key)
+        .map_err(|message| HTTPError::new_with_category_status(&message, "quota", 429))?;
+
+    Ok(API_KEY.scope(api_key.name.clone(), next.run(req)).await)
+}
+
+/// Get the path of the named storage, the api key of the request
+/// may restrict which storages are allowed.
+pub fn get_storage_path(name: &str) -> HTTPResult<String> {
+    let invalid = || HTTPError::new(&format!("storage({name}) is invalid"), "validate");
+    let storages = config::get_storages();
+    let path = storages
+        .iter()
+        .find(|(item, _)| item == name)
+        .map(|(_, path)| path.clone())
+        .ok_or_else(invalid)?;
+    // api key可限制使用的存储
+    let key = API_KEY
+        .try_with(clone_value_from_task_local)
+        .unwrap_or_default();
+    if !key.is_empty() {
+        if let Some(allowed) = config::get_api_key_allowed("STORAGES", &key) {
+            if !allowed.iter().any(|item| item == name) {
+                return Err(HTTPError::new_with_category_status(
+                    &format!("storage({name}) is not allowed"),
+                    "forbidden",
+                    403,
+                ));
+            }
+        }
+    }
+    Ok(path)
+}
+
+pub async fn storage(req: Request<Body>, next: Next) -> HTTPResult<Response> {
+    let Some(value) = req.headers().get("X-Storage") else {
+        return Ok(next.run(req).await);
+    };
+    let path = get_storage_path(value.to_str().unwrap_or_default())?;
+    Ok(STORAGE.scope(path, next.run(req)).await)
+}
+
 pub async fn access_log(
     InsecureClientIp(ip): InsecureClientIp,
     req: Request<Body>,
@@ -0,0 +1,100 @@
+use crate::config;
+use crate::http_client;
+use crate::image_analysis;
+use crate::image_processing::{run_blocking, STAGE_PROCESS};
+use image::DynamicImage;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use snafu::Snafu;
+use std::time::Duration;
+
+#[derive(Debug, Snafu)]
+pub enum ModerationError {
+    #[snafu(display("Image is flagged, reason:{reason}"))]
+    Flagged { reason: String },
+    #[snafu(display("Moderation is unavailable, message:{message}"))]
+    Unavailable { message: String },
+}
+
+type Result<T, E = ModerationError> = std::result::Result<T, E>;
+
+#[derive(Serialize)]
+struct HashPayload {
+    sha256: String,
+    phash: String,
+    size: usize,
+}
+
+#[derive(Deserialize)]
+struct ModerationResult {
+    flagged: bool,
+    reason: Option<String>,
+}
+
+// hash的计算较耗时，在其它线程中执行，计算完成后恢复图片
+async fn new_hash_payload(data: Vec<u8>, di: &mut DynamicImage) -> Result<HashPayload> {
+    let image = std::mem::take(di);
+    let (image, payload) = run_blocking(STAGE_PROCESS, Duration::ZERO, move || {
+        let payload = HashPayload {
+            sha256: format!("{:x}", Sha256::digest(&data)),
+            phash: image_analysis::get_perceptual_hashes(&image).phash,
+            size: data.len(),
+        };
+        (image, payload)
+    })
+    .await
+    .map_err(|e| ModerationError::Unavailable {
+        message: e.to_string(),
+    })?;
+    *di = image;
+    Ok(payload)
+}
+
+async fn request(url: &str, data: Vec<u8>, ext: &str, payload: Option<HashPayload>) -> Result<()> {
+    let unavailable = |e: reqwest::Error| ModerationError::Unavailable {
+        message: e.to_string(),
+    };
+    let req = http_client::get_client()
+        .post(url)
+        .timeout(config::get_moderation_timeout());
+    // 仅提交hash或者提交图片数据
+    let req = if let Some(payload) = payload {
+        req.json(&payload)
+    } else {
+        req.header("Content-Type", format!("image/{ext}"))
+            .body(data)
+    };
+    let result: ModerationResult = req
+        .send()
+        .await
+        .and_then(|resp| resp.error_for_status())
+        .map_err(unavailable)?
+        .json()
+        .await
+        .map_err(unavailable)?;
+    if result.flagged {
+        return FlaggedSnafu {
+            reason: result.reason.unwrap_or_default(),
+        }
+        .fail();
+    }
+    Ok(())
+}
+
+/// Check the loaded image by the external moderation service,
+/// the unavailable error is ignored if moderation is fail-open.
+pub async fn check(url: &str, data: Vec<u8>, ext: &str, di: &mut DynamicImage) -> Result<()> {
+    // hash计算失败时图片已不可用，不能忽略
+    let (data, payload) = if config::is_moderation_hash_only() {
+        (vec![], Some(new_hash_payload(data, di).await?))
+    } else {
+        (data, None)
+    };
+    match request(url, data, ext, payload).await {
+        Err(ModerationError::Unavailable { message }) if config::is_moderation_fail_open() => {
+            tracing::warn!(url, "Moderation is skipped, {message}");
+            Ok(())
+        }
+        result => result,
+    }
+}
@@ -1,48 +1,167 @@
-use crate::error::{HTTPError, HTTPResult};
+use crate::api_key;
+use crate::audit;
+use crate::barcode;
+use crate::card;
+use crate::config;
+use crate::error::{ErrorCode, HTTPError, HTTPResult};
+use crate::image_analysis::{self, ContentKind};
+use crate::image_encoder::{
+    AnimatedFrame, ChromaSubsampling, EncoderOptions, Interlace, PngFilter, QualityPreset,
+};
+use crate::image_processing::{
+    self, Effect, LoaderProcess, OptimProcess, OutputType, Process, ProcessImage, ResizeProcess,
+    Task,
+};
 use crate::images;
+use crate::proxy;
 use crate::response::ResponseResult;
-use axum::body::Bytes;
-use axum::extract::{Multipart, Path, Query, RawQuery};
+use crate::state;
+use crate::stats;
+use crate::task_local::{clone_value_from_task_local, API_KEY, STORAGE, TRACE_ID};
+use crate::tl_info;
+use axum::body::{Body, Bytes};
+use axum::extract::ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{DefaultBodyLimit, Multipart, Path, Query, RawQuery};
+use axum::handler::Handler;
+use axum::http::{header, HeaderMap, HeaderValue};
+use axum::response::{IntoResponse, Response};
 use axum::routing::{get, post};
 use axum::{Json, Router};
 use base64::{engine::general_purpose, Engine as _};
+use futures_util::future::try_join_all;
+use futures_util::{stream, StreamExt, TryStreamExt};
+use image::DynamicImage;
 use once_cell::sync::Lazy;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
-use urlencoding::decode;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::future::Future;
+use std::io::Write;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio::task::JoinSet;
+use tracing::info;
+use urlencoding::{decode, encode};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
 
 pub fn new_router() -> Router {
-    let optim_images = Router::new().route("/", get(optim_image_preview).post(optim_image));
+    // 上传与post的数据大小限制
+    let body_limit = DefaultBodyLimit::max(config::get_max_upload_bytes());
+    let optim_images = Router::new().route(
+        "/",
+        get(optim_image_preview).post(optim_image.layer(body_limit.clone())),
+    );
     let pipe_line = Router::new()
         .route("/", get(pipeline_image))
         .route("/preview", get(pipeline_image_preview));
 
     Router::new()
-        .route("/images/*path", get(handle_image))
-        .route("/upload", post(handle_upload))
+        .route("/images/colors", get(image_colors))
+        .route("/images/hash", get(image_hash))
+        .route("/images/stats", get(image_stats))
+        .route("/images/qr", get(image_qr))
+        .route("/images/card", get(image_card))
+        .route("/images/compare", get(image_compare))
+        .route("/images/srcset", get(image_srcset))
+        .route("/images/info", get(image_info))
+        .route("/images/bulk", get(handle_bulk))
+        .route("/images/sprite", post(image_sprite))
+        .route("/images/validate", post(validate_pipeline))
+        .route(
+            "/images/optim",
+            post(optim_image_body.layer(body_limit.clone())),
+        )
+        .route("/images/*path", get(handle_image).head(head_image))
+        .route("/upload", post(handle_upload.layer(body_limit)))
         .nest("/optim-images", optim_images)
         .nest("/pipeline-images", pipe_line)
 }
-static OPTIM_PATH: Lazy<String> = Lazy::new(|| {
-    std::env::var_os("OPTIM_PATH")
-        .unwrap_or_default()
-        .to_string_lossy()
-        .to_string()
+static OPTIM_PATH: Lazy<String> = Lazy::new(config::get_optim_path);
+
+// 配置的前置与后置任务，格式有误则忽略
+static DEFAULT_TASKS: Lazy<(Vec<Task>, Vec<Task>)> = Lazy::new(|| {
+    let parse = |key: &str, value: String| {
+        if value.is_empty() {
+            return vec![];
+        }
+        convert_query_to_tasks(Some(value)).unwrap_or_else(|e| {
+            tracing::error!(key, "Parse default tasks fail, {}", e.message);
+            vec![]
+        })
+    };
+    (
+        parse("OPTIM_PRE_TASKS", config::get_pre_tasks()),
+        parse("OPTIM_POST_TASKS", config::get_post_tasks()),
+    )
 });
 
+// 前置任务在加载之后，后置任务在压缩之前(无压缩则在最后)
+fn add_default_tasks(mut tasks: Vec<Task>) -> Vec<Task> {
+    let (pre, post) = &*DEFAULT_TASKS;
+    if !post.is_empty() {
+        let index = tasks
+            .iter()
+            .position(|task| matches!(task, Task::Optim { .. }))
+            .unwrap_or(tasks.len());
+        tasks.splice(index..index, post.iter().cloned());
+    }
+    if !pre.is_empty() {
+        let index = tasks
+            .iter()
+            .position(|task| !matches!(task, Task::Load { .. }))
+            .unwrap_or(tasks.len());
+        tasks.splice(index..index, pre.iter().cloned());
+    }
+    tasks
+}
+
+// 对已加载的图片执行默认的前置或后置任务(用于不经过pipeline的处理)
+async fn apply_default_tasks(img: ProcessImage, tasks: &[Task]) -> HTTPResult<ProcessImage> {
+    if tasks.is_empty() {
+        return Ok(img);
+    }
+    check_tasks_allowed(tasks)?;
+    let tasks = resolve_file_urls(tasks.to_vec())?;
+    Ok(image_processing::run_with(img, tasks).await?)
+}
+
 #[derive(Serialize)]
 struct OptimImageResult {
     diff: f64,
     data: String,
     output_type: String,
     ratio: usize,
+    size: usize,
 }
 
-struct OptimResult {
-    diff: f64,
-    data: Vec<u8>,
-    output_type: String,
-    ratio: usize,
+pub(crate) struct OptimResult {
+    pub(crate) diff: f64,
+    pub(crate) data: Vec<u8>,
+    pub(crate) output_type: String,
+    pub(crate) ratio: usize,
+    passthrough: bool,
+    encoding: Option<image_processing::AppliedEncoding>,
+    pub(crate) original_size: usize,
+    report: image_processing::ProcessReport,
+    // 原图片的名称(不含扩展名)
+    name: String,
+    source_headers: Vec<(String, String)>,
+    content: Option<ContentKind>,
+}
+
+impl From<OptimResult> for OptimImageResult {
+    fn from(result: OptimResult) -> Self {
+        OptimImageResult {
+            diff: result.diff,
+            ratio: result.ratio,
+            size: result.data.len(),
+            data: general_purpose::STANDARD.encode(result.data),
+            output_type: result.output_type,
+        }
+    }
 }
 
 #[derive(Serialize)]
@@ -50,49 +169,558 @@ struct UploadResult {
     pub optims: Vec<OptimImageResult>,
 }
 
-async fn handle_upload(mut multipart: Multipart) -> ResponseResult<Json<UploadResult>> {
-    let mut filename = "".to_string();
-    let mut data = Bytes::new();
-    while let Some(field) = multipart.next_field().await? {
+#[derive(Deserialize)]
+struct UploadParams {
+    archive: Option<String>,
+}
+
+struct UploadFile {
+    name: String,
+    data: Bytes,
+}
+
+impl UploadFile {
+    fn ext(&self) -> &str {
+        self.name.split('.').next_back().unwrap_or_default()
+    }
+    // 转换的格式，原格式不支持则转换为jpeg
+    fn output_types(&self) -> Vec<OutputType> {
+        let original_type = self.ext().parse().unwrap_or(OutputType::Jpeg);
+        let mut types = vec![OutputType::Avif, OutputType::Webp];
+        if !types.contains(&original_type) {
+            types.push(original_type);
+        }
+        types
+    }
+    fn params(&self, output_type: OutputType) -> OptimImageParams {
+        OptimImageParams {
+            data: general_purpose::STANDARD.encode(&self.data),
+            data_type: Some(self.ext().to_string()),
+            output_type: Some(output_type),
+            quality: Some(QualityParam::Value(config::get_upload_quality())),
+            ..Default::default()
+        }
+    }
+}
+
+async fn handle_upload(
+    Query(params): Query<UploadParams>,
+    mut multipart: Multipart,
+) -> ResponseResult<Response> {
+    let mut files = vec![];
+    let max_size = config::get_max_upload_bytes();
+    let mut size = 0;
+    while let Some(mut field) = multipart.next_field().await? {
         if field.name().unwrap_or_default() != "file" {
             continue;
         }
-        filename = field.file_name().unwrap_or_default().to_string();
-        data = field.bytes().await?;
+        let name = field.file_name().unwrap_or_default().to_string();
+        // 分块读取，超出限制时尽早中止
+        let mut data = vec![];
+        while let Some(chunk) = field.chunk().await? {
+            size += chunk.len();
+            if size > max_size {
+                return Err(HTTPError::new_with_category_status(
+                    &format!("upload data is too large, max is {max_size} bytes"),
+                    "too_large",
+                    413,
+                ));
+            }
+            data.extend_from_slice(&chunk);
+        }
+        let data = Bytes::from(data);
+        if !data.is_empty() {
+            files.push(UploadFile { name, data });
+        }
     }
-    if data.is_empty() {
-        return Err(HTTPError::new("data is empty", "invalid"));
+    match params.archive.as_deref() {
+        Some("zip") => {
+            if files.is_empty() {
+                return Err(HTTPError::new("data is empty", "invalid"));
+            }
+            return Ok(upload_archive(files));
+        }
+        Some(archive) => {
+            return Err(HTTPError::new(
+                &format!("archive({archive}) is not support"),
+                "validate",
+            ));
+        }
+        None => {}
     }
-    let ext = filename.split('.').last().unwrap_or_default();
-    let data = general_purpose::STANDARD.encode(data);
-    let mut optims = vec![];
-    for item in ["avif".to_string(), "webp".to_string(), ext.to_string()] {
-        // TODO 后续调整复用
-        let params = OptimImageParams {
-            data: data.clone(),
-            data_type: Some(ext.to_string()),
-            output_type: Some(item),
-            quality: Some(90),
-            ..Default::default()
-        };
-        let result = handle(params).await?;
-        optims.push(OptimImageResult {
-            diff: result.diff,
+    // 非打包仅处理最后一个文件
+    let file = files
+        .pop()
+        .ok_or_else(|| HTTPError::new("data is empty", "invalid"))?;
+    // 各格式同时处理
+    let optims = try_join_all(
+        file.output_types()
+            .into_iter()
+            .map(|item| handle(file.params(item))),
+    )
+    .await?
+    .into_iter()
+    .map(OptimImageResult::from)
+    .collect();
+
+    Ok(Json(UploadResult { optims }).into_response())
+}
+
+// 将写入的数据发送至channel，用于流式响应
+struct ChannelWriter(UnboundedSender<std::io::Result<Bytes>>);
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0
+            .send(Ok(Bytes::copy_from_slice(buf)))
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::BrokenPipe, e))?;
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+async fn write_archive(
+    files: Vec<UploadFile>,
+    tx: UnboundedSender<std::io::Result<Bytes>>,
+) -> HTTPResult<()> {
+    let mut zip = ZipWriter::new_stream(ChannelWriter(tx));
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+    let quality = config::get_upload_quality();
+    for file in files {
+        let stem = file
+            .name
+            .rsplit_once('.')
+            .map(|(stem, _)| stem)
+            .unwrap_or(&file.name)
+            .to_string();
+        for item in file.output_types() {
+            let result = handle(file.params(item)).await?;
+            let name = format!("{stem}_{quality}.{}", result.output_type);
+            zip.start_file(name, options)
+                .map_err(|e| HTTPError::new(&e.to_string(), "zip"))?;
+            zip.write_all(&result.data)
+                .map_err(|e| HTTPError::new(&e.to_string(), "zip"))?;
+        }
+    }
+    zip.finish()
+        .map_err(|e| HTTPError::new(&e.to_string(), "zip"))?;
+    Ok(())
+}
+
+// 每个图片处理完成后即写入响应
+fn upload_archive(files: Vec<UploadFile>) -> Response {
+    let (tx, rx) = unbounded_channel();
+    // 新的task需要设置trace id与api key
+    let trace_id = TRACE_ID.with(clone_value_from_task_local);
+    let api_key = API_KEY
+        .try_with(clone_value_from_task_local)
+        .unwrap_or_default();
+    let task = async move {
+        // 出错时以错误结束响应，中断连接而非正常结束，避免客户端将不完整的压缩包视为成功
+        if let Err(e) = write_archive(files, tx.clone()).await {
+            tracing::error!("Write archive fail, {}", e.message);
+            let _ = tx.send(Err(std::io::Error::other(e.message)));
+        }
+    };
+    tokio::spawn(TRACE_ID.scope(trace_id, API_KEY.scope(api_key, task)));
+    let stream = stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    });
+    (
+        [
+            (header::CONTENT_TYPE, "application/zip"),
+            (
+                header::CONTENT_DISPOSITION,
+                r#"attachment; filename="images.zip""#,
+            ),
+        ],
+        Body::from_stream(stream),
+    )
+        .into_response()
+}
+
+#[derive(Deserialize)]
+struct PreviewParams {
+    fallback: Option<bool>,
+    debug: Option<String>,
+    download: Option<String>,
+    cache_control: Option<String>,
+    format: Option<String>,
+    effect: Option<String>,
+    enhance: Option<bool>,
+    gamma: Option<f32>,
+    page: Option<u32>,
+}
+
+#[derive(PartialEq)]
+enum DebugMode {
+    // 报告设置在响应头中
+    Header,
+    // 返回报告而非图片
+    Json,
+}
+
+// 获取调试模式，参数或请求头X-Optim-Debug指定，需要配置允许
+fn get_debug_mode(value: Option<String>, headers: &HeaderMap) -> HTTPResult<Option<DebugMode>> {
+    let value = value.or_else(|| {
+        headers
+            .get("X-Optim-Debug")
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.to_string())
+    });
+    let mode = match value.as_deref() {
+        None | Some("") | Some("0") | Some("false") => return Ok(None),
+        Some("json") => DebugMode::Json,
+        _ => DebugMode::Header,
+    };
+    let name = API_KEY
+        .try_with(clone_value_from_task_local)
+        .unwrap_or_default();
+    if !config::is_debug_enabled() && (name.is_empty() || !config::is_api_key_debug(&name)) {
+        return Err(HTTPError::new_with_category_status(
+            "debug is not allowed",
+            "forbidden",
+            403,
+        ));
+    }
+    Ok(Some(mode))
+}
+
+#[derive(Serialize)]
+struct DebugReport<'a> {
+    output_type: &'a str,
+    original_size: usize,
+    size: usize,
+    ratio: usize,
+    diff: f64,
+    passthrough: bool,
+    encoding: Option<image_processing::AppliedEncoding>,
+    content: Option<ContentKind>,
+    #[serde(flatten)]
+    process: &'a image_processing::ProcessReport,
+}
+
+fn get_header_value(headers: &HeaderMap, name: header::HeaderName) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string())
+}
+
+// 下载的文件名，未指定名称(1或true)则使用原图片的名称，再加上尺寸与质量
+fn get_download_name(download: &str, result: &OptimResult) -> String {
+    let name = if ["1", "true"].contains(&download) {
+        &result.name
+    } else {
+        download
+            .rsplit_once('.')
+            .map(|(stem, _)| stem)
+            .unwrap_or(download)
+    };
+    let mut name: String = name
+        .chars()
+        .map(|c| {
+            // 保留中文等非ascii的文字，响应头中编码处理
+            if c.is_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if name.is_empty() {
+        name = "image".to_string();
+    }
+    if let Some(stage) = result.report.stages.last() {
+        name = format!("{name}_{}x{}", stage.width, stage.height);
+    }
+    if let Some(encoding) = &result.encoding {
+        name = format!("{name}_q{}", encoding.quality);
+    }
+    format!("{name}.{}", result.output_type)
+}
+
+// 非ascii的文件名使用filename*编码，filename则替换为_兼容旧的客户端
+fn get_content_disposition(name: &str) -> String {
+    if name.is_ascii() {
+        return format!("attachment; filename=\"{name}\"");
+    }
+    let fallback: String = name
+        .chars()
+        .map(|c| if c.is_ascii() { c } else { '_' })
+        .collect();
+    format!(
+        "attachment; filename=\"{fallback}\"; filename*=UTF-8''{}",
+        encode(name)
+    )
+}
+
+// 各处理任务的耗时，格式与Server-Timing一致，如load;dur=12, optim;dur=210
+fn get_server_timing(report: &image_processing::ProcessReport) -> String {
+    report
+        .stages
+        .iter()
+        .map(|stage| format!("{};dur={}", stage.task, stage.cost))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// 图片尺寸的响应头，用于前端通过HEAD请求获取尺寸，尺寸未知(未解码)则不设置
+fn set_dimension_headers(headers: &mut HeaderMap, size: (u32, u32), original: (u32, u32)) {
+    let values = [
+        ("X-Image-Width", size.0),
+        ("X-Image-Height", size.1),
+        ("X-Image-Original-Width", original.0),
+        ("X-Image-Original-Height", original.1),
+    ];
+    if values.iter().any(|(_, value)| *value == 0) {
+        return;
+    }
+    for (name, value) in values {
+        headers.insert(name, HeaderValue::from(value));
+    }
+}
+
+struct PreviewOptions {
+    fallback: bool,
+    debug: Option<DebugMode>,
+    download: Option<String>,
+    cache_control: String,
+    // 返回json而非图片数据
+    json: bool,
+}
+
+// 响应的格式，json则返回图片的信息与数据(base64)
+fn is_json_format(value: Option<&str>) -> HTTPResult<bool> {
+    match value {
+        None | Some("") | Some("image") => Ok(false),
+        Some("json") => Ok(true),
+        Some(value) => Err(HTTPError::new(
+            &format!("format({value}) is not support"),
+            "validate",
+        )),
+    }
+}
+
+#[derive(Serialize)]
+struct PreviewJsonResult<'a> {
+    data: String,
+    output_type: &'a str,
+    size: usize,
+    original_size: usize,
+    ratio: usize,
+    diff: f64,
+    width: u32,
+    height: u32,
+    original_width: u32,
+    original_height: u32,
+    encoding: Option<image_processing::AppliedEncoding>,
+    stages: &'a [image_processing::StageReport],
+}
+
+// 获取请求指定的缓存控制，未指定则使用默认配置
+fn get_cache_control(value: Option<String>) -> HTTPResult<String> {
+    let Some(value) = value else {
+        return Ok(images::get_default_cache_control());
+    };
+    images::parse_cache_control(&value).map_err(|message| HTTPError::new(&message, "validate"))
+}
+
+// 处理出错且启用fallback时返回fallback图片
+fn preview_response(
+    result: HTTPResult<OptimResult>,
+    options: PreviewOptions,
+    headers: &HeaderMap,
+) -> ResponseResult<Response> {
+    let debug = options.debug;
+    let result = match result {
+        Ok(result) => result,
+        Err(error) if options.fallback && !options.json && error.category == "image_process" => {
+            return Ok(images::FallbackImage { error }.into_response());
+        }
+        Err(error) => return Err(error),
+    };
+    let report = debug.as_ref().map(|_| DebugReport {
+        output_type: &result.output_type,
+        original_size: result.original_size,
+        size: result.data.len(),
+        ratio: result.ratio,
+        diff: result.diff,
+        passthrough: result.passthrough,
+        encoding: result.encoding,
+        content: result.content,
+        process: &result.report,
+    });
+    if debug == Some(DebugMode::Json) {
+        return Ok(Json(report).into_response());
+    }
+    if options.json {
+        // 加载任务为原图尺寸，最后的任务为输出尺寸
+        let stages = &result.report.stages;
+        let first = stages.first();
+        let last = stages.last();
+        return Ok(Json(PreviewJsonResult {
+            data: general_purpose::STANDARD.encode(&result.data),
+            output_type: &result.output_type,
+            size: result.data.len(),
+            original_size: result.original_size,
             ratio: result.ratio,
-            data: general_purpose::STANDARD.encode(result.data),
-            output_type: result.output_type,
-        });
+            diff: result.diff,
+            width: last.map(|stage| stage.width).unwrap_or_default(),
+            height: last.map(|stage| stage.height).unwrap_or_default(),
+            original_width: first.map(|stage| stage.width).unwrap_or_default(),
+            original_height: first.map(|stage| stage.height).unwrap_or_default(),
+            encoding: result.encoding,
+            stages,
+        })
+        .into_response());
     }
+    let report = report
+        .and_then(|report| serde_json::to_string(&report).ok())
+        .and_then(|report| HeaderValue::from_str(&report).ok());
+    let timing = Some(get_server_timing(&result.report))
+        .filter(|timing| !timing.is_empty())
+        .and_then(|timing| HeaderValue::from_str(&timing).ok());
+    let disposition = options
+        .download
+        .map(|download| get_download_name(&download, &result))
+        .and_then(|name| HeaderValue::from_str(&get_content_disposition(&name)).ok());
 
-    Ok(Json(UploadResult { optims }))
+    let mut res = images::ImagePreview {
+        ratio: result.ratio,
+        diff: result.diff,
+        data: result.data,
+        image_type: result.output_type,
+        passthrough: result.passthrough,
+        encoding: result.encoding,
+        range: get_header_value(headers, header::RANGE),
+        if_range: get_header_value(headers, header::IF_RANGE),
+        cache_control: options.cache_control,
+        content: result.content.map(|content| content.as_str()),
+    }
+    .into_response();
+    if let Some(timing) = timing {
+        res.headers_mut().insert("Server-Timing", timing.clone());
+        res.headers_mut().insert("X-Optim-Timing", timing);
+    }
+    if let Some(report) = report {
+        res.headers_mut().insert("X-Optim-Report", report);
+    }
+    // 差异值由缩小后的图片计算
+    if result.report.diff_approximate {
+        res.headers_mut()
+            .insert("X-Dssim-Approximate", HeaderValue::from_static("true"));
+    }
+    // 加载任务为原图尺寸，最后的任务为输出尺寸
+    if let (Some(first), Some(last)) = (result.report.stages.first(), result.report.stages.last()) {
+        set_dimension_headers(
+            res.headers_mut(),
+            (last.width, last.height),
+            (first.width, first.height),
+        );
+    }
+    // 透传原图片的响应头，不覆盖已设置的
+    for (name, value) in result.source_headers {
+        if res.headers().contains_key(&name) {
+            continue;
+        }
+        if let (Ok(name), Ok(value)) = (
+            header::HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            res.headers_mut().insert(name, value);
+        }
+    }
+    if let Some(disposition) = disposition {
+        res.headers_mut()
+            .insert(header::CONTENT_DISPOSITION, disposition);
+    }
+    Ok(res)
 }
 
-async fn handle_image(Path(path): Path<String>) -> ResponseResult<images::ImagePreview> {
+async fn handle_image(
+    Path(path): Path<String>,
+    Query(preview): Query<PreviewParams>,
+    headers: HeaderMap,
+) -> ResponseResult<Response> {
+    let debug = get_debug_mode(preview.debug, &headers)?;
+    let mut params = parse_image_path(&path)?;
+    params.effect = preview
+        .effect
+        .as_deref()
+        .filter(|value| !value.is_empty())
+        .map(|value| value.parse())
+        .transpose()?;
+    params.enhance = preview.enhance;
+    params.gamma = preview.gamma;
+    params.page = preview.page;
+    // 代理的图片使用单独的缓存时长
+    let cache_control = match preview.cache_control {
+        None if proxy::is_proxy_url(&params.data) => images::get_proxy_cache_control(),
+        value => get_cache_control(value)?,
+    };
+    let result = handle(params).await;
+
+    let options = PreviewOptions {
+        fallback: preview.fallback.unwrap_or_default(),
+        debug,
+        download: preview.download,
+        cache_control,
+        json: is_json_format(preview.format.as_deref())?,
+    };
+    preview_response(result, options, &headers)
+}
+
+// HEAD请求不处理图片时，仅根据输出类型返回响应头(无数据长度)
+async fn head_image(
+    Path(path): Path<String>,
+    query: Query<PreviewParams>,
+    headers: HeaderMap,
+) -> ResponseResult<Response> {
+    if config::is_head_process() {
+        return handle_image(Path(path), query, headers).await;
+    }
+    let params = parse_image_path(&path)?;
+    let output_type = params.output_type.unwrap_or(OutputType::Jpeg);
+    // 使用stream的body，不会设置Content-Length
+    let mut res = Body::from_stream(stream::empty::<Result<Bytes, Infallible>>()).into_response();
+    let result = mime_guess::from_ext(output_type.as_str()).first_or(mime::IMAGE_JPEG);
+    if let Ok(value) = HeaderValue::from_str(result.as_ref()) {
+        res.headers_mut().insert(header::CONTENT_TYPE, value);
+    }
+    let cache_control = if proxy::is_proxy_url(&params.data) {
+        images::get_proxy_cache_control()
+    } else {
+        images::get_default_cache_control()
+    };
+    if let Ok(value) = HeaderValue::from_str(&cache_control) {
+        res.headers_mut().insert(header::CACHE_CONTROL, value);
+    }
+    // 仅读取图片头获取尺寸，图片目录的处理不调整尺寸，因此与原图一致
+    if let Some(file) = params.data.strip_prefix("file://") {
+        let file = get_local_file_url(file)?;
+        let file = file.trim_start_matches("file://").to_string();
+        let header = tokio::task::spawn_blocking(move || image_processing::probe_file(&file))
+            .await
+            .ok()
+            .and_then(|result| result.ok());
+        if let Some(header) = header {
+            let size = (header.width, header.height);
+            set_dimension_headers(res.headers_mut(), size, size);
+        }
+    }
+    Ok(res)
+}
+
+// 图片路径格式为{file}_{quality}.{ext}
+fn parse_image_path(path: &str) -> HTTPResult<OptimImageParams> {
     let re = Regex::new(
         r"(?x)
     (?P<file>[\s\S]+*)  # the file
     _
-    (?P<quality>\d{2}) # the quality
+    (?P<quality>\d{2}|low|medium|high|lossless) # the quality or preset
     \.
     (?P<ext>\S+)   # the day
     ",
@@ -100,84 +728,1088 @@ async fn handle_image(Path(path): Path<String>) -> ResponseResult<images::ImageP
     .map_err(|e| HTTPError::new(&e.to_string(), "regexp"))?;
 
     let caps = re
-        .captures(&path)
+        .captures(path)
         .ok_or_else(|| HTTPError::new("image path is invalid", "regexp"))?;
 
-    let prefix = OPTIM_PATH.to_string();
-
-    let file = format!("file://{prefix}/{}", &caps["file"]);
-    let quality: u8 = caps["quality"].to_string().parse().unwrap_or_default();
-    let params = OptimImageParams {
+    let file = get_load_url(&caps["file"])?;
+    let quality: QualityParam = caps["quality"].parse()?;
+    Ok(OptimImageParams {
         data: file,
-        output_type: Some(caps["ext"].to_string()),
+        output_type: Some(caps["ext"].parse()?),
         quality: Some(quality),
         ..Default::default()
+    })
+}
+
+// 校验并规范化文件路径，避免访问图片目录以外的文件
+pub(crate) fn sanitize_file_path(file: &str) -> HTTPResult<String> {
+    let invalid =
+        |reason: &str| HTTPError::new(&format!("file path is invalid, {reason}"), "validate");
+    // 多次编码的路径逐层解码后均需校验
+    let mut values = vec![file.to_string()];
+    for _ in 0..3 {
+        let value = &values[values.len() - 1];
+        let decoded = decode(value)
+            .map_err(|_| invalid("bad percent encoding"))?
+            .to_string();
+        if &decoded == value {
+            break;
+        }
+        values.push(decoded);
+    }
+    for value in values.iter() {
+        if value.chars().any(|c| c.is_control()) {
+            return Err(invalid("control character is not allowed"));
+        }
+        if value.contains('\\') {
+            return Err(invalid("backslash is not allowed"));
+        }
+        if value.contains("//") {
+            return Err(invalid("double slash is not allowed"));
+        }
+        if value
+            .trim_start_matches('/')
+            .split('/')
+            .any(|item| item == ".." || item == ".")
+        {
+            return Err(invalid("dot segment is not allowed"));
+        }
+    }
+    let file = file.trim_start_matches('/');
+    if file.is_empty() {
+        return Err(invalid("file is empty"));
+    }
+    Ok(file.to_string())
+}
+
+// 校验文件是否允许读取，禁止的路径优先
+fn check_file_access(file: &str) -> HTTPResult<()> {
+    let file = file.trim_start_matches('/');
+    if config::get_denied_paths()
+        .iter()
+        .any(|prefix| file.starts_with(prefix))
+    {
+        return Err(HTTPError::new_with_category_status(
+            "file path is denied",
+            "forbidden",
+            403,
+        ));
+    }
+    let allowed = config::get_allowed_paths();
+    if !allowed.is_empty() && !allowed.iter().any(|prefix| file.starts_with(prefix)) {
+        return Err(HTTPError::new_with_category_status(
+            "file path is not in the allowed paths",
+            "forbidden",
+            403,
+        ));
+    }
+    Ok(())
+}
+
+// 图片目录中文件的实际地址，文件路径均相对于图片目录
+fn get_local_file_url(file: &str) -> HTTPResult<String> {
+    let file = sanitize_file_path(file)?;
+    check_file_access(&file)?;
+    // 请求指定了存储则使用其目录
+    let root = STORAGE
+        .try_with(clone_value_from_task_local)
+        .unwrap_or_else(|_| OPTIM_PATH.to_string());
+    // 未配置图片目录时不允许读取文件，避免以根目录读取
+    if root.is_empty() {
+        return Err(HTTPError::new_with_category_status(
+            "image directory is not configured",
+            "forbidden",
+            403,
+        ));
+    }
+    Ok(format!("file://{root}/{file}"))
+}
+
+// 图片目录中文件的加载地址，远程地址则需允许代理
+fn get_file_url(file: &str) -> HTTPResult<String> {
+    if proxy::is_proxy_url(file) {
+        proxy::check(file)?;
+        return Ok(file.to_string());
+    }
+    get_local_file_url(file)
+}
+
+// pipeline中的加载地址，文件为相对于图片目录的路径，处理时才转换为实际地址
+pub(crate) fn get_load_url(file: &str) -> HTTPResult<String> {
+    if proxy::is_proxy_url(file) {
+        proxy::check(file)?;
+        return Ok(file.to_string());
+    }
+    let file = sanitize_file_path(file)?;
+    check_file_access(&file)?;
+    Ok(format!("file://{file}"))
+}
+
+async fn load_file(file: &str) -> HTTPResult<ProcessImage> {
+    load_url(&get_file_url(file)?).await
+}
+
+// 加载已转换为实际地址的图片
+async fn load_url(url: &str) -> HTTPResult<ProcessImage> {
+    let img = LoaderProcess::new(url, "")
+        .process(ProcessImage::default())
+        .await?;
+    // 分析类的处理需要解码后的图片
+    if img.passthrough {
+        return Err(
+            HTTPError::new("image format is not support", "image_process")
+                .with_code(ErrorCode::UnsupportedFormat),
+        );
+    }
+    add_usage(&img);
+    Ok(img)
+}
+
+// 记录api key处理的像素与原始数据大小
+fn add_usage(img: &ProcessImage) {
+    let name = API_KEY
+        .try_with(clone_value_from_task_local)
+        .unwrap_or_default();
+    if !name.is_empty() {
+        api_key::add_processed(&name, img.source_pixels, img.original_size);
+    }
+}
+
+#[derive(Deserialize)]
+struct ImageColorsParams {
+    file: String,
+    count: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct ImageColorsResult {
+    colors: Vec<image_analysis::DominantColor>,
+}
+
+async fn image_colors(
+    Query(params): Query<ImageColorsParams>,
+) -> ResponseResult<Json<ImageColorsResult>> {
+    let count = params.count.unwrap_or(5);
+    if count == 0 || count > 16 {
+        return Err(HTTPError::new("count should be 1-16", "validate"));
+    }
+    let colors = analyze_file(&params.file, move |di| {
+        image_analysis::get_dominant_colors(di, count)
+    })
+    .await?;
+    Ok(Json(ImageColorsResult { colors }))
+}
+
+// 加载图片后在其它线程中分析，计数随分析的线程释放(客户端断开连接也需要等待分析完成)
+async fn analyze_file<T, F>(file: &str, f: F) -> HTTPResult<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&DynamicImage) -> T + Send + 'static,
+{
+    check_analysis_overload()?;
+    let processing_guard = state::start_processing();
+    let img = load_file(file).await?;
+    let pixels_guard = state::start_pixels(img.source_pixels);
+    tokio::task::spawn_blocking(move || {
+        let _guards = (processing_guard, pixels_guard);
+        f(img.get_image())
+    })
+    .await
+    .map_err(|e| HTTPError::new(&e.to_string(), "image_process"))
+}
+
+#[derive(Deserialize)]
+struct ImageFileParams {
+    file: String,
+}
+
+#[derive(Serialize)]
+struct ImageInfoResult {
+    #[serde(flatten)]
+    header: image_processing::ImageHeader,
+    size: u64,
+}
+
+// 仅读取文件头获取图片信息，无需解码
+async fn image_info(
+    Query(params): Query<ImageFileParams>,
+) -> ResponseResult<Json<ImageInfoResult>> {
+    let url = get_file_url(&params.file)?;
+    let Some(file) = url.strip_prefix("file://").map(|file| file.to_string()) else {
+        return Err(HTTPError::new(
+            "info is only supported for the files of image directory",
+            "validate",
+        ));
     };
-    let result = handle(params).await?;
+    let (header, size) = tokio::task::spawn_blocking(move || {
+        let size = std::fs::metadata(&file)
+            .map(|meta| meta.len())
+            .unwrap_or_default();
+        image_processing::probe_file(&file).map(|header| (header, size))
+    })
+    .await
+    .map_err(|e| HTTPError::new(&e.to_string(), "image_process"))??;
+    Ok(Json(ImageInfoResult { header, size }))
+}
 
-    Ok(images::ImagePreview {
-        ratio: result.ratio,
-        diff: result.diff,
-        data: result.data,
-        image_type: result.output_type,
+async fn image_hash(
+    Query(params): Query<ImageFileParams>,
+) -> ResponseResult<Json<image_analysis::PerceptualHash>> {
+    let hashes = analyze_file(&params.file, image_analysis::get_perceptual_hashes).await?;
+    Ok(Json(hashes))
+}
+
+// 各通道的直方图以及过暗、过亮的像素比例
+async fn image_stats(
+    Query(params): Query<ImageFileParams>,
+) -> ResponseResult<Json<image_analysis::ExposureStats>> {
+    let stats = analyze_file(&params.file, image_analysis::get_exposure_stats).await?;
+    Ok(Json(stats))
+}
+
+#[derive(Deserialize)]
+struct ImageQrParams {
+    text: String,
+    kind: Option<barcode::CodeKind>,
+    size: Option<u32>,
+    // svg或图片的输出格式
+    format: Option<String>,
+    quality: Option<QualityParam>,
+    cache_control: Option<String>,
+    download: Option<String>,
+}
+
+// 生成二维码或条形码，图片与其它图片一样经过压缩处理
+async fn image_qr(
+    Query(params): Query<ImageQrParams>,
+    headers: HeaderMap,
+) -> ResponseResult<Response> {
+    if params.text.len() > 2048 {
+        return Err(HTTPError::new(
+            "text should not be longer than 2048",
+            "validate",
+        ));
+    }
+    let size = params.size.unwrap_or(256);
+    if !(32..=2048).contains(&size) {
+        return Err(HTTPError::new("size should be 32-2048", "validate"));
+    }
+    let kind = params.kind.unwrap_or_default();
+    let format = params.format.unwrap_or_else(|| "png".to_string());
+    if format == "svg" {
+        let cache_control = get_cache_control(params.cache_control)?;
+        let svg = barcode::new_code_svg(kind, &params.text, size)?;
+        return Ok((
+            [
+                (header::CONTENT_TYPE, "image/svg+xml".to_string()),
+                (header::CACHE_CONTROL, cache_control),
+            ],
+            svg,
+        )
+            .into_response());
+    }
+    let data = barcode::new_code_png(kind, &params.text, size)?;
+    let params = OptimImageParams {
+        data: general_purpose::STANDARD.encode(data),
+        data_type: Some("png".to_string()),
+        output_type: Some(format.parse()?),
+        quality: params.quality,
+        cache_control: params.cache_control,
+        download: params.download,
+        ..Default::default()
+    };
+    optim_image_preview(Query(params), headers).await
+}
+
+#[derive(Deserialize)]
+struct ImageCardParams {
+    template: String,
+    output_type: Option<OutputType>,
+    quality: Option<QualityParam>,
+    cache_control: Option<String>,
+    download: Option<String>,
+}
+
+// 根据模板生成分享卡片，其它参数为文字块的内容
+async fn image_card(
+    Query(params): Query<ImageCardParams>,
+    Query(values): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> ResponseResult<Response> {
+    let template = card::get_template(&params.template)?;
+    let background = if template.background.starts_with('#') {
+        None
+    } else {
+        Some(load_file(&template.background).await?)
+    };
+    let data = tokio::task::spawn_blocking(move || {
+        card::render_png(
+            &template,
+            background.as_ref().map(|img| img.get_image()),
+            &values,
+        )
     })
+    .await
+    .map_err(|e| HTTPError::new(&e.to_string(), "image_process"))??;
+    let params = OptimImageParams {
+        data: general_purpose::STANDARD.encode(data),
+        data_type: Some("png".to_string()),
+        output_type: Some(params.output_type.unwrap_or(OutputType::Jpeg)),
+        quality: params.quality,
+        cache_control: params.cache_control,
+        download: params.download,
+        ..Default::default()
+    };
+    optim_image_preview(Query(params), headers).await
+}
+
+#[derive(Deserialize)]
+struct ImageCompareParams {
+    file: String,
+    target: String,
+}
+
+async fn image_compare(
+    Query(params): Query<ImageCompareParams>,
+) -> ResponseResult<Json<image_analysis::CompareResult>> {
+    check_analysis_overload()?;
+    let processing_guard = state::start_processing();
+    let img = load_file(&params.file).await?;
+    let target = load_file(&params.target).await?;
+    let pixels_guard = state::start_pixels(img.source_pixels + target.source_pixels);
+    // dssim与psnr的计算较耗时，在其它线程中执行
+    let result = tokio::task::spawn_blocking(move || {
+        let _guards = (processing_guard, pixels_guard);
+        image_analysis::compare(img.get_image(), target.get_image())
+    })
+    .await
+    .map_err(|e| HTTPError::new(&e.to_string(), "image_process"))?;
+    Ok(Json(result))
+}
+
+#[derive(Deserialize)]
+struct ImageSrcsetParams {
+    file: String,
+    widths: String,
+    output_type: Option<OutputType>,
+    quality: Option<u8>,
+}
+
+#[derive(Serialize)]
+struct SrcsetVariant {
+    width: u32,
+    height: u32,
+    size: usize,
+    output_type: String,
+    url: String,
+    data: String,
+}
+
+#[derive(Serialize)]
+struct ImageSrcsetResult {
+    srcset: String,
+    variants: Vec<SrcsetVariant>,
+}
+
+async fn image_srcset(
+    Query(params): Query<ImageSrcsetParams>,
+) -> ResponseResult<Json<ImageSrcsetResult>> {
+    let mut widths = vec![];
+    for item in params.widths.split(',') {
+        let width = item
+            .trim()
+            .parse::<u32>()
+            .map_err(|_| HTTPError::new(&format!("width({item}) is invalid"), "validate"))?;
+        if width == 0 {
+            return Err(HTTPError::new("width should be greater than 0", "validate"));
+        }
+        widths.push(width);
+    }
+    if widths.is_empty() || widths.len() > 10 {
+        return Err(HTTPError::new("widths should be 1-10 items", "validate"));
+    }
+    let quality = params.quality.unwrap_or(80);
+    check_allowed(image_processing::PROCESS_RESIZE, None)?;
+    check_allowed(image_processing::PROCESS_OPTIM, params.output_type)?;
+
+    // 加载地址只转换一次，避免代理地址重复计入限流
+    let load = get_load_url(&params.file)?;
+    let _guard = state::start_processing();
+    // 只解码一次，每个尺寸基于解码后的图片处理
+    let img = load_url(&resolve_file_url(load.clone())?).await?;
+    let img = apply_default_tasks(img, &DEFAULT_TASKS.0).await?;
+    // 不放大图片，超过原图宽度的使用原图宽度
+    let max_width = img.get_image().width();
+    let mut widths: Vec<_> = widths
+        .into_iter()
+        .map(|width| width.min(max_width))
+        .collect();
+    widths.sort_unstable();
+    widths.dedup();
+
+    let load = encode(&load).to_string();
+    let mut variants = vec![];
+    for width in widths {
+        let result = ResizeProcess::new(width, 0).process(img.clone()).await?;
+        let result = apply_default_tasks(result, &DEFAULT_TASKS.1).await?;
+        let height = result.get_image().height();
+        let result = OptimProcess::new(params.output_type, quality, 3)
+            .process(result)
+            .await?;
+        let data = result.get_buffer().await?;
+        let optim = encode(&format!("{}|{quality}", result.ext)).to_string();
+        variants.push(SrcsetVariant {
+            width,
+            height,
+            size: data.len(),
+            url: format!("/pipeline-images/preview?load={load}&resize={width}%7C0&optim={optim}"),
+            output_type: result.ext,
+            data: general_purpose::STANDARD.encode(data),
+        });
+    }
+    let srcset = variants
+        .iter()
+        .map(|item| format!("{} {}w", item.url, item.width))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    Ok(Json(ImageSrcsetResult { srcset, variants }))
+}
+
+// 雪碧图同时加载的图片数量
+const SPRITE_LOAD_CONCURRENCY: usize = 4;
+
+#[derive(Deserialize)]
+struct ImageSpriteParams {
+    files: Vec<String>,
+    columns: Option<usize>,
+    padding: Option<u32>,
+    output_type: Option<OutputType>,
+    quality: Option<u8>,
+}
+
+#[derive(Serialize)]
+struct SpriteItem {
+    file: String,
+    #[serde(flatten)]
+    position: image_processing::SpritePosition,
+}
+
+#[derive(Serialize)]
+struct ImageSpriteResult {
+    width: u32,
+    height: u32,
+    size: usize,
+    output_type: String,
+    data: String,
+    items: Vec<SpriteItem>,
+}
+
+async fn image_sprite(
+    Json(params): Json<ImageSpriteParams>,
+) -> ResponseResult<Json<ImageSpriteResult>> {
+    if params.files.is_empty() || params.files.len() > 100 {
+        return Err(HTTPError::new("files should be 1-100 items", "validate"));
+    }
+    let padding = params.padding.unwrap_or_default();
+    if padding > 100 {
+        return Err(HTTPError::new("padding should be 0-100", "validate"));
+    }
+    check_allowed(image_processing::PROCESS_OPTIM, params.output_type)?;
+    let _guard = state::start_processing();
+    // 限制同时加载的图片数量，并记录各图片的像素
+    let mut loaded: Vec<_> = stream::iter(params.files.clone().into_iter().enumerate())
+        .map(|(index, file)| async move {
+            let img = apply_default_tasks(load_file(&file).await?, &DEFAULT_TASKS.0).await?;
+            let pixels_guard = state::start_pixels(img.source_pixels);
+            Ok::<_, HTTPError>((index, img, pixels_guard))
+        })
+        .buffer_unordered(SPRITE_LOAD_CONCURRENCY)
+        .try_collect()
+        .await?;
+    loaded.sort_unstable_by_key(|(index, ..)| *index);
+    let (images, _pixels_guards): (Vec<_>, Vec<_>) = loaded
+        .into_iter()
+        .map(|(_, img, pixels_guard)| (img, pixels_guard))
+        .unzip();
+    // 默认按接近正方形排列
+    let columns = params
+        .columns
+        .unwrap_or_else(|| (images.len() as f64).sqrt().ceil() as usize);
+    let (sprite, positions) = image_processing::new_sprite(images, columns, padding).await?;
+    let sprite = apply_default_tasks(sprite, &DEFAULT_TASKS.1).await?;
+    let (width, height) = (sprite.get_image().width(), sprite.get_image().height());
+    let result = OptimProcess::new(params.output_type, params.quality.unwrap_or(90), 3)
+        .process(sprite)
+        .await?;
+    let data = result.get_buffer().await?;
+    let items = params
+        .files
+        .into_iter()
+        .zip(positions)
+        .map(|(file, position)| SpriteItem { file, position })
+        .collect();
+    Ok(Json(ImageSpriteResult {
+        width,
+        height,
+        size: data.len(),
+        output_type: result.ext,
+        data: general_purpose::STANDARD.encode(data),
+        items,
+    }))
 }
 
 async fn handle(params: OptimImageParams) -> HTTPResult<OptimResult> {
-    let desc = params.description();
-    pipeline(desc).await
+    pipeline(params.tasks()).await
+}
+
+// 校验api key是否允许执行该任务
+fn check_allowed(task: &str, output_type: Option<OutputType>) -> HTTPResult<()> {
+    let name = API_KEY
+        .try_with(clone_value_from_task_local)
+        .unwrap_or_default();
+    if name.is_empty() {
+        return Ok(());
+    }
+    api_key::check_allowed(&name, task, output_type)
+        .map_err(|message| HTTPError::new_with_category_status(&message, "forbidden", 403))
+}
+
+// 校验api key是否允许执行所有任务
+fn check_tasks_allowed(tasks: &[Task]) -> HTTPResult<()> {
+    for task in tasks.iter() {
+        let output_type = match task {
+            // 加载总是允许，本地文件需校验路径与访问权限
+            Task::Load { data, .. } => {
+                resolve_file_url(data.clone())?;
+                continue;
+            }
+            Task::Watermark { url, .. } | Task::Composite { url, .. } => {
+                resolve_file_url(url.clone())?;
+                None
+            }
+            Task::Watermarks { items } => {
+                for item in items.iter() {
+                    resolve_file_url(item.url.clone())?;
+                }
+                None
+            }
+            Task::Optim { output_type, .. } => *output_type,
+            _ => None,
+        };
+        check_allowed(task.name(), output_type)?;
+    }
+    Ok(())
 }
 
-async fn pipeline(desc: Vec<Vec<String>>) -> HTTPResult<OptimResult> {
-    let process_img = imageoptimize::run(desc).await?;
+// 本地文件转换为图片目录中的实际地址，其它地址不变
+fn resolve_file_url(url: String) -> HTTPResult<String> {
+    match url.strip_prefix("file://") {
+        Some(file) => get_local_file_url(file),
+        None => Ok(url),
+    }
+}
+
+// 加载、水印与合成的本地文件均转换为图片目录中的实际地址，绝对路径也相对于图片目录
+fn resolve_file_urls(tasks: Vec<Task>) -> HTTPResult<Vec<Task>> {
+    tasks
+        .into_iter()
+        .map(|task| {
+            let task = match task {
+                Task::Load {
+                    data,
+                    ext,
+                    page,
+                    bytes,
+                } => Task::Load {
+                    data: resolve_file_url(data)?,
+                    ext,
+                    page,
+                    bytes,
+                },
+                Task::Watermark {
+                    url,
+                    position,
+                    margin_left,
+                    margin_top,
+                } => Task::Watermark {
+                    url: resolve_file_url(url)?,
+                    position,
+                    margin_left,
+                    margin_top,
+                },
+                Task::Watermarks { items } => Task::Watermarks {
+                    items: items
+                        .into_iter()
+                        .map(|mut item| {
+                            item.url = resolve_file_url(item.url)?;
+                            Ok(item)
+                        })
+                        .collect::<HTTPResult<_>>()?,
+                },
+                Task::Composite {
+                    url,
+                    x,
+                    y,
+                    blend,
+                    opacity,
+                } => Task::Composite {
+                    url: resolve_file_url(url)?,
+                    x,
+                    y,
+                    blend,
+                    opacity,
+                },
+                task => task,
+            };
+            Ok(task)
+        })
+        .collect()
+}
 
-    let data = process_img.get_buffer()?;
-    let mut ratio = 0;
-    if process_img.original_size > 0 {
-        ratio = 100 * data.len() / process_img.original_size;
+// 处理未完成时future被drop(客户端断开连接或超时)，记录为中止
+struct AbortGuard {
+    input: String,
+    output: String,
+    finished: bool,
+}
+
+impl AbortGuard {
+    fn new(tasks: &[Task]) -> Self {
+        // 未加载无法得知原图格式，根据指定的类型或地址的扩展名判断
+        let input = tasks
+            .iter()
+            .find_map(|task| match task {
+                Task::Load { ext, .. } if !ext.is_empty() => Some(ext.clone()),
+                Task::Load { data, .. }
+                    if data.starts_with("http") || data.starts_with("file://") =>
+                {
+                    let path = data.split(['?', '#']).next().unwrap_or_default();
+                    path.rsplit_once('.').map(|(_, ext)| ext.to_lowercase())
+                }
+                _ => None,
+            })
+            .map(|ext| {
+                ext.parse::<OutputType>()
+                    .map(|value| value.as_str().to_string())
+                    .unwrap_or(ext)
+            })
+            .unwrap_or_else(|| "unknown".to_string());
+        let output = tasks
+            .iter()
+            .find_map(|task| match task {
+                Task::Optim {
+                    output_type: Some(output_type),
+                    ..
+                } => Some(output_type.as_str().to_string()),
+                _ => None,
+            })
+            .unwrap_or_else(|| input.clone());
+        Self {
+            input,
+            output,
+            finished: false,
+        }
     }
+}
+
+impl Drop for AbortGuard {
+    fn drop(&mut self) {
+        if !self.finished {
+            stats::add_aborted(&self.input, &self.output);
+            info!(
+                input = self.input,
+                output = self.output,
+                "Processing is aborted"
+            );
+        }
+    }
+}
+
+pub(crate) async fn pipeline(tasks: Vec<Task>) -> HTTPResult<OptimResult> {
+    // 默认任务需要与请求的任务一样校验并解析文件地址
+    let tasks = add_default_tasks(tasks);
+    check_tasks_allowed(&tasks)?;
+    let tasks = resolve_file_urls(tasks)?;
+    let name = tasks
+        .iter()
+        .find_map(|task| match task {
+            Task::Load { data, .. } => Some(get_source_name(data)),
+            _ => None,
+        })
+        .unwrap_or_default();
+    let tasks = check_overload(tasks)?;
+    let audited: Vec<Task> = tasks
+        .iter()
+        .filter(|task| audit::is_audited(task))
+        .cloned()
+        .collect();
+    let source = tasks
+        .iter()
+        .find_map(|task| match task {
+            Task::Load { data, .. } if data.starts_with("http") || data.starts_with("file://") => {
+                Some(data.clone())
+            }
+            Task::Load { .. } => Some("base64".to_string()),
+            _ => None,
+        })
+        .unwrap_or_default();
+    let mut abort_guard = AbortGuard::new(&tasks);
+    let started_at = Instant::now();
+    let result = state::scope_job(image_processing::run(tasks)).await;
+    abort_guard.finished = true;
+    let result = match result {
+        Ok(process_img) => process_img
+            .get_buffer()
+            .await
+            .map(|data| (process_img, data))
+            .map_err(HTTPError::from),
+        Err(e) => Err(HTTPError::from(e)),
+    };
+    if !audited.is_empty() {
+        let trace_id = TRACE_ID.with(clone_value_from_task_local);
+        let api_key = API_KEY
+            .try_with(clone_value_from_task_local)
+            .unwrap_or_default();
+        audit::record(&audit::AuditRecord {
+            trace_id: &trace_id,
+            api_key: &api_key,
+            file: &source,
+            operations: &audited,
+            hash: result.as_ref().ok().map(|(_, data)| audit::get_hash(data)),
+            error: result.as_ref().err().map(|e| e.message.as_str()),
+        });
+    }
+    let (process_img, data) = result?;
+    add_usage(&process_img);
+
+    let ratio = (100 * data.len())
+        .checked_div(process_img.original_size)
+        .unwrap_or_default();
+    stats::add(stats::Processed {
+        input: &process_img.source_ext,
+        output: &process_img.ext,
+        original_size: process_img.original_size,
+        size: data.len(),
+        ratio,
+        diff: process_img.diff,
+        latency: started_at.elapsed(),
+    });
+    tl_info!(
+        category = "stats",
+        original_size = process_img.original_size,
+        size = data.len(),
+        peak_memory = process_img.peak_memory,
+        retries = process_img.report.retries,
+    );
 
     Ok(OptimResult {
         diff: process_img.diff,
         ratio,
         data,
         output_type: process_img.ext,
+        passthrough: process_img.passthrough,
+        encoding: process_img.encoding,
+        original_size: process_img.original_size,
+        report: process_img.report,
+        name,
+        source_headers: process_img.source_headers,
+        content: process_img.content,
     })
 }
 
+// 处理中的任务、像素或内存超过限制时拒绝请求，或者降低质量处理
+fn is_overloaded() -> bool {
+    let max_processing = config::get_max_processing();
+    let max_pixels = config::get_max_processing_pixels();
+    let max_memory = config::get_max_memory();
+    (max_processing != 0 && state::get_processing() >= max_processing)
+        || (max_pixels != 0 && state::get_processing_pixels() >= max_pixels)
+        || (max_memory != 0 && state::get_memory_usage().unwrap_or_default() >= max_memory)
+}
+
+fn new_overload_error() -> HTTPError {
+    HTTPError::new_with_category_status("server is overloaded", "overload", 503)
+}
+
+// 分析类的处理无法降级，过载时直接出错
+fn check_analysis_overload() -> HTTPResult<()> {
+    if is_overloaded() {
+        return Err(new_overload_error());
+    }
+    Ok(())
+}
+
+fn check_overload(mut tasks: Vec<Task>) -> HTTPResult<Vec<Task>> {
+    if !is_overloaded() {
+        return Ok(tasks);
+    }
+    if !config::is_overload_downgrade() {
+        return Err(new_overload_error());
+    }
+    tl_info!(category = "overload", "Downgrade the quality of processing");
+    for task in tasks.iter_mut() {
+        if let Task::Optim {
+            quality,
+            speed,
+            options,
+            ..
+        } = task
+        {
+            // 质量预设则使用其jpeg的质量降低处理
+            if let Some(preset) = options.preset.take() {
+                *quality = config::get_quality_preset(preset.as_str(), "jpeg").unwrap_or(*quality);
+            }
+            *quality = quality.saturating_sub(20).max(40);
+            *speed = 10;
+        }
+    }
+    Ok(tasks)
+}
+
+// 根据加载地址获取图片名称，base64数据则为空
+fn get_source_name(data: &str) -> String {
+    let file = if let Some(file) = data.strip_prefix("file://") {
+        // 本地文件的路径未编码，不能再解码(如文件名包含%)
+        file.rsplit('/').next().unwrap_or_default().to_string()
+    } else if data.starts_with("http") {
+        let path = data.split(['?', '#']).next().unwrap_or_default();
+        let file = path.rsplit('/').next().unwrap_or_default();
+        decode(file)
+            .map(|value| value.to_string())
+            .unwrap_or_default()
+    } else {
+        return "".to_string();
+    };
+    file.rsplit_once('.')
+        .map(|(stem, _)| stem.to_string())
+        .unwrap_or(file)
+}
+
 async fn optim_image_preview(
-    Query(params): Query<OptimImageParams>,
-) -> ResponseResult<images::ImagePreview> {
-    let result = handle(params).await?;
+    Query(mut params): Query<OptimImageParams>,
+    headers: HeaderMap,
+) -> ResponseResult<Response> {
+    let options = PreviewOptions {
+        fallback: params.fallback.unwrap_or_default(),
+        debug: get_debug_mode(params.debug.take(), &headers)?,
+        download: params.download.take(),
+        cache_control: get_cache_control(params.cache_control.take())?,
+        json: is_json_format(params.format.take().as_deref())?,
+    };
+    let result = handle(params).await;
 
-    Ok(images::ImagePreview {
-        ratio: result.ratio,
-        diff: result.diff,
-        data: result.data,
-        image_type: result.output_type,
-    })
+    preview_response(result, options, &headers)
+}
+
+// 请求体为图片数据(无需base64)，处理参数为查询参数
+async fn optim_image_body(
+    Query(mut params): Query<OptimImageParams>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> ResponseResult<Response> {
+    if body.is_empty() {
+        return Err(HTTPError::new("data is empty", "invalid"));
+    }
+    let content_type = get_header_value(&headers, header::CONTENT_TYPE).unwrap_or_default();
+    let mime = content_type.split(';').next().unwrap_or_default().trim();
+    if let Some(sub_type) = mime.strip_prefix("image/") {
+        // 如image/svg+xml
+        let ext = sub_type.split('+').next().unwrap_or_default();
+        if params.data_type.is_none() && !ext.is_empty() {
+            params.data_type = Some(ext.to_string());
+        }
+    } else if !mime.is_empty() && mime != "application/octet-stream" {
+        return Err(HTTPError::new_with_category_status(
+            &format!("content type({mime}) is not support"),
+            "validate",
+            415,
+        ));
+    }
+    // 直接使用提交的数据加载，无需base64编码
+    params.bytes = Some(Arc::new(body.into()));
+    optim_image_preview(Query(params), headers).await
 }
 
 async fn optim_image(
     Json(params): Json<OptimImageParams>,
 ) -> ResponseResult<Json<OptimImageResult>> {
     let result = handle(params).await?;
-    Ok(Json(OptimImageResult {
-        diff: result.diff,
-        ratio: result.ratio,
-        data: general_purpose::STANDARD.encode(result.data),
-        output_type: result.output_type,
-    }))
+    Ok(Json(OptimImageResult::from(result)))
+}
+
+#[derive(Deserialize)]
+struct BulkRequest {
+    id: String,
+    #[serde(flatten)]
+    params: OptimImageParams,
+}
+
+#[derive(Serialize)]
+struct BulkResult {
+    id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<HTTPError>,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    output_type: String,
+    size: usize,
+    ratio: usize,
+    diff: f64,
+}
+
+// 批量处理的消息格式：4字节(大端)的json头部长度 + json头部 + 图片数据
+fn new_bulk_message(result: BulkResult, data: &[u8]) -> Vec<u8> {
+    let header = serde_json::to_vec(&result).unwrap_or_default();
+    let mut message = Vec::with_capacity(4 + header.len() + data.len());
+    message.extend_from_slice(&(header.len() as u32).to_be_bytes());
+    message.extend_from_slice(&header);
+    message.extend_from_slice(data);
+    message
 }
 
-fn convert_query_to_desc(query: Option<String>) -> Result<Vec<Vec<String>>, HTTPError> {
+async fn process_bulk_message(message: Vec<u8>) -> Vec<u8> {
+    let mut id = "".to_string();
+    let result = async {
+        let (size, rest) = message
+            .split_first_chunk::<4>()
+            .ok_or_else(|| HTTPError::new("message is invalid", "validate"))?;
+        let size = u32::from_be_bytes(*size) as usize;
+        if size > rest.len() {
+            return Err(HTTPError::new("message header is invalid", "validate"));
+        }
+        let (header, data) = rest.split_at(size);
+        let mut request: BulkRequest = serde_json::from_slice(header)
+            .map_err(|e| HTTPError::new(&e.to_string(), "validate"))?;
+        id = request.id;
+        // 连接时仅校验了一次，每个请求均需校验配额
+        let name = API_KEY
+            .try_with(clone_value_from_task_local)
+            .unwrap_or_default();
+        if let Some(api_key) = api_key::get_by_name(&name) {
+            api_key::check_request(api_key)
+                .map_err(|message| HTTPError::new_with_category_status(&message, "quota", 429))?;
+        }
+        // 无图片数据时使用头部中的data(如图片地址)
+        if !data.is_empty() {
+            request.params.bytes = Some(Arc::new(data.to_vec()));
+        }
+        handle(request.params).await
+    }
+    .await;
+    match result {
+        Ok(result) => new_bulk_message(
+            BulkResult {
+                id,
+                error: None,
+                output_type: result.output_type,
+                size: result.data.len(),
+                ratio: result.ratio,
+                diff: result.diff,
+            },
+            &result.data,
+        ),
+        Err(e) => new_bulk_message(
+            BulkResult {
+                id,
+                error: Some(e),
+                output_type: "".to_string(),
+                size: 0,
+                ratio: 0,
+                diff: -1.0,
+            },
+            &[],
+        ),
+    }
+}
+
+// 同一连接中的请求并发处理，完成后即返回结果，因此返回顺序与请求顺序不一定一致
+// 新的task需要设置trace id、api key与存储
+fn with_task_locals<F: Future>(f: F) -> impl Future<Output = F::Output> {
+    let trace_id = TRACE_ID.with(clone_value_from_task_local);
+    let api_key = API_KEY
+        .try_with(clone_value_from_task_local)
+        .unwrap_or_default();
+    // 未指定存储则使用默认的图片目录
+    let storage = STORAGE
+        .try_with(clone_value_from_task_local)
+        .unwrap_or_else(|_| OPTIM_PATH.to_string());
+    TRACE_ID.scope(trace_id, API_KEY.scope(api_key, STORAGE.scope(storage, f)))
+}
+
+async fn handle_bulk_socket(mut socket: WebSocket) {
+    let max_concurrency = config::get_bulk_max_concurrency().max(1);
+    let mut tasks = JoinSet::new();
+    // 服务停止时不再接收新的消息，处理中的完成后关闭连接
+    let mut stopping = false;
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        tokio::select! {
+            _ = interval.tick(), if !stopping => {
+                stopping = state::is_stopping();
+            }
+            message = socket.recv(), if !stopping && tasks.len() < max_concurrency => {
+                let message = match message {
+                    Some(Ok(Message::Binary(message))) => message,
+                    // ping与pong已自动处理，文本消息忽略
+                    Some(Ok(Message::Text(_) | Message::Ping(_) | Message::Pong(_))) => continue,
+                    _ => break,
+                };
+                tasks.spawn(with_task_locals(process_bulk_message(message)));
+            }
+            Some(result) = tasks.join_next() => {
+                let message = match result {
+                    Ok(message) => message,
+                    Err(e) => {
+                        tracing::error!("Bulk process fail, {e}");
+                        continue;
+                    }
+                };
+                if socket.send(Message::Binary(message)).await.is_err() {
+                    break;
+                }
+            }
+            else => break,
+        }
+    }
+    if stopping {
+        let _ = socket
+            .send(Message::Close(Some(CloseFrame {
+                code: close_code::AWAY,
+                reason: "server is stopping".into(),
+            })))
+            .await;
+    }
+}
+
+async fn handle_bulk(ws: WebSocketUpgrade) -> Response {
+    let trace_id = TRACE_ID.with(clone_value_from_task_local);
+    let api_key = API_KEY
+        .try_with(clone_value_from_task_local)
+        .unwrap_or_default();
+    let storage = STORAGE
+        .try_with(clone_value_from_task_local)
+        .unwrap_or_else(|_| OPTIM_PATH.to_string());
+    // 预留json头部的大小
+    ws.max_message_size(config::get_max_upload_bytes() + 64 * 1024)
+        .on_upgrade(move |socket| {
+            TRACE_ID.scope(
+                trace_id,
+                API_KEY.scope(api_key, STORAGE.scope(storage, handle_bulk_socket(socket))),
+            )
+        })
+}
+
+const PREVIEW_PARAMS: [&str; 5] = ["fallback", "debug", "download", "cache_control", "format"];
+
+fn convert_query_to_tasks(query: Option<String>) -> Result<Vec<Task>, HTTPError> {
     let desc = query.ok_or_else(|| HTTPError::new("params is null", "validate"))?;
     let sep = "&";
     let arr = desc.split(sep);
     let mut result = Vec::new();
     for str in arr {
         let items: Vec<_> = str.split('=').collect();
-        if items.len() != 2 {
+        // 预览的参数非处理任务
+        if items.len() != 2 || PREVIEW_PARAMS.contains(&items[0]) {
             continue;
         }
         let value = decode(items[1])?.to_string();
@@ -187,66 +1819,320 @@ fn convert_query_to_desc(query: Option<String>) -> Result<Vec<Vec<String>>, HTTP
         }
         result.push(params);
     }
-    Ok(result)
+    Ok(image_processing::parse_tasks(result)?)
 }
 
 async fn pipeline_image(RawQuery(query): RawQuery) -> ResponseResult<Json<OptimImageResult>> {
-    let desc = convert_query_to_desc(query)?;
+    let tasks = convert_query_to_tasks(query)?;
 
-    let result = pipeline(desc).await?;
+    let result = pipeline(tasks).await?;
 
-    Ok(Json(OptimImageResult {
-        diff: result.diff,
-        ratio: result.ratio,
-        data: general_purpose::STANDARD.encode(result.data),
-        output_type: result.output_type,
-    }))
+    Ok(Json(OptimImageResult::from(result)))
 }
-async fn pipeline_image_preview(RawQuery(query): RawQuery) -> ResponseResult<images::ImagePreview> {
-    let desc = convert_query_to_desc(query)?;
+async fn pipeline_image_preview(
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
+) -> ResponseResult<Response> {
+    let get_value = |key: &str| {
+        query
+            .as_deref()
+            .unwrap_or_default()
+            .split('&')
+            .find_map(|item| item.strip_prefix(&format!("{key}=")))
+            .map(|value| value.to_string())
+    };
+    let options = PreviewOptions {
+        fallback: get_value("fallback").as_deref() == Some("true"),
+        debug: get_debug_mode(get_value("debug"), &headers)?,
+        download: get_value("download")
+            .map(|value| decode(&value).map(|value| value.to_string()))
+            .transpose()?,
+        cache_control: get_cache_control(
+            get_value("cache_control")
+                .map(|value| decode(&value).map(|value| value.to_string()))
+                .transpose()?,
+        )?,
+        json: is_json_format(get_value("format").as_deref())?,
+    };
+    let tasks = convert_query_to_tasks(query)?;
 
-    let result = pipeline(desc).await?;
-    Ok(images::ImagePreview {
-        ratio: result.ratio,
-        diff: result.diff,
-        data: result.data,
-        image_type: result.output_type,
-    })
+    let result = pipeline(tasks).await;
+    preview_response(result, options, &headers)
+}
+
+#[derive(Deserialize, Default, Debug)]
+struct ValidateParams {
+    // pipeline的查询参数，如load=...&resize=...
+    query: Option<String>,
+    // 任务列表，优先于query
+    tasks: Option<Vec<Task>>,
+}
+
+#[derive(Serialize, Debug)]
+struct ValidateResult {
+    tasks: Vec<Task>,
+}
+
+// 仅解析与校验任务(不加载图片)，返回添加默认任务后的完整任务列表
+async fn validate_pipeline(
+    Json(params): Json<ValidateParams>,
+) -> ResponseResult<Json<ValidateResult>> {
+    let tasks = match params.tasks {
+        Some(tasks) => tasks,
+        None => convert_query_to_tasks(params.query)?,
+    };
+    let tasks = add_default_tasks(tasks);
+    check_tasks_allowed(&tasks)?;
+    image_processing::validate_tasks(&tasks)?;
+    Ok(Json(ValidateResult { tasks }))
+}
+
+// 质量参数，可为数值或预设名称(如high)
+#[derive(Debug, Clone, Copy)]
+enum QualityParam {
+    Value(u8),
+    Preset(QualityPreset),
+}
+
+impl std::str::FromStr for QualityParam {
+    type Err = HTTPError;
+    fn from_str(value: &str) -> HTTPResult<Self> {
+        if let Some(preset) = QualityPreset::from_name(value) {
+            return Ok(QualityParam::Preset(preset));
+        }
+        value
+            .parse()
+            .map(QualityParam::Value)
+            .map_err(|_| HTTPError::new(&format!("quality({value}) is invalid"), "validate"))
+    }
+}
+
+// json为数值或字符串，查询参数则为字符串
+impl<'de> Deserialize<'de> for QualityParam {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Value(u8),
+            Name(String),
+        }
+        match Raw::deserialize(deserializer)? {
+            Raw::Value(value) => Ok(QualityParam::Value(value)),
+            Raw::Name(name) => name
+                .parse()
+                .map_err(|e: HTTPError| serde::de::Error::custom(e.message)),
+        }
+    }
 }
 
 #[derive(Deserialize, Default, Debug)]
 struct OptimImageParams {
+    // 直接提交图片数据时为空
+    #[serde(default)]
     data: String,
+    // 直接提交的图片数据，无需base64编码
+    #[serde(skip)]
+    bytes: Option<Arc<Vec<u8>>>,
     data_type: Option<String>,
-    output_type: Option<String>,
-    quality: Option<u8>,
+    // 多页tiff的页，从1开始
+    page: Option<u32>,
+    output_type: Option<OutputType>,
+    quality: Option<QualityParam>,
+    max_diff: Option<f64>,
     speed: Option<u8>,
     diff: Option<bool>,
+    fallback: Option<bool>,
+    debug: Option<String>,
+    download: Option<String>,
+    cache_control: Option<String>,
+    format: Option<String>,
+    progressive: Option<bool>,
+    chroma_subsampling: Option<ChromaSubsampling>,
+    interlace: Option<Interlace>,
+    compression_level: Option<u8>,
+    png_filter: Option<PngFilter>,
+    bit_depth: Option<u8>,
+    alpha_quality: Option<u8>,
+    crf: Option<u8>,
+    bitrate: Option<u32>,
+    force: Option<bool>,
+    animated: Option<AnimatedFrame>,
+    effect: Option<Effect>,
+    enhance: Option<bool>,
+    gamma: Option<f32>,
 }
 impl OptimImageParams {
-    // to processing description string
-    pub fn description(self) -> Vec<Vec<String>> {
-        let load_process = vec![
-            imageoptimize::PROCESS_LOAD.to_string(),
-            self.data,
-            self.data_type.unwrap_or_default(),
-        ];
-
-        let quality = self.quality.unwrap_or(80);
-        let speed = self.speed.unwrap_or(3);
-
-        let optim_process = vec![
-            imageoptimize::PROCESS_OPTIM.to_string(),
-            self.output_type.unwrap_or_default(),
-            quality.to_string(),
-            speed.to_string(),
-        ];
-
-        let mut arr = vec![load_process, optim_process];
+    // to processing tasks
+    pub fn tasks(self) -> Vec<Task> {
+        let mut tasks = vec![Task::Load {
+            data: self.data,
+            ext: self.data_type.unwrap_or_default(),
+            page: self.page.unwrap_or_default(),
+            bytes: self.bytes,
+        }];
+        let levels = self.enhance.unwrap_or_default();
+        if levels || self.gamma.is_some() {
+            tasks.push(Task::Enhance {
+                levels,
+                gamma: self.gamma,
+            });
+        }
+        if let Some(effect) = self.effect {
+            tasks.push(Task::Effect { effect });
+        }
+        tasks.push(Task::Optim {
+            output_type: self.output_type,
+            quality: match self.quality {
+                Some(QualityParam::Value(quality)) => quality,
+                _ => 80,
+            },
+            speed: self.speed.unwrap_or(3),
+            options: EncoderOptions {
+                progressive: self.progressive,
+                chroma_subsampling: self.chroma_subsampling,
+                interlace: self.interlace,
+                compression_level: self.compression_level,
+                png_filter: self.png_filter,
+                bit_depth: self.bit_depth,
+                alpha_quality: self.alpha_quality,
+                crf: self.crf,
+                bitrate: self.bitrate,
+                force: self.force,
+                animated: self.animated,
+                max_diff: self.max_diff,
+                preset: match self.quality {
+                    Some(QualityParam::Preset(preset)) => Some(preset),
+                    _ => None,
+                },
+            },
+        });
         if self.diff.unwrap_or_default() {
-            arr.push(vec![imageoptimize::PROCESS_DIFF.to_string()]);
+            tasks.push(Task::Diff);
         }
 
-        arr
+        tasks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitize_file_path_trims_leading_slash() {
+        assert_eq!("a/b.png", sanitize_file_path("/a/b.png").unwrap());
+        assert_eq!("a/b.png", sanitize_file_path("a/b.png").unwrap());
+        assert!(sanitize_file_path("/").is_err());
+        assert!(sanitize_file_path("").is_err());
+    }
+
+    #[test]
+    fn sanitize_file_path_rejects_dot_segments() {
+        for file in [
+            "../etc/passwd",
+            "/a/../../etc/passwd",
+            "/a/./b.png",
+            "/a/..",
+            "/%2e%2e/etc/passwd",
+            "/%252e%252e/etc/passwd",
+        ] {
+            assert!(sanitize_file_path(file).is_err(), "{file}");
+        }
+    }
+
+    #[test]
+    fn sanitize_file_path_rejects_double_slash() {
+        for file in ["//etc/passwd", "/a//b.png", "/a/%2F/b.png"] {
+            assert!(sanitize_file_path(file).is_err(), "{file}");
+        }
+    }
+
+    #[test]
+    fn sanitize_file_path_rejects_backslash() {
+        for file in [
+            "/a\\b.png",
+            "/..\\etc\\passwd",
+            "/a%5Cb.png",
+            "/a%255Cb.png",
+        ] {
+            assert!(sanitize_file_path(file).is_err(), "{file}");
+        }
+    }
+
+    #[test]
+    fn sanitize_file_path_rejects_control_characters() {
+        for file in [
+            "/a\0b.png",
+            "/a\nb.png",
+            "/a%00b.png",
+            "/a%0Ab.png",
+            "/a%2500b.png",
+        ] {
+            assert!(sanitize_file_path(file).is_err(), "{file}");
+        }
+    }
+
+    #[test]
+    fn resolve_file_urls_in_root() {
+        let tasks = vec![Task::Load {
+            data: "file:///etc/passwd".to_string(),
+            ext: "".to_string(),
+            page: 0,
+            bytes: None,
+        }];
+        let tasks = STORAGE
+            .sync_scope("/data/images".to_string(), || resolve_file_urls(tasks))
+            .unwrap();
+        let Task::Load { data, .. } = &tasks[0] else {
+            panic!("load task is expected");
+        };
+        assert_eq!("file:///data/images/etc/passwd", data);
+
+        for file in [
+            "/../etc/passwd",
+            "/a/../../etc/passwd",
+            "/%2e%2e/etc/passwd",
+        ] {
+            let tasks = vec![Task::Load {
+                data: format!("file://{file}"),
+                ext: "".to_string(),
+                page: 0,
+                bytes: None,
+            }];
+            let result = STORAGE.sync_scope("/data/images".to_string(), || {
+                check_tasks_allowed(&tasks)?;
+                resolve_file_urls(tasks)
+            });
+            assert!(result.is_err(), "{file} should be rejected");
+        }
+    }
+
+    #[test]
+    fn resolve_file_urls_of_watermark() {
+        let tasks = vec![Task::Composite {
+            url: "file:///etc/passwd".to_string(),
+            x: 0,
+            y: 0,
+            blend: Default::default(),
+            opacity: 100,
+        }];
+        let tasks = STORAGE
+            .sync_scope("/data/images".to_string(), || resolve_file_urls(tasks))
+            .unwrap();
+        let Task::Composite { url, .. } = &tasks[0] else {
+            panic!("composite task is expected");
+        };
+        assert_eq!("file:///data/images/etc/passwd", url);
+    }
+
+    #[test]
+    fn resolve_file_urls_without_root() {
+        let tasks = vec![Task::Load {
+            data: "file:///etc/passwd".to_string(),
+            ext: "".to_string(),
+            page: 0,
+            bytes: None,
+        }];
+        assert!(resolve_file_urls(tasks).is_err());
     }
 }
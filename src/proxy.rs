@@ -0,0 +1,61 @@
+use crate::config;
+use crate::error::{HTTPError, HTTPResult};
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+static PROXY_HOSTS: Lazy<Vec<String>> = Lazy::new(config::get_proxy_hosts);
+
+// 各host当前分钟(unix时间)的请求数
+static REQUESTS: Lazy<Mutex<HashMap<String, (u64, u32)>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Whether the file is a remote url to be proxied.
+pub fn is_proxy_url(file: &str) -> bool {
+    file.starts_with("http://") || file.starts_with("https://")
+}
+
+/// Check the host of url is allowed to be proxied and count the request of the host,
+/// it returns 429 if the rate limit of the host is exceeded.
+pub fn check(url: &str) -> HTTPResult<()> {
+    let forbidden = |message: &str| HTTPError::new_with_category_status(message, "forbidden", 403);
+    if PROXY_HOSTS.is_empty() {
+        return Err(forbidden("proxy is not enabled"));
+    }
+    let url = reqwest::Url::parse(url).map_err(|e| HTTPError::new(&e.to_string(), "validate"))?;
+    let host = url.host_str().unwrap_or_default().to_lowercase();
+    let host_port = url.port().map(|port| format!("{host}:{port}"));
+    // host以.开头则匹配其子域名
+    let allowed = !host.is_empty()
+        && PROXY_HOSTS.iter().any(|name| {
+            *name == host
+                || Some(name) == host_port.as_ref()
+                || (name.starts_with('.') && host.ends_with(name.as_str()))
+        });
+    if !allowed {
+        return Err(forbidden(&format!("host({host}) is not allowed to proxy")));
+    }
+
+    let limit = config::get_proxy_rate_limit();
+    if limit == 0 {
+        return Ok(());
+    }
+    let minute = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / 60;
+    let mut requests = REQUESTS.lock().unwrap_or_else(|e| e.into_inner());
+    requests.retain(|_, (value, _)| *value == minute);
+    let (_, count) = requests.entry(host.clone()).or_insert((minute, 0));
+    if *count >= limit {
+        return Err(HTTPError::new_with_category_status(
+            &format!("rate limit of proxied host({host}) is exceeded"),
+            "quota",
+            429,
+        ));
+    }
+    *count += 1;
+    Ok(())
+}
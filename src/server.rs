@@ -0,0 +1,251 @@
+use axum::extract::Request;
+use axum::response::Response;
+use axum::Router;
+use hyper::body::Incoming;
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use hyper_util::server::conn::auto::Builder;
+use image_optim::config;
+use std::convert::Infallible;
+use std::fs::File;
+use std::future::Future;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::rustls::{self, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+use tower::Service;
+
+fn invalid_data(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+// 读取pem格式的证书链与私钥
+fn load_certified_key(cert: &str, key: &str) -> std::io::Result<CertifiedKey> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(invalid_data(format!("no certificate found in {cert}")));
+    }
+    let private_key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key)?))?
+        .ok_or_else(|| invalid_data(format!("no private key found in {key}")))?;
+    let signing_key = rustls::crypto::ring::sign::any_supported_type(&private_key)
+        .map_err(|e| invalid_data(e.to_string()))?;
+    Ok(CertifiedKey::new(certs, signing_key))
+}
+
+// 证书与私钥文件的修改时间，变化时重新加载
+fn get_modified(cert: &str, key: &str) -> Option<(SystemTime, SystemTime)> {
+    let modified = |file: &str| {
+        std::fs::metadata(file)
+            .and_then(|meta| meta.modified())
+            .ok()
+    };
+    Some((modified(cert)?, modified(key)?))
+}
+
+// 重新加载后新的连接使用新证书，已建立的连接不受影响
+#[derive(Debug)]
+struct CertResolver {
+    certified_key: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.certified_key.read().ok().map(|value| value.clone())
+    }
+}
+
+fn new_tls_acceptor(cert: String, key: String) -> std::io::Result<TlsAcceptor> {
+    let resolver = Arc::new(CertResolver {
+        certified_key: RwLock::new(Arc::new(load_certified_key(&cert, &key)?)),
+    });
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut tls_config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| invalid_data(e.to_string()))?
+        .with_no_client_auth()
+        .with_cert_resolver(resolver.clone());
+    tls_config.alpn_protocols = if config::is_http2_enabled() {
+        vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+    } else {
+        vec![b"http/1.1".to_vec()]
+    };
+
+    let interval = config::get_tls_reload_interval();
+    if !interval.is_zero() {
+        tokio::spawn(async move {
+            let mut modified = get_modified(&cert, &key);
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                let current = get_modified(&cert, &key);
+                if current.is_none() || current == modified {
+                    continue;
+                }
+                match load_certified_key(&cert, &key) {
+                    Ok(value) => {
+                        if let Ok(mut certified_key) = resolver.certified_key.write() {
+                            *certified_key = Arc::new(value);
+                        }
+                        modified = current;
+                        tracing::info!(cert, "Tls certificate is reloaded");
+                    }
+                    // 证书与私钥可能未同时更新完成，下次再尝试
+                    Err(e) => tracing::error!(cert, "Reload tls certificate fail, {e}"),
+                }
+            }
+        });
+    }
+    Ok(TlsAcceptor::from(Arc::new(tls_config)))
+}
+
+// 根据配置调整http1与http2的连接参数，0表示使用默认值或不启用
+fn new_builder() -> Builder<TokioExecutor> {
+    let mut builder = Builder::new(TokioExecutor::new());
+    if !config::is_http2_enabled() {
+        builder = builder.http1_only();
+    }
+    let header_read_timeout = config::get_http1_header_read_timeout();
+    builder
+        .http1()
+        .timer(TokioTimer::new())
+        .keep_alive(config::is_http1_keep_alive())
+        .header_read_timeout(Some(header_read_timeout).filter(|value| !value.is_zero()));
+    let max_concurrent_streams = config::get_http2_max_concurrent_streams();
+    let keep_alive_interval = config::get_http2_keep_alive_interval();
+    builder
+        .http2()
+        .timer(TokioTimer::new())
+        .max_concurrent_streams(Some(max_concurrent_streams).filter(|value| *value != 0))
+        .keep_alive_interval(Some(keep_alive_interval).filter(|value| !value.is_zero()))
+        .keep_alive_timeout(config::get_http2_keep_alive_timeout());
+    builder
+}
+
+async fn serve_connection<I, S>(
+    builder: &Builder<TokioExecutor>,
+    io: I,
+    service: S,
+    mut signal_rx: watch::Receiver<()>,
+) where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Service<Request<Incoming>, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    let service =
+        hyper::service::service_fn(move |request: Request<Incoming>| service.clone().call(request));
+    let conn = builder.serve_connection_with_upgrades(TokioIo::new(io), service);
+    tokio::pin!(conn);
+    let result = tokio::select! {
+        result = conn.as_mut() => result,
+        _ = signal_rx.changed() => {
+            conn.as_mut().graceful_shutdown();
+            conn.await
+        }
+    };
+    if let Err(e) = result {
+        tracing::debug!("Serve connection fail, {e}");
+    }
+}
+
+/// Serve the app on the listener until the signal is received,
+/// it is https if the certificate is configured.
+pub async fn serve(
+    listener: TcpListener,
+    app: Router,
+    signal: impl Future<Output = ()>,
+) -> std::io::Result<()> {
+    let cert = config::get_tls_cert();
+    let acceptor = if cert.is_empty() {
+        None
+    } else {
+        Some(new_tls_acceptor(cert, config::get_tls_key())?)
+    };
+    let mut make_service = app.into_make_service_with_connect_info::<SocketAddr>();
+    let builder = Arc::new(new_builder());
+    let nodelay = config::is_tcp_nodelay();
+    let handshake_timeout = config::get_tls_handshake_timeout();
+    // 停止时通知各连接，并等待所有连接关闭
+    let (signal_tx, signal_rx) = watch::channel(());
+    let (close_tx, close_rx) = watch::channel(());
+    tokio::pin!(signal);
+    loop {
+        let (stream, remote) = tokio::select! {
+            result = listener.accept() => match result {
+                Ok(value) => value,
+                Err(e) => {
+                    tracing::error!("Accept connection fail, {e}");
+                    // 如文件句柄不足等错误，稍等再接受连接
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            },
+            _ = &mut signal => break,
+        };
+        if nodelay {
+            if let Err(e) = stream.set_nodelay(true) {
+                tracing::debug!(%remote, "Set tcp nodelay fail, {e}");
+            }
+        }
+        let service = make_service
+            .call(remote)
+            .await
+            .unwrap_or_else(|e| match e {});
+        let acceptor = acceptor.clone();
+        let builder = builder.clone();
+        let mut signal_rx = signal_rx.clone();
+        let close_rx = close_rx.clone();
+        tokio::spawn(async move {
+            match acceptor {
+                Some(acceptor) => {
+                    // 握手需要限制时长，服务停止时也不再等待
+                    let result = tokio::select! {
+                        result = with_timeout(handshake_timeout, acceptor.accept(stream)) => result,
+                        _ = signal_rx.changed() => {
+                            tracing::debug!(%remote, "Tls handshake is canceled by shutdown");
+                            return;
+                        }
+                    };
+                    match result {
+                        Some(Ok(stream)) => {
+                            serve_connection(&builder, stream, service, signal_rx).await
+                        }
+                        Some(Err(e)) => tracing::debug!(%remote, "Tls handshake fail, {e}"),
+                        None => tracing::debug!(%remote, "Tls handshake timeout"),
+                    }
+                }
+                None => serve_connection(&builder, stream, service, signal_rx).await,
+            }
+            drop(close_rx);
+        });
+    }
+    drop(listener);
+    drop(close_rx);
+    let _ = signal_tx.send(());
+    // 等待连接关闭的时长与drain一致，超时则不再等待
+    let drain_timeout = config::get_drain_timeout();
+    if with_timeout(drain_timeout, close_tx.closed())
+        .await
+        .is_none()
+    {
+        tracing::warn!(
+            connections = close_tx.receiver_count(),
+            "Wait for connections closed timeout"
+        );
+    }
+    Ok(())
+}
+
+// 超时则返回None，0表示不限制
+async fn with_timeout<F: Future>(timeout: Duration, f: F) -> Option<F::Output> {
+    if timeout.is_zero() {
+        return Some(f.await);
+    }
+    tokio::time::timeout(timeout, f).await.ok()
+}
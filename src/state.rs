@@ -0,0 +1,154 @@
+use once_cell::sync::Lazy;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::{sleep, Instant};
+
+static STOPPING: AtomicBool = AtomicBool::new(false);
+static PROCESSING: AtomicU32 = AtomicU32::new(0);
+static AVIF_ENCODING: AtomicU32 = AtomicU32::new(0);
+static PROCESSING_PIXELS: AtomicU64 = AtomicU64::new(0);
+static DRAIN: Lazy<Notify> = Lazy::new(Notify::new);
+
+/// Whether the app is stopping, new requests should be routed elsewhere.
+pub fn is_stopping() -> bool {
+    STOPPING.load(Ordering::Relaxed)
+}
+
+pub fn set_stopping() {
+    STOPPING.store(true, Ordering::Relaxed);
+}
+
+/// Count of in-flight pipeline jobs.
+pub fn get_processing() -> u32 {
+    PROCESSING.load(Ordering::Relaxed)
+}
+
+/// Processing guard decreases the in-flight count when dropped.
+pub struct ProcessingGuard {}
+
+/// Increase the in-flight count, the returned guard should be held
+/// until the pipeline job is done.
+pub fn start_processing() -> ProcessingGuard {
+    PROCESSING.fetch_add(1, Ordering::Relaxed);
+    ProcessingGuard {}
+}
+
+impl Drop for ProcessingGuard {
+    fn drop(&mut self) {
+        PROCESSING.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Count of in-flight avif encodes.
+pub fn get_avif_encoding() -> u32 {
+    AVIF_ENCODING.load(Ordering::Relaxed)
+}
+
+/// Avif encoding guard decreases the in-flight count when dropped.
+pub struct AvifEncodingGuard {}
+
+/// Increase the in-flight count of avif encodes.
+pub fn start_avif_encoding() -> AvifEncodingGuard {
+    AVIF_ENCODING.fetch_add(1, Ordering::Relaxed);
+    AvifEncodingGuard {}
+}
+
+impl Drop for AvifEncodingGuard {
+    fn drop(&mut self) {
+        AVIF_ENCODING.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Pixels of the in-flight decoded images.
+pub fn get_processing_pixels() -> u64 {
+    PROCESSING_PIXELS.load(Ordering::Relaxed)
+}
+
+/// Pixels guard decreases the in-flight pixels when dropped.
+pub struct PixelsGuard {
+    pixels: u64,
+}
+
+/// Increase the in-flight pixels, the returned guard should be held
+/// until the image is processed.
+pub fn start_pixels(pixels: u64) -> PixelsGuard {
+    PROCESSING_PIXELS.fetch_add(pixels, Ordering::Relaxed);
+    PixelsGuard { pixels }
+}
+
+impl Drop for PixelsGuard {
+    fn drop(&mut self) {
+        PROCESSING_PIXELS.fetch_sub(self.pixels, Ordering::Relaxed);
+    }
+}
+
+/// Guards of a pipeline job, they are shared with the blocking work of the job.
+pub struct JobGuard {
+    _processing: ProcessingGuard,
+    pixels: OnceLock<PixelsGuard>,
+}
+
+tokio::task_local! {
+    static JOB: Arc<JobGuard>;
+}
+
+/// Run the future as a pipeline job, the in-flight count and pixels are
+/// released after the future and all of its blocking work are done,
+/// the blocking work keeps running even if the future is dropped.
+pub async fn scope_job<F: Future>(f: F) -> F::Output {
+    let job = Arc::new(JobGuard {
+        _processing: start_processing(),
+        pixels: OnceLock::new(),
+    });
+    JOB.scope(job, f).await
+}
+
+/// Get the guard of the current job, it should be moved into the blocking work.
+pub fn current_job() -> Option<Arc<JobGuard>> {
+    JOB.try_with(Arc::clone).ok()
+}
+
+/// Increase the in-flight pixels, the guard is held by the current job,
+/// otherwise the returned guard should be held until the image is processed.
+pub fn start_job_pixels(pixels: u64) -> Option<PixelsGuard> {
+    match JOB.try_with(|job| {
+        job.pixels.get_or_init(|| start_pixels(pixels));
+    }) {
+        Ok(()) => None,
+        Err(_) => Some(start_pixels(pixels)),
+    }
+}
+
+/// Resident memory(bytes) of the process, only supported on linux.
+pub fn get_memory_usage() -> Option<u64> {
+    // statm的第二项为常驻内存的页数
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages = statm.split_whitespace().nth(1)?.parse::<u64>().ok()?;
+    Some(pages * 4096)
+}
+
+/// Wait for all in-flight pipeline jobs to be done,
+/// or the deadline to be exceeded.
+pub async fn wait_for_idle(timeout: Duration) -> u32 {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let count = get_processing();
+        if count == 0 || Instant::now() >= deadline {
+            return count;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+}
+
+/// Trigger the graceful shutdown of server.
+pub fn drain() {
+    DRAIN.notify_one();
+}
+
+/// Wait for the drain to be triggered.
+pub async fn drained() {
+    DRAIN.notified().await;
+}
@@ -0,0 +1,129 @@
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Accumulated values of processing for an input and output format pair.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct FormatStats {
+    pub count: u64,
+    pub original_size: u64,
+    pub size: u64,
+    pub ratio: u64,
+    /// Count of the processing with diff calculated.
+    pub diff_count: u64,
+    pub diff: f64,
+    /// Latency in milliseconds.
+    pub latency: u64,
+    /// Count of the processing aborted before finished, e.g. client disconnected.
+    #[serde(default)]
+    pub aborted: u64,
+}
+
+// key为input:output，如jpeg:avif
+static STATS: Lazy<Mutex<HashMap<String, FormatStats>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Result of the processing to be added to the stats.
+pub struct Processed<'a> {
+    pub input: &'a str,
+    pub output: &'a str,
+    pub original_size: usize,
+    pub size: usize,
+    pub ratio: usize,
+    /// The diff is less than 0 if not calculated.
+    pub diff: f64,
+    pub latency: Duration,
+}
+
+/// Add the result of processing to the stats of its format pair.
+pub fn add(processed: Processed) {
+    let mut stats = STATS.lock().unwrap_or_else(|e| e.into_inner());
+    let item = stats
+        .entry(format!("{}:{}", processed.input, processed.output))
+        .or_default();
+    item.count += 1;
+    item.original_size += processed.original_size as u64;
+    item.size += processed.size as u64;
+    item.ratio += processed.ratio as u64;
+    if processed.diff >= 0.0 {
+        item.diff_count += 1;
+        item.diff += processed.diff;
+    }
+    item.latency += processed.latency.as_millis() as u64;
+}
+
+/// Add the aborted processing to the stats of its format pair.
+pub fn add_aborted(input: &str, output: &str) {
+    let mut stats = STATS.lock().unwrap_or_else(|e| e.into_inner());
+    stats
+        .entry(format!("{input}:{output}"))
+        .or_default()
+        .aborted += 1;
+}
+
+#[derive(Serialize)]
+pub struct FormatReport {
+    pub input: String,
+    pub output: String,
+    pub count: u64,
+    pub original_size: u64,
+    pub size: u64,
+    /// Bytes saved by processing, negative means the output is larger.
+    pub saved: i64,
+    pub avg_ratio: f64,
+    pub avg_diff: Option<f64>,
+    /// Average latency in milliseconds.
+    pub avg_latency: f64,
+    pub aborted: u64,
+}
+
+/// Get the stats of all format pairs with the averages.
+pub fn get_reports() -> Vec<FormatReport> {
+    let stats = STATS.lock().unwrap_or_else(|e| e.into_inner());
+    let mut reports: Vec<FormatReport> = stats
+        .iter()
+        .filter(|(_, item)| item.count != 0 || item.aborted != 0)
+        .map(|(key, item)| {
+            let (input, output) = key.split_once(':').unwrap_or((key, ""));
+            // 仅有中止的处理时平均值为0
+            let count = item.count.max(1) as f64;
+            FormatReport {
+                input: input.to_string(),
+                output: output.to_string(),
+                count: item.count,
+                original_size: item.original_size,
+                size: item.size,
+                saved: item.original_size as i64 - item.size as i64,
+                avg_ratio: item.ratio as f64 / count,
+                avg_diff: (item.diff_count != 0).then(|| item.diff / item.diff_count as f64),
+                avg_latency: item.latency as f64 / count,
+                aborted: item.aborted,
+            }
+        })
+        .collect();
+    reports.sort_by(|a, b| (&a.input, &a.output).cmp(&(&b.input, &b.output)));
+    reports
+}
+
+/// Load the stats from file, it is ignored if the file does not exist.
+pub fn load(file: &str) -> std::io::Result<()> {
+    let data = match std::fs::read(file) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let values: HashMap<String, FormatStats> = serde_json::from_slice(&data)?;
+    let mut stats = STATS.lock().unwrap_or_else(|e| e.into_inner());
+    *stats = values;
+    Ok(())
+}
+
+/// Save the stats to file.
+pub fn save(file: &str) -> std::io::Result<()> {
+    let data = {
+        let stats = STATS.lock().unwrap_or_else(|e| e.into_inner());
+        serde_json::to_vec(&*stats)?
+    };
+    std::fs::write(file, data)
+}
@@ -11,4 +11,6 @@ where
 tokio::task_local! {
     pub static TRACE_ID: String;
     pub static STARTED_AT: i64;
+    pub static API_KEY: String;
+    pub static STORAGE: String;
 }
@@ -0,0 +1,69 @@
+use crate::config;
+use crate::image_encoder::EncoderOptions;
+use std::path::PathBuf;
+use tokio::process::Command;
+
+// 转码的临时文件，在drop时删除
+struct TempFile(PathBuf);
+
+impl TempFile {
+    fn new(ext: &str) -> Self {
+        let name = format!("image-optim-{}.{ext}", nanoid::nanoid!(16));
+        TempFile(std::env::temp_dir().join(name))
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        // 转码失败时输出文件可能不存在
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Transcode the animated image to mp4(h264) or webm(vp9) by ffmpeg,
+/// the default crf is 23 for mp4 and 32 for webm.
+pub async fn transcode(
+    data: &[u8],
+    ext: &str,
+    options: &EncoderOptions,
+) -> Result<Vec<u8>, String> {
+    let input = TempFile::new("gif");
+    let output = TempFile::new(ext);
+    tokio::fs::write(&input.0, data)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let (codec, default_crf) = if ext == "webm" {
+        ("libvpx-vp9", 32)
+    } else {
+        ("libx264", 23)
+    };
+    let crf = options.crf.unwrap_or(default_crf).to_string();
+    // vp9未指定码率时需设置为0，以crf控制质量
+    let bitrate = options
+        .bitrate
+        .map(|value| format!("{value}k"))
+        .unwrap_or_else(|| "0".to_string());
+    let mut cmd = Command::new(config::get_ffmpeg_path());
+    cmd.arg("-y")
+        .arg("-loglevel")
+        .arg("error")
+        .arg("-i")
+        .arg(&input.0)
+        .args(["-an", "-c:v", codec, "-crf", &crf, "-b:v", &bitrate])
+        // 宽高需要为偶数
+        .args(["-vf", "scale=trunc(iw/2)*2:trunc(ih/2)*2"])
+        .args(["-pix_fmt", "yuv420p"]);
+    if ext == "mp4" {
+        cmd.args(["-movflags", "+faststart"]);
+    }
+    let result = cmd
+        .arg(&output.0)
+        .output()
+        .await
+        .map_err(|e| e.to_string())?;
+    if !result.status.success() {
+        return Err(String::from_utf8_lossy(&result.stderr).trim().to_string());
+    }
+    tokio::fs::read(&output.0).await.map_err(|e| e.to_string())
+}